@@ -1,41 +1,73 @@
-use crate::distribution::pull::pull;
+pub mod sync;
+
+use crate::distribution::pull::pull_with;
+use crate::distribution::session::RegistrySession;
 use crate::filesystem::FileSystem;
+use crate::image::build::config::instructions::Kind;
 use crate::image::config::ConfigFile;
+use crate::image::index::host_platform;
 use crate::image::layer::tar_file::TarFileTy;
+use crate::image::layer::LayerMetadata;
 use crate::image::manifest::Manifest;
+use crate::image::wasm;
 use crate::image::Repositories;
+use crate::util::cancel::Cancel;
 use crate::util::DigestPre;
-use anyhow::{anyhow, Error, Result};
+use anyhow::{anyhow, bail, Error, Result};
 use log::{debug, info, warn};
+use oci_distribution::manifest::OciImageManifest;
 use oci_distribution::secrets::RegistryAuth;
 use oci_distribution::Reference;
-use std::fs::File;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
 
 ///
 /// 初始化镜像
 /// 如果本地不存在该镜像，则先pull再初始化。
 pub async fn init(image: &Reference, auth: &RegistryAuth, force: bool) -> Result<Container> {
+    init_with(
+        &RegistrySession::shared(auth)?,
+        image,
+        force,
+        &Cancel::new(),
+    )
+    .await
+}
+
+///
+/// 初始化镜像，本地不存在时通过指定的registry会话拉取。
+/// 取消或超时时返回[`crate::util::cancel::Cancelled`]错误，展开到一半的容器目录会被删除
+pub async fn init_with(
+    session: &RegistrySession,
+    image: &Reference,
+    force: bool,
+    cancel: &Cancel,
+) -> Result<Container> {
     debug!("初始化镜像: {:?}", image);
     // 判断是否已存在该容器：
     // 读取config
     // 读取layer
     // 初始化容器的文件系统
     let repo = Repositories::init()?;
-    let manifest_digest = match repo.image_digest(&image) {
+    let manifest_digest = match repo.image_digest(image) {
         Some(digest) => digest.get_digest()?,
         None => {
             info!("本地未找到镜像{:?}，先拉取镜像！", image);
-            pull(image, auth).await?
+            pull_with(session.client(), image, &host_platform(), cancel).await?
         }
     };
+    // 镜像索引按拉取时选择的平台（默认本机平台）选择manifest
+    let platform = Repositories::init()?
+        .image_platform(image)
+        .cloned()
+        .unwrap_or_else(host_platform);
+    let manifest_digest = Manifest::resolve(&manifest_digest, &platform)?;
     let path = FileSystem.container()?.join(&manifest_digest);
 
     let manifest = Manifest::load(manifest_digest.as_str())?.to_oci_manifest()?;
 
-    let config = ConfigFile::load(&manifest.config.digest.get_digest()?)?;
-
-    let container = Container { path, config };
+    let container = Container::from_manifest(path, &manifest)?;
+    cancel.check()?;
     if force {
         container.clear()?;
     } else {
@@ -44,21 +76,51 @@ pub async fn init(image: &Reference, auth: &RegistryAuth, force: bool) -> Result
             return Ok(container);
         }
     }
-    container.init()?;
+    if let Err(e) = container.unpack(cancel) {
+        // 不保留不完整的容器，否则下次初始化会误认为容器已存在
+        container.clear()?;
+        return Err(e);
+    }
     Ok(container)
 }
 
 pub struct Container {
     pub config: ConfigFile,
     pub path: PathBuf,
+    /// wasm artifact中模块的blob digest（不带`sha256:`前缀），普通镜像为None
+    pub wasm_module: Option<String>,
 }
 
 impl Container {
+    /// 由镜像manifest生成容器，wasm artifact没有镜像config，按模块layer生成
+    fn from_manifest(path: PathBuf, manifest: &OciImageManifest) -> Result<Self> {
+        if wasm::is_wasm_artifact(manifest) {
+            let layer = wasm::module_layer(manifest)?;
+            return Ok(Self {
+                path,
+                config: wasm::config_file(manifest)?,
+                wasm_module: Some(layer.digest.get_digest()?),
+            });
+        }
+        let config = ConfigFile::load(&manifest.config.digest.get_digest()?)?;
+        Ok(Self {
+            path,
+            config,
+            wasm_module: None,
+        })
+    }
     pub fn cmd(&self) -> PathBuf {
         let path = self.path.join(self.config.cmd.as_str());
         debug!("base={:?} path={:?}", self.path, path);
         path
     }
+    /// Wasi镜像（包括wasm artifact）的模块文件路径，其它镜像返回None
+    pub fn module(&self) -> Option<PathBuf> {
+        match self.config.kind {
+            Kind::Wasi => Some(self.cmd()),
+            Kind::App => None,
+        }
+    }
     pub fn exists(&self) -> bool {
         self.path.exists()
     }
@@ -69,63 +131,290 @@ impl Container {
         Ok(())
     }
     pub fn init(&self) -> Result<()> {
-        let config = &self.config;
-        std::fs::create_dir_all(&self.path)?;
-        for copy in config.rootf.diff_ids.as_slice() {
-            debug!("read layer {:?}", copy);
-            let file = std::fs::File::open(FileSystem.layer_blobs()?.join(copy.get_digest()?))?;
-            let mut archive = tar::Archive::new(file);
-            let entries = archive.entries().unwrap();
-            for (_index, item) in entries.enumerate() {
-                if let Ok(item) = item {
-                    if let Some(path) = item.path()?.to_str().and_then(|x| Some(x.to_string())) {
-                        let tar_file: TarFileTy = path.into();
-                        apply_tar_file(tar_file, &self.path, item)?;
-                    } else {
-                        warn!("archive.entries.item has not path")
+        self.unpack(&Cancel::new())
+    }
+    /// 展开layer到容器目录；wasm artifact则将模块文件复制为CMD
+    fn unpack(&self, cancel: &Cancel) -> Result<()> {
+        match &self.wasm_module {
+            Some(digest) => {
+                cancel.check()?;
+                std::fs::create_dir_all(&self.path)?;
+                std::fs::copy(FileSystem.layer_blobs()?.join(digest), self.cmd())?;
+                Ok(())
+            }
+            None => unpack_layers_with(&self.config.rootf.diff_ids, &self.path, cancel),
+        }
+    }
+}
+
+/// 按顺序将layer展开到目标文件夹（处理whiteout文件）
+pub fn unpack_layers(diff_ids: &[String], base: &Path) -> Result<()> {
+    unpack_layers_with(diff_ids, base, &Cancel::new())
+}
+
+/// 按顺序将layer展开到目标文件夹，每个文件展开前检查是否已取消
+pub fn unpack_layers_with(diff_ids: &[String], base: &Path, cancel: &Cancel) -> Result<()> {
+    std::fs::create_dir_all(base)?;
+    for layer in LayerMetadata::load_all(diff_ids)? {
+        debug!("read layer {:?}", layer);
+        unpack_layer(|| layer.open(), base, cancel)?;
+    }
+    Ok(())
+}
+
+///
+/// 展开单个layer：whiteout只作用于下层，因此先收集本层的whiteout并应用，
+/// 再重新读取layer展开本层的文件，避免删除同一layer中的条目
+fn unpack_layer<R: Read>(open: impl Fn() -> Result<R>, base: &Path, cancel: &Cancel) -> Result<()> {
+    let mut whiteouts = Vec::new();
+    let mut archive = tar::Archive::new(open()?);
+    for item in archive.entries()? {
+        cancel.check()?;
+        if let Some(path) = item?.path()?.to_str().map(|x| x.to_string()) {
+            match TarFileTy::from(path) {
+                TarFileTy::Update(_) => {}
+                whiteout => whiteouts.push(whiteout),
+            }
+        }
+    }
+    for whiteout in whiteouts.iter() {
+        apply_whiteout(whiteout, base)?;
+    }
+    unpack_archive(open()?, base, cancel)
+}
+
+///
+/// 将单个layer的tar展开到目标文件夹，跳过whiteout条目。
+/// 文件夹中写入文件会改变其修改时间，文件夹的修改时间在全部条目展开后重新设置
+fn unpack_archive<R: Read>(reader: R, base: &Path, cancel: &Cancel) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    let entries = archive.entries()?;
+    let mut dirs = Vec::new();
+    for item in entries {
+        cancel.check()?;
+        if let Ok(item) = item {
+            if let Some(path) = item.path()?.to_str().map(|x| x.to_string()) {
+                let tar_file: TarFileTy = path.into();
+                if let TarFileTy::Update(file) = &tar_file {
+                    if item.header().entry_type().is_dir() {
+                        dirs.push((entry_path(base, file)?, item.header().mtime()?));
                     }
-                } else {
-                    warn!("archive.entries.item fail")
+                    apply_tar_file(tar_file, base, item)?;
                 }
+            } else {
+                warn!("archive.entries.item has not path")
             }
+        } else {
+            warn!("archive.entries.item fail")
         }
-        Ok(())
     }
+    for (dir, mtime) in dirs.iter().rev() {
+        filetime::set_file_mtime(dir, filetime::FileTime::from_unix_time(*mtime as i64, 0))?;
+    }
+    Ok(())
 }
 
-pub fn apply_tar_file(
-    tar_file_ty: TarFileTy,
-    base: &PathBuf,
-    mut item: tar::Entry<File>,
-) -> Result<()> {
-    debug!("{:?}", tar_file_ty);
+///
+/// layer中的条目在容器目录中的路径：拒绝绝对路径与`..`，
+/// 且已存在的上级路径中不能有符号链接，避免恶意layer经符号链接写到容器目录之外
+fn entry_path(base: &Path, path: &str) -> Result<PathBuf> {
+    let mut target = base.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => {
+                if target != base && target.symlink_metadata().is_ok_and(|x| x.is_symlink()) {
+                    bail!("layer中的路径{:?}经过符号链接{:?}", path, target);
+                }
+                target.push(name);
+            }
+            Component::CurDir => {}
+            _ => bail!("layer中的路径非法: {:?}", path),
+        }
+    }
+    Ok(target)
+}
+
+///
+/// 删除目标处已存在的条目：先unlink再写入，避免跟随符号链接或经硬链接改写共享的文件；
+/// `keep_dir`为true时保留已存在的文件夹
+fn remove_existing(target_path: &Path, keep_dir: bool) -> Result<()> {
+    match target_path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => {
+            if !keep_dir {
+                std::fs::remove_dir_all(target_path)?;
+            }
+        }
+        Ok(_) => std::fs::remove_file(target_path)?,
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+/// 应用whiteout：删除下层中的文件，或清空下层中文件夹的内容（不透明whiteout）
+fn apply_whiteout(tar_file_ty: &TarFileTy, base: &Path) -> Result<()> {
     match tar_file_ty {
         TarFileTy::Delete(file) => {
-            let target_path = base.join(&file);
+            // whiteout在tar中一般为空文件，按目标实际的类型删除；下层中不存在时忽略
+            let target_path = entry_path(base, file)?;
             debug!("target: {:?}", target_path);
-            if item.header().entry_type().is_file() {
-                std::fs::remove_file(&target_path)?;
-            } else if item.header().entry_type().is_dir() {
-                std::fs::remove_dir_all(&target_path)?;
-            } else {
-                warn!("暂不支持其他文件类型: {:?}", item.header().entry_type());
+            if target_path == base {
+                warn!("忽略删除容器根目录的whiteout");
+                return Ok(());
+            }
+            match target_path.symlink_metadata() {
+                Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(&target_path)?,
+                Ok(_) => std::fs::remove_file(&target_path)?,
+                Err(_) => debug!("whiteout目标不存在: {:?}", target_path),
+            }
+        }
+        TarFileTy::Opaque(dir) => {
+            let target_path = entry_path(base, dir)?;
+            debug!("opaque: {:?}", target_path);
+            if target_path.symlink_metadata().is_ok_and(|x| x.is_dir()) {
+                for entry in std::fs::read_dir(&target_path)? {
+                    let path = entry?.path();
+                    if path.symlink_metadata()?.is_dir() {
+                        std::fs::remove_dir_all(&path)?;
+                    } else {
+                        std::fs::remove_file(&path)?;
+                    }
+                }
             }
         }
+        TarFileTy::Update(_) => {}
+    }
+    Ok(())
+}
+
+pub fn apply_tar_file<R: Read>(
+    tar_file_ty: TarFileTy,
+    base: &Path,
+    mut item: tar::Entry<R>,
+) -> Result<()> {
+    debug!("{:?}", tar_file_ty);
+    match tar_file_ty {
+        TarFileTy::Delete(_) | TarFileTy::Opaque(_) => apply_whiteout(&tar_file_ty, base)?,
         TarFileTy::Update(file) => {
-            let target_path = base.join(&file);
+            let target_path = entry_path(base, &file)?;
             debug!("target: {:?}", target_path,);
-            if item.header().entry_type().is_file() {
-                let mut file = std::fs::File::create(target_path)?;
+            // layer中可能没有上级文件夹的条目；entry_path已确认已存在的上级中没有符号链接
+            if let Some(parent) = target_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let entry_type = item.header().entry_type();
+            if target_path == base && !entry_type.is_dir() {
+                bail!("layer中的条目{:?}不能替换容器根目录", file);
+            }
+            let xattrs = entry_xattrs(&mut item)?;
+            if entry_type.is_file() {
+                remove_existing(&target_path, false)?;
+                let mut file = std::fs::File::create(&target_path)?;
                 // let mut data = Vec::with_capacity(item.size() as usize);
                 // let read_size = item.read_to_end(&mut data)?;
                 std::io::copy(&mut item, &mut file)?;
-            } else if item.header().entry_type().is_dir() {
-                std::fs::create_dir_all(target_path)?;
+                apply_tar_metadata(item.header(), &target_path, &xattrs)?;
+            } else if entry_type.is_dir() {
+                remove_existing(&target_path, true)?;
+                std::fs::create_dir_all(&target_path)?;
+                apply_tar_metadata(item.header(), &target_path, &xattrs)?;
+            } else if entry_type.is_symlink() {
+                let link_name = item
+                    .link_name()?
+                    .ok_or(anyhow!("符号链接{:?}缺少目标", target_path))?;
+                remove_existing(&target_path, false)?;
+                create_symlink(&link_name, &target_path)?;
+                #[cfg(target_family = "unix")]
+                apply_tar_metadata(item.header(), &target_path, &xattrs)?;
+            } else if entry_type.is_hard_link() {
+                // 硬链接的目标为layer中的路径，相对于根目录，与条目路径一样不能离开容器目录
+                let link_name = item
+                    .link_name()?
+                    .ok_or(anyhow!("硬链接{:?}缺少目标", target_path))?;
+                let link_name = link_name.to_string_lossy();
+                let source = entry_path(base, link_name.trim_start_matches('/'))?;
+                if source == target_path || !source.symlink_metadata().is_ok_and(|x| !x.is_dir()) {
+                    bail!("硬链接{:?}的目标{:?}无效", file, link_name);
+                }
+                remove_existing(&target_path, false)?;
+                std::fs::hard_link(&source, &target_path)?;
             } else {
-                warn!("暂不支持其他文件类型: {:?}", item.header().entry_type());
+                warn!("暂不支持其他文件类型: {:?}", entry_type);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 条目PAX扩展头中记录的扩展属性（`SCHILY.xattr.<name>`）
+fn entry_xattrs<R: Read>(item: &mut tar::Entry<R>) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+    if let Some(extensions) = item.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            if let Some(name) = extension
+                .key()
+                .ok()
+                .and_then(|x| x.strip_prefix("SCHILY.xattr."))
+            {
+                xattrs.push((name.to_string(), extension.value_bytes().to_vec()));
+            }
+        }
+    }
+    Ok(xattrs)
+}
+
+///
+/// 按tar头部记录的属主、权限位、修改时间及PAX扩展属性恢复文件元数据（符号链接不跟随）。
+/// 非root用户一般无权修改属主或设置`trusted.`、`security.`扩展属性，这两项失败时忽略
+fn apply_tar_metadata(
+    header: &tar::Header,
+    target_path: &Path,
+    xattrs: &[(String, Vec<u8>)],
+) -> Result<()> {
+    let symlink = header.entry_type().is_symlink();
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let uid = header.uid().ok().and_then(|x| u32::try_from(x).ok());
+        let gid = header.gid().ok().and_then(|x| u32::try_from(x).ok());
+        // 修改属主会清除setuid、setgid位，须在设置权限位之前
+        if let Err(e) = std::os::unix::fs::lchown(target_path, uid, gid) {
+            debug!("恢复{:?}的属主失败：{}", target_path, e);
+        }
+        if !symlink {
+            let mode = header.mode()?;
+            std::fs::set_permissions(target_path, std::fs::Permissions::from_mode(mode))?;
+        }
+        for (name, value) in xattrs {
+            if let Err(e) = xattr::set(target_path, name, value) {
+                debug!("恢复{:?}的扩展属性{}失败：{}", target_path, name, e);
             }
         }
     }
+    #[cfg(not(target_family = "unix"))]
+    if !xattrs.is_empty() {
+        debug!("当前平台不支持扩展属性，忽略: {:?}", target_path);
+    }
+    let mtime = filetime::FileTime::from_unix_time(header.mtime()? as i64, 0);
+    if symlink {
+        filetime::set_symlink_file_times(target_path, mtime, mtime)?;
+    } else {
+        filetime::set_file_mtime(target_path, mtime)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_family = "unix")]
+fn create_symlink(link_name: &Path, target_path: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(link_name, target_path)?;
+    Ok(())
+}
+#[cfg(not(target_family = "unix"))]
+fn create_symlink(link_name: &Path, target_path: &Path) -> Result<()> {
+    warn!(
+        "当前平台不支持符号链接: {:?} -> {:?}",
+        target_path, link_name
+    );
     Ok(())
 }
 
@@ -134,11 +423,245 @@ impl TryFrom<&Reference> for Container {
 
     fn try_from(image: &Reference) -> std::result::Result<Self, Self::Error> {
         let repo = Repositories::init()?;
-        let config_digest = repo
-            .image_digest(&image)
-            .ok_or(anyhow!("本地未找到镜像{:?}", image))?;
-        let path = FileSystem.container()?.join(&config_digest);
-        let config = ConfigFile::load(&config_digest)?;
-        Ok(Self { path, config })
+        let manifest_digest = repo
+            .image_digest(image)
+            .ok_or(anyhow!("本地未找到镜像{:?}", image))?
+            .get_digest()?;
+        let platform = repo
+            .image_platform(image)
+            .cloned()
+            .unwrap_or_else(host_platform);
+        let manifest_digest = Manifest::resolve(&manifest_digest, &platform)?;
+        let path = FileSystem.container()?.join(&manifest_digest);
+        let manifest = Manifest::load(manifest_digest.as_str())?.to_oci_manifest()?;
+        Self::from_manifest(path, &manifest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{unpack_archive, unpack_layer};
+    use crate::util::cancel::Cancel;
+
+    fn append(builder: &mut tar::Builder<Vec<u8>>, path: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        // 直接写入名称，绕过tar对`..`与绝对路径的检查以构造恶意layer
+        let name = &mut header.as_old_mut().name;
+        name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    }
+
+    fn append_link(
+        builder: &mut tar::Builder<Vec<u8>>,
+        entry_type: tar::EntryType,
+        path: &str,
+        target: &str,
+    ) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(0);
+        builder.append_link(&mut header, path, target).unwrap();
+    }
+
+    fn unpack(layer: &[u8], base: &std::path::Path) -> anyhow::Result<()> {
+        unpack_layer(|| Ok(layer), base, &Cancel::new())
+    }
+
+    #[test]
+    fn test_whiteout() {
+        let base = tempfile::tempdir().unwrap();
+        let mut builder = tar::Builder::new(Vec::new());
+        for path in ["dir/a", "dir/sub/b", "keep", "gone", "gone-dir/c"] {
+            append(&mut builder, path, b"lower");
+        }
+        unpack(&builder.into_inner().unwrap(), base.path()).unwrap();
+
+        // whiteout排在本层文件之后，也只作用于下层
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, "dir/new", b"upper");
+        append(&mut builder, "same", b"upper");
+        append(&mut builder, ".wh.gone", b"");
+        append(&mut builder, ".wh.gone-dir", b"");
+        append(&mut builder, ".wh.same", b"");
+        append(&mut builder, ".wh.missing", b"");
+        append(&mut builder, "dir/.wh..wh..opq", b"");
+        unpack(&builder.into_inner().unwrap(), base.path()).unwrap();
+
+        let base = base.path();
+        assert_eq!(std::fs::read(base.join("keep")).unwrap(), b"lower");
+        assert!(!base.join("gone").exists());
+        assert!(!base.join("gone-dir").exists());
+        assert!(!base.join("dir/a").exists());
+        assert!(!base.join("dir/sub").exists());
+        assert_eq!(std::fs::read(base.join("dir/new")).unwrap(), b"upper");
+        assert_eq!(std::fs::read(base.join("same")).unwrap(), b"upper");
+        assert!(!base.join(".wh.gone").exists());
+        assert!(!base.join("dir/.wh..wh..opq").exists());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_metadata_round_trip() {
+        use crate::filesystem::snapshot::{LayerOptions, Snapshot};
+        use oci_spec::image::MediaType;
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let empty = tempfile::tempdir().unwrap();
+        let src = tempfile::tempdir().unwrap();
+        let app = src.path().join("bin/app");
+        std::fs::create_dir(src.path().join("bin")).unwrap();
+        std::fs::write(&app, "app").unwrap();
+        std::fs::set_permissions(&app, std::fs::Permissions::from_mode(0o750)).unwrap();
+        std::os::unix::fs::symlink("bin/app", src.path().join("app")).unwrap();
+        // 不支持user.扩展属性的文件系统上只检查属主与修改时间
+        let xattr = xattr::set(&app, "user.round-trip", b"value").is_ok();
+        let root = std::fs::metadata(src.path()).unwrap().uid() == 0;
+        if root {
+            std::os::unix::fs::lchown(&app, Some(1234), Some(1234)).unwrap();
+        }
+        // tar头部只记录整秒，亚秒部分不参与比较
+        let mtime = filetime::FileTime::from_unix_time(1_000_000, 123_456_789);
+        filetime::set_symlink_file_times(src.path().join("app"), mtime, mtime).unwrap();
+        filetime::set_file_mtime(&app, mtime).unwrap();
+        filetime::set_file_mtime(src.path().join("bin"), mtime).unwrap();
+
+        let source = Snapshot::init(src.path().to_path_buf()).unwrap();
+        let layout = tempfile::tempdir().unwrap();
+        let (_, descriptor) = Snapshot::init(empty.path().to_path_buf())
+            .unwrap()
+            .diff(&source)
+            .write_layer(
+                layout.path(),
+                &MediaType::ImageLayer,
+                &LayerOptions::default(),
+            )
+            .unwrap()
+            .unwrap();
+        let blob = layout
+            .path()
+            .join("blobs/sha256")
+            .join(descriptor.digest().trim_start_matches("sha256:"));
+        let target = tempfile::tempdir().unwrap();
+        unpack_layer(
+            || Ok(std::fs::File::open(&blob)?),
+            target.path(),
+            &Cancel::new(),
+        )
+        .unwrap();
+
+        let unpacked = Snapshot::init(target.path().to_path_buf()).unwrap();
+        assert!(source.diff(&unpacked).items.is_empty());
+        let unpacked_app = target.path().join("bin/app");
+        assert_eq!(unpacked_app.metadata().unwrap().mode() & 0o7777, 0o750);
+        if xattr {
+            assert_eq!(
+                xattr::get(&unpacked_app, "user.round-trip").unwrap(),
+                Some(b"value".to_vec())
+            );
+        }
+        if root {
+            assert_eq!(unpacked_app.metadata().unwrap().uid(), 1234);
+            assert_eq!(unpacked_app.metadata().unwrap().gid(), 1234);
+        }
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_hard_link() {
+        let base = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), "secret").unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, "bin/data", b"shared");
+        append_link(&mut builder, tar::EntryType::Link, "bin/link", "bin/data");
+        append_link(&mut builder, tar::EntryType::Link, "abs", "/bin/data");
+        unpack(&builder.into_inner().unwrap(), base.path()).unwrap();
+        assert_eq!(
+            std::fs::read(base.path().join("bin/link")).unwrap(),
+            b"shared"
+        );
+        assert_eq!(std::fs::read(base.path().join("abs")).unwrap(), b"shared");
+
+        // 上层改写硬链接时先unlink，不影响共享同一inode的其它路径
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, "bin/link", b"changed");
+        unpack(&builder.into_inner().unwrap(), base.path()).unwrap();
+        assert_eq!(
+            std::fs::read(base.path().join("bin/link")).unwrap(),
+            b"changed"
+        );
+        assert_eq!(
+            std::fs::read(base.path().join("bin/data")).unwrap(),
+            b"shared"
+        );
+        assert_eq!(std::fs::read(base.path().join("abs")).unwrap(), b"shared");
+
+        // 硬链接的目标不能离开容器目录
+        let secret = outside.path().join("secret");
+        for target in ["../secret", "out/secret", "bin", "missing"] {
+            let mut builder = tar::Builder::new(Vec::new());
+            append_link(
+                &mut builder,
+                tar::EntryType::Symlink,
+                "out",
+                outside.path().to_str().unwrap(),
+            );
+            append_link(&mut builder, tar::EntryType::Link, "evil", target);
+            assert!(
+                unpack(&builder.into_inner().unwrap(), base.path()).is_err(),
+                "{}",
+                target
+            );
+            assert!(!base.path().join("evil").exists());
+        }
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(secret.metadata().unwrap().nlink(), 1);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_malicious_layer() {
+        let base = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+
+        // 先创建指向容器外的符号链接，再经该链接写文件
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "etc", outside.path())
+            .unwrap();
+        append(&mut builder, "etc/passwd", b"evil");
+        let layer = builder.into_inner().unwrap();
+        assert!(unpack_archive(layer.as_slice(), base.path(), &Cancel::new()).is_err());
+        assert!(!outside.path().join("passwd").exists());
+
+        // 最后一级为符号链接时替换链接本身，不写入链接目标
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "passwd", outside.path().join("passwd"))
+            .unwrap();
+        append(&mut builder, "passwd", b"evil");
+        let layer = builder.into_inner().unwrap();
+        unpack_archive(layer.as_slice(), base.path(), &Cancel::new()).unwrap();
+        assert!(!outside.path().join("passwd").exists());
+        assert_eq!(std::fs::read(base.path().join("passwd")).unwrap(), b"evil");
+
+        for path in ["../escape", "/escape"] {
+            let mut builder = tar::Builder::new(Vec::new());
+            append(&mut builder, path, b"evil");
+            let layer = builder.into_inner().unwrap();
+            assert!(unpack_archive(layer.as_slice(), base.path(), &Cancel::new()).is_err());
+        }
+        assert!(!base.path().parent().unwrap().join("escape").exists());
     }
 }
@@ -0,0 +1,113 @@
+use crate::container::init_with;
+use crate::distribution::pull::pull_with;
+use crate::distribution::session::RegistrySession;
+use crate::distribution::update::check_update_with;
+use crate::image::gc::{gc, GcReport};
+use crate::image::index::host_platform;
+use crate::image::{record_name, Repositories};
+use crate::util::cancel::{Cancel, Cancelled};
+use anyhow::Result;
+use log::{debug, info, warn};
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use std::collections::HashSet;
+
+/// 同步结果
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// 本地原本不存在、新拉取的镜像
+    pub pulled: Vec<Reference>,
+    /// registry上有更新、重新拉取的镜像
+    pub updated: Vec<Reference>,
+    /// 已是最新的镜像
+    pub unchanged: Vec<Reference>,
+    /// 不再需要、已从images.json移除的镜像记录名
+    pub removed: Vec<String>,
+    /// 同步失败的镜像及原因，失败的镜像保留本地现有版本
+    pub failed: Vec<(Reference, String)>,
+    pub gc: GcReport,
+}
+
+///
+/// 按期望状态同步本地镜像：
+/// 拉取本地缺失或registry上有更新的镜像并重新初始化其容器，
+/// 从images.json移除不在`images`中的镜像（包括本地构建的镜像），最后执行垃圾回收。
+/// 单个镜像失败不影响其它镜像，记录在`SyncReport::failed`中
+pub async fn sync(images: &[Reference], auth: &RegistryAuth) -> Result<SyncReport> {
+    sync_with(&RegistrySession::shared(auth)?, images, &Cancel::new()).await
+}
+
+///
+/// 使用指定的registry会话同步本地镜像，所有镜像共享会话中的token与连接。
+/// 取消或超时时立即返回[`Cancelled`]错误，不移除镜像记录、不执行垃圾回收
+pub async fn sync_with(
+    session: &RegistrySession,
+    images: &[Reference],
+    cancel: &Cancel,
+) -> Result<SyncReport> {
+    let mut report = SyncReport::default();
+    for image in images.iter() {
+        match sync_image(session, image, cancel).await {
+            Ok(SyncState::Pulled) => report.pulled.push(image.clone()),
+            Ok(SyncState::Updated) => report.updated.push(image.clone()),
+            Ok(SyncState::Unchanged) => report.unchanged.push(image.clone()),
+            Err(e) if e.downcast_ref::<Cancelled>().is_some() => return Err(e),
+            Err(e) => {
+                warn!("同步镜像{:?}失败：{:?}", image, e);
+                report.failed.push((image.clone(), format!("{:?}", e)));
+            }
+        }
+    }
+
+    cancel.check()?;
+    let desired: HashSet<String> = images.iter().map(record_name).collect();
+    report.removed =
+        Repositories::modify(|repo| repo.retain(|whole_name| desired.contains(whole_name)))?.0;
+    if !report.removed.is_empty() {
+        info!("移除不再需要的镜像: {:?}", report.removed);
+    }
+
+    report.gc = gc()?;
+    Ok(report)
+}
+
+enum SyncState {
+    Pulled,
+    Updated,
+    Unchanged,
+}
+
+async fn sync_image(
+    session: &RegistrySession,
+    image: &Reference,
+    cancel: &Cancel,
+) -> Result<SyncState> {
+    let state = match Repositories::init()?.image_digest(image) {
+        None => SyncState::Pulled,
+        // digest引用的内容不会变化
+        Some(_) if image.digest().is_some() => SyncState::Unchanged,
+        Some(_) => {
+            if cancel
+                .run(check_update_with(session.client(), image))
+                .await?
+                .update_available
+            {
+                SyncState::Updated
+            } else {
+                SyncState::Unchanged
+            }
+        }
+    };
+    match state {
+        SyncState::Unchanged => {
+            debug!("镜像{:?}已是最新", image);
+            // 容器目录可能被删除，不存在时重新初始化
+            init_with(session, image, false, cancel).await?;
+        }
+        SyncState::Pulled | SyncState::Updated => {
+            pull_with(session.client(), image, &host_platform(), cancel).await?;
+            init_with(session, image, true, cancel).await?;
+        }
+    }
+    Ok(state)
+}
@@ -0,0 +1,547 @@
+use crate::distribution::client::{RegistryClient, RegistryError};
+use crate::distribution::push::{push_blob_data, PushError, PushReport};
+use crate::distribution::session::RegistrySession;
+use crate::image::manifest::{Manifest, TITLE_ANNOTATION};
+use crate::image::Repositories;
+use crate::util::cancel::Cancel;
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, warn};
+use oci_distribution::manifest::{OciDescriptor, OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE};
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// OCI 1.1规定的空描述符媒体类型，artifact没有config时使用
+pub const EMPTY_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+
+/// 空描述符的内容
+const EMPTY_CONFIG: &[u8] = b"{}";
+
+/// artifact中的一个文件
+#[derive(Debug, Clone)]
+pub struct ArtifactFile {
+    pub path: PathBuf,
+    /// layer的媒体类型，如`application/spdx+json`
+    pub media_type: String,
+    /// layer的注解，未指定`org.opencontainers.image.title`时以文件名补充
+    pub annotations: HashMap<String, String>,
+}
+
+impl ArtifactFile {
+    pub fn new(path: impl Into<PathBuf>, media_type: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            media_type: media_type.into(),
+            annotations: HashMap::new(),
+        }
+    }
+}
+
+/// 待推送的OCI artifact（SBOM、签名、配置文件等）
+#[derive(Debug, Clone)]
+pub struct Artifact {
+    /// manifest的`artifactType`，如`application/vnd.example.sbom.v1`
+    pub artifact_type: String,
+    pub files: Vec<ArtifactFile>,
+    /// manifest的注解
+    pub annotations: HashMap<String, String>,
+    /// 关联的镜像，写入manifest的`subject`字段；须与artifact在同一仓库
+    pub subject: Option<Reference>,
+}
+
+/// artifact的manifest（OCI image manifest，带`artifactType`与`subject`字段）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArtifactManifest {
+    pub schema_version: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    pub config: OciDescriptor,
+    #[serde(default)]
+    pub layers: Vec<OciDescriptor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<OciDescriptor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+impl ArtifactManifest {
+    /// artifact类型：`artifactType`字段，缺失时为config的媒体类型
+    pub fn artifact_type(&self) -> &str {
+        self.artifact_type
+            .as_deref()
+            .unwrap_or(self.config.media_type.as_str())
+    }
+}
+
+/// 引用了某个manifest的artifact，即referrers列表（OCI image index）中的条目
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Referrer {
+    pub media_type: String,
+    pub digest: String,
+    pub size: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+/// referrers列表
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReferrerIndex {
+    schema_version: u8,
+    media_type: String,
+    #[serde(default)]
+    manifests: Vec<Referrer>,
+}
+
+/// 拉取到本地的artifact
+#[derive(Debug)]
+pub struct PulledArtifact {
+    /// manifest的digest（带`sha256:`前缀）
+    pub digest: String,
+    pub manifest: ArtifactManifest,
+    /// 各layer写入的文件，与`manifest.layers`按顺序对应
+    pub files: Vec<PathBuf>,
+}
+
+/// registry不支持referrers API时，记录referrers列表的tag（tag schema），如`sha256-<hex>`
+pub fn referrers_tag(digest: &str) -> String {
+    digest.replacen(':', "-", 1)
+}
+
+/// 推送artifact，见[`push_artifact_with`]
+pub async fn push_artifact(
+    target: &Reference,
+    auth: &RegistryAuth,
+    artifact: &Artifact,
+) -> Result<PushReport, PushError> {
+    let session = RegistrySession::shared(auth)?;
+    let client = session.client();
+    push_artifact_with(client, target, artifact, &Cancel::new()).await
+}
+
+///
+/// 推送artifact：各文件作为layer，config为空描述符。
+/// 指定了subject时，registry未通过`OCI-Subject`响应头表明支持referrers API的，
+/// 将artifact追加到tag schema的referrers列表（`sha256-<hex>`）
+pub async fn push_artifact_with(
+    client: &RegistryClient,
+    target: &Reference,
+    artifact: &Artifact,
+    cancel: &Cancel,
+) -> Result<PushReport, PushError> {
+    let mut report = PushReport::default();
+    let subject = match &artifact.subject {
+        Some(subject) => Some(
+            cancel
+                .run(subject_descriptor(client, target, subject))
+                .await?,
+        ),
+        None => None,
+    };
+
+    let mut layers = Vec::with_capacity(artifact.files.len());
+    for file in artifact.files.iter() {
+        let data = std::fs::read(&file.path)
+            .map_err(|e| anyhow!("读取artifact文件{:?}失败：{}", file.path, e))?;
+        let mut annotations = file.annotations.clone();
+        if let Some(name) = file.path.file_name() {
+            annotations
+                .entry(TITLE_ANNOTATION.to_string())
+                .or_insert(name.to_string_lossy().to_string());
+        }
+        let descriptor = OciDescriptor {
+            media_type: file.media_type.clone(),
+            digest: format!("sha256:{}", sha256::digest(data.as_slice())),
+            size: data.len() as i64,
+            urls: None,
+            annotations: Some(annotations),
+        };
+        let outcome = cancel
+            .run(push_blob_data(
+                client,
+                target,
+                &descriptor.digest,
+                &HashMap::new(),
+                || Ok(data),
+            ))
+            .await?;
+        report.add(descriptor.digest.clone(), outcome);
+        layers.push(descriptor);
+    }
+    let config = OciDescriptor {
+        media_type: EMPTY_MEDIA_TYPE.to_string(),
+        digest: format!("sha256:{}", sha256::digest(EMPTY_CONFIG)),
+        size: EMPTY_CONFIG.len() as i64,
+        urls: None,
+        annotations: None,
+    };
+    let outcome = cancel
+        .run(push_blob_data(
+            client,
+            target,
+            &config.digest,
+            &HashMap::new(),
+            || Ok(EMPTY_CONFIG.to_vec()),
+        ))
+        .await?;
+    report.add(config.digest.clone(), outcome);
+
+    let manifest = ArtifactManifest {
+        schema_version: 2,
+        media_type: Some(OCI_IMAGE_MEDIA_TYPE.to_string()),
+        artifact_type: Some(artifact.artifact_type.clone()),
+        config,
+        layers,
+        subject,
+        annotations: if artifact.annotations.is_empty() {
+            None
+        } else {
+            Some(artifact.annotations.clone())
+        },
+    };
+    let data = serde_json::to_vec(&manifest).map_err(anyhow::Error::from)?;
+    report.manifest_digest = format!("sha256:{}", sha256::digest(data.as_slice()));
+    let (url, processed) = cancel
+        .run(client.push_manifest_subject(target, &data, OCI_IMAGE_MEDIA_TYPE))
+        .await?;
+    report.manifest_url = url;
+
+    if let (Some(subject), None) = (&manifest.subject, processed) {
+        debug!("registry未处理subject，更新tag schema的referrers列表");
+        let referrer = Referrer {
+            media_type: OCI_IMAGE_MEDIA_TYPE.to_string(),
+            digest: report.manifest_digest.clone(),
+            size: data.len() as i64,
+            artifact_type: manifest.artifact_type.clone(),
+            annotations: manifest.annotations.clone(),
+        };
+        cancel
+            .run(add_referrer(client, target, &subject.digest, referrer))
+            .await?;
+    }
+    Ok(report)
+}
+
+/// subject的描述符：按registry上的manifest计算
+async fn subject_descriptor(
+    client: &RegistryClient,
+    target: &Reference,
+    subject: &Reference,
+) -> Result<OciDescriptor> {
+    if subject.resolve_registry() != target.resolve_registry()
+        || subject.repository() != target.repository()
+    {
+        bail!(
+            "subject{:?}须与artifact{:?}在同一仓库",
+            subject.whole(),
+            target.whole()
+        );
+    }
+    let raw = client
+        .pull_manifest_raw(subject)
+        .await
+        .with_context(|| format!("获取subject{:?}失败", subject.whole()))?;
+    let media_type = Manifest::new(raw.data.clone())
+        .media_type()
+        .or(raw.media_type)
+        .unwrap_or(OCI_IMAGE_MEDIA_TYPE.to_string());
+    Ok(OciDescriptor {
+        media_type,
+        digest: raw.digest,
+        size: raw.data.len() as i64,
+        urls: None,
+        annotations: None,
+    })
+}
+
+/// 将artifact追加到tag schema的referrers列表，已存在时不重复添加
+async fn add_referrer(
+    client: &RegistryClient,
+    image: &Reference,
+    subject: &str,
+    referrer: Referrer,
+) -> Result<()> {
+    let tag = Reference::with_tag(
+        image.registry().to_string(),
+        image.repository().to_string(),
+        referrers_tag(subject),
+    );
+    let mut index = match tag_referrers(client, &tag).await? {
+        Some(index) => index,
+        None => ReferrerIndex {
+            schema_version: 2,
+            media_type: OCI_IMAGE_INDEX_MEDIA_TYPE.to_string(),
+            manifests: Vec::new(),
+        },
+    };
+    if index.manifests.iter().any(|x| x.digest == referrer.digest) {
+        return Ok(());
+    }
+    index.manifests.push(referrer);
+    let data = serde_json::to_vec(&index)?;
+    client
+        .push_manifest_raw(&tag, &data, OCI_IMAGE_INDEX_MEDIA_TYPE)
+        .await?;
+    Ok(())
+}
+
+/// 读取tag schema的referrers列表，tag不存在时返回None
+async fn tag_referrers(client: &RegistryClient, tag: &Reference) -> Result<Option<ReferrerIndex>> {
+    match client.pull_manifest_raw(tag).await {
+        Ok(raw) => Ok(Some(
+            serde_json::from_slice(&raw.data).context("解析referrers列表失败")?,
+        )),
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<RegistryError>()
+        .is_some_and(|x| x.status == StatusCode::NOT_FOUND)
+}
+
+/// 拉取artifact，见[`pull_artifact_with`]
+pub async fn pull_artifact(
+    image: &Reference,
+    auth: &RegistryAuth,
+    dir: &Path,
+) -> Result<PulledArtifact> {
+    let session = RegistrySession::shared(auth)?;
+    let client = session.client();
+    pull_artifact_with(client, image, dir, &Cancel::new()).await
+}
+
+///
+/// 拉取artifact，将各layer写入`dir`：文件名取自`org.opencontainers.image.title`注解，
+/// 缺失或与其它layer重复时为layer的digest。artifact不写入本地镜像仓库
+pub async fn pull_artifact_with(
+    client: &RegistryClient,
+    image: &Reference,
+    dir: &Path,
+    cancel: &Cancel,
+) -> Result<PulledArtifact> {
+    let raw = cancel.run(client.pull_manifest_raw(image)).await?;
+    if Manifest::new(raw.data.clone()).is_index() {
+        bail!("{:?}为镜像索引，不是artifact", image.whole());
+    }
+    let manifest: ArtifactManifest =
+        serde_json::from_slice(&raw.data).context("解析artifact的manifest失败")?;
+    std::fs::create_dir_all(dir)?;
+    let titles: Vec<Option<String>> = manifest.layers.iter().map(layer_title).collect();
+    let mut files = Vec::with_capacity(manifest.layers.len());
+    for (layer, title) in manifest.layers.iter().zip(titles.iter()) {
+        // 多个layer的文件名相同时会互相覆盖，改用digest命名
+        let name = match title {
+            Some(title) if titles.iter().filter(|x| x.as_ref() == Some(title)).count() == 1 => {
+                title.clone()
+            }
+            Some(title) => {
+                warn!("artifact中有多个layer的文件名为{}，改用digest命名", title);
+                layer.digest.replacen(':', "-", 1)
+            }
+            None => layer.digest.replacen(':', "-", 1),
+        };
+        debug!("artifact layer[{}] -> {}", layer.digest, name);
+        let data = cancel.run(client.pull_blob(image, &layer.digest)).await?;
+        let path = dir.join(name);
+        std::fs::write(&path, data)?;
+        files.push(path);
+    }
+    Ok(PulledArtifact {
+        digest: raw.digest,
+        manifest,
+        files,
+    })
+}
+
+/// layer注解中的文件名，只取文件名部分，避免写到dir之外
+fn layer_title(layer: &OciDescriptor) -> Option<String> {
+    layer
+        .annotations
+        .as_ref()
+        .and_then(|x| x.get(TITLE_ANNOTATION))
+        .and_then(|x| Path::new(x).file_name())
+        .map(|x| x.to_string_lossy().to_string())
+}
+
+/// 列出本地镜像在registry上的referrers，见[`referrers_with`]
+pub async fn referrers(
+    image: &Reference,
+    auth: &RegistryAuth,
+    artifact_type: Option<&str>,
+) -> Result<Vec<Referrer>> {
+    let session = RegistrySession::shared(auth)?;
+    let client = session.client();
+    referrers_with(client, image, artifact_type).await
+}
+
+///
+/// 列出引用了本地镜像（按images.json中记录的digest）的artifact，可按`artifact_type`过滤。
+/// 优先使用referrers API，registry不支持时读取tag schema的referrers列表
+pub async fn referrers_with(
+    client: &RegistryClient,
+    image: &Reference,
+    artifact_type: Option<&str>,
+) -> Result<Vec<Referrer>> {
+    let digest = Repositories::init()?
+        .image_digest(image)
+        .ok_or(anyhow!("本地未找到镜像{:?}", image))?;
+    let referrers = match client.referrers(image, &digest, artifact_type).await? {
+        Some(referrers) => referrers,
+        None => {
+            debug!("registry不支持referrers API，读取tag schema");
+            let tag = Reference::with_tag(
+                image.registry().to_string(),
+                image.repository().to_string(),
+                referrers_tag(&digest),
+            );
+            tag_referrers(client, &tag)
+                .await?
+                .map(|x| x.manifests)
+                .unwrap_or_default()
+        }
+    };
+    // registry可能忽略过滤条件，统一在本地再过滤一次
+    Ok(referrers
+        .into_iter()
+        .filter(|x| artifact_type.is_none() || x.artifact_type.as_deref() == artifact_type)
+        .collect())
+}
+
+#[cfg(all(test, feature = "test-registry"))]
+mod test {
+    use super::{
+        pull_artifact_with, push_artifact_with, referrers_tag, referrers_with, Artifact,
+        ArtifactFile,
+    };
+    use crate::distribution::pull::pull_with;
+    use crate::distribution::push::{push_with, PushOptions};
+    use crate::distribution::test_registry::{TestRegistry, TestRegistryOptions};
+    use crate::image::build::build_test_image;
+    use crate::image::index::host_platform;
+    use crate::util::cancel::Cancel;
+    use crate::util::DigestPre;
+    use oci_distribution::secrets::RegistryAuth;
+
+    #[tokio::test]
+    async fn test_artifact_referrers() {
+        for referrers in [true, false] {
+            let (image, _) = build_test_image("localhost/test/artifact-subject:1").await;
+            let registry = TestRegistry::start(TestRegistryOptions {
+                referrers,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+            let client = registry.client(RegistryAuth::Anonymous).unwrap();
+            let target = registry.reference("test/subject:1");
+            let options = PushOptions::default();
+            push_with(&client, &image, &target, &options, &Cancel::new())
+                .await
+                .unwrap();
+            let digest = pull_with(&client, &target, &host_platform(), &Cancel::new())
+                .await
+                .unwrap();
+
+            let src = tempfile::tempdir().unwrap();
+            let sbom = src.path().join("sbom.spdx.json");
+            std::fs::write(&sbom, br#"{"spdxVersion":"SPDX-2.3"}"#).unwrap();
+            let artifact = Artifact {
+                artifact_type: "application/vnd.example.sbom.v1".to_string(),
+                files: vec![ArtifactFile::new(&sbom, "application/spdx+json")],
+                annotations: [("created-by".to_string(), "test".to_string())].into(),
+                subject: Some(target.clone()),
+            };
+            let sbom_ref = registry.reference("test/subject:sbom");
+            // 重复推送不会重复记录
+            for _ in 0..2 {
+                push_artifact_with(&client, &sbom_ref, &artifact, &Cancel::new())
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(
+                registry
+                    .manifest("test/subject", &referrers_tag(&digest.sha256_pre()))
+                    .is_some(),
+                !referrers
+            );
+
+            let list = referrers_with(&client, &target, None).await.unwrap();
+            assert_eq!(list.len(), 1, "referrers={}", referrers);
+            assert_eq!(
+                list[0].artifact_type.as_deref(),
+                Some("application/vnd.example.sbom.v1")
+            );
+            assert_eq!(list[0].annotations.as_ref().unwrap()["created-by"], "test");
+            let other = referrers_with(&client, &target, Some("application/other"))
+                .await
+                .unwrap();
+            assert!(other.is_empty());
+
+            let dest = tempfile::tempdir().unwrap();
+            let pulled = pull_artifact_with(&client, &sbom_ref, dest.path(), &Cancel::new())
+                .await
+                .unwrap();
+            assert_eq!(pulled.digest, list[0].digest);
+            assert_eq!(pulled.manifest.artifact_type(), artifact.artifact_type);
+            assert_eq!(pulled.files, vec![dest.path().join("sbom.spdx.json")]);
+            assert_eq!(
+                std::fs::read(&pulled.files[0]).unwrap(),
+                std::fs::read(&sbom).unwrap()
+            );
+
+            // 文件名重复的layer改用digest命名，不会互相覆盖
+            let other_dir = tempfile::tempdir().unwrap();
+            let other_sbom = other_dir.path().join("sbom.spdx.json");
+            std::fs::write(&other_sbom, br#"{"spdxVersion":"SPDX-2.2"}"#).unwrap();
+            let readme = src.path().join("README");
+            std::fs::write(&readme, "readme").unwrap();
+            let artifact = Artifact {
+                artifact_type: "application/vnd.example.sbom.v1".to_string(),
+                files: vec![
+                    ArtifactFile::new(&sbom, "application/spdx+json"),
+                    ArtifactFile::new(&other_sbom, "application/spdx+json"),
+                    ArtifactFile::new(&readme, "text/plain"),
+                ],
+                annotations: Default::default(),
+                subject: None,
+            };
+            let bundle_ref = registry.reference("test/subject:bundle");
+            push_artifact_with(&client, &bundle_ref, &artifact, &Cancel::new())
+                .await
+                .unwrap();
+            let dest = tempfile::tempdir().unwrap();
+            let pulled = pull_artifact_with(&client, &bundle_ref, dest.path(), &Cancel::new())
+                .await
+                .unwrap();
+            let names: Vec<String> = pulled
+                .manifest
+                .layers
+                .iter()
+                .map(|x| x.digest.replacen(':', "-", 1))
+                .collect();
+            assert_eq!(
+                pulled.files,
+                vec![
+                    dest.path().join(&names[0]),
+                    dest.path().join(&names[1]),
+                    dest.path().join("README"),
+                ]
+            );
+            for (file, src) in pulled.files.iter().zip([&sbom, &other_sbom, &readme]) {
+                assert_eq!(std::fs::read(file).unwrap(), std::fs::read(src).unwrap());
+            }
+            registry.shutdown().await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,893 @@
+use crate::distribution::artifact::Referrer;
+use crate::distribution::retry::RetryPolicy;
+use crate::util::lock;
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use oci_distribution::client::ClientProtocol;
+use oci_distribution::manifest::{
+    IMAGE_MANIFEST_LIST_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE,
+    OCI_IMAGE_MEDIA_TYPE,
+};
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use reqwest::header::{
+    HeaderMap, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, LINK, LOCATION, WWW_AUTHENTICATE,
+};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// registry返回manifest digest的响应头
+pub const DOCKER_CONTENT_DIGEST: &str = "Docker-Content-Digest";
+
+/// registry已处理manifest中subject字段时返回的响应头
+pub const OCI_SUBJECT: &str = "OCI-Subject";
+
+/// 拉取所需的token action
+const PULL: &str = "pull";
+/// 推送所需的token action
+const PUSH: &str = "pull,push";
+
+/// token响应未给出`expires_in`时的有效期，见distribution token规范
+const DEFAULT_TOKEN_EXPIRES_IN: u64 = 60;
+
+/// 删除所需的token action
+const DELETE: &str = "delete";
+
+/// 拉取manifest时可接受的媒体类型
+const MANIFEST_ACCEPT: [&str; 4] = [
+    OCI_IMAGE_MEDIA_TYPE,
+    OCI_IMAGE_INDEX_MEDIA_TYPE,
+    IMAGE_MANIFEST_MEDIA_TYPE,
+    IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+];
+
+/// registry返回的manifest原始内容
+pub struct RawManifest {
+    /// 原始字节，未经过任何解析与序列化
+    pub data: Vec<u8>,
+    /// Content-Type响应头
+    pub media_type: Option<String>,
+    /// registry返回的digest（带`sha256:`前缀）
+    pub digest: String,
+}
+
+/// 挂载blob的结果
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlobMount {
+    /// 已从源仓库挂载
+    Mounted,
+    /// registry未挂载，改为开启了普通上传会话，记录上传地址
+    Upload(String),
+}
+
+///
+/// OCI distribution API客户端。
+/// oci-distribution的Client不提供manifest原始字节，这里直接按API规范请求registry，
+/// 鉴权方式与其一致：按`WWW-Authenticate`质询获取Bearer token或使用Basic认证
+pub struct RegistryClient {
+    protocol: ClientProtocol,
+    http: reqwest::Client,
+    auth: RegistryAuth,
+    /// 已完成的鉴权，键为registry与scope
+    tokens: Mutex<HashMap<(String, String), Authorization>>,
+    /// 各registry最近一次的`WWW-Authenticate`质询，token过期时据此直接刷新
+    challenges: Mutex<HashMap<String, String>>,
+    /// 单个manifest、blob请求的重试策略
+    retry: RetryPolicy,
+}
+
+/// 请求registry时携带的认证信息
+#[derive(Clone)]
+enum Authorization {
+    Basic(String, String),
+    /// token及其过期时间
+    Bearer(String, Instant),
+}
+
+impl Authorization {
+    fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Authorization::Basic(username, password) => {
+                request.basic_auth(username, Some(password))
+            }
+            Authorization::Bearer(token, _) => request.bearer_auth(token),
+        }
+    }
+    fn is_expired(&self) -> bool {
+        match self {
+            Authorization::Basic(..) => false,
+            Authorization::Bearer(_, expires_at) => Instant::now() >= *expires_at,
+        }
+    }
+}
+
+impl RegistryClient {
+    pub fn new(protocol: ClientProtocol, auth: RegistryAuth) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .build()
+            .context("创建registry http客户端失败")?;
+        Ok(Self {
+            protocol,
+            http,
+            auth,
+            tokens: Mutex::new(HashMap::new()),
+            challenges: Mutex::new(HashMap::new()),
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    /// 设置重试策略，默认为`RetryPolicy::default()`
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// registry的API地址前缀
+    fn base_url(&self, registry: &str) -> String {
+        let scheme = match &self.protocol {
+            ClientProtocol::Http => "http",
+            ClientProtocol::Https => "https",
+            ClientProtocol::HttpsExcept(exceptions) => {
+                if exceptions.iter().any(|x| x == registry) {
+                    "http"
+                } else {
+                    "https"
+                }
+            }
+        };
+        format!("{}://{}/v2", scheme, registry)
+    }
+
+    fn manifest_url(&self, image: &Reference) -> String {
+        let reference = image.digest().or_else(|| image.tag()).unwrap_or("latest");
+        format!(
+            "{}/{}/manifests/{}",
+            self.base_url(image.resolve_registry()),
+            image.repository(),
+            reference
+        )
+    }
+
+    fn blob_url(&self, image: &Reference, digest: &str) -> String {
+        format!(
+            "{}/{}/blobs/{}",
+            self.base_url(image.resolve_registry()),
+            image.repository(),
+            digest
+        )
+    }
+
+    /// 拉取manifest原始字节，并校验内容与registry返回的digest、Reference中的digest一致
+    pub async fn pull_manifest_raw(&self, image: &Reference) -> Result<RawManifest> {
+        let url = self.manifest_url(image);
+        let (headers, data) = self
+            .retry
+            .run(&url, || async {
+                let response = self
+                    .send(image.resolve_registry(), &scope(image, PULL), || {
+                        self.http
+                            .get(&url)
+                            .header(ACCEPT, MANIFEST_ACCEPT.join(", "))
+                    })
+                    .await?;
+                let response = check_status(response, &url).await?;
+                let headers = response.headers().clone();
+                Ok((headers, response.bytes().await?.to_vec()))
+            })
+            .await?;
+        let computed = format!("sha256:{}", sha256::digest(data.as_slice()));
+        let digest = match header_str(&headers, DOCKER_CONTENT_DIGEST) {
+            Some(digest) => {
+                // 只有sha256能在本地校验，其它算法原样记录
+                if digest.starts_with("sha256:") && digest != computed {
+                    bail!(
+                        "manifest内容digest({})与registry返回的digest({})不一致: {}",
+                        computed,
+                        digest,
+                        url
+                    );
+                }
+                digest
+            }
+            None => computed.clone(),
+        };
+        if let Some(expected) = image.digest() {
+            if expected != computed {
+                bail!(
+                    "manifest内容digest({})与请求的digest({})不一致: {}",
+                    computed,
+                    expected,
+                    url
+                );
+            }
+        }
+        debug!("pull manifest {} -> {}", url, digest);
+        Ok(RawManifest {
+            data,
+            media_type: header_str(&headers, CONTENT_TYPE.as_str()),
+            digest,
+        })
+    }
+
+    ///
+    /// 获取manifest的digest（带`sha256:`前缀）：优先使用HEAD请求的`Docker-Content-Digest`，
+    /// registry未返回该响应头时退回拉取manifest内容计算
+    pub async fn fetch_manifest_digest(&self, image: &Reference) -> Result<String> {
+        let url = self.manifest_url(image);
+        let response = self
+            .retry
+            .run(&url, || async {
+                let response = self
+                    .send(image.resolve_registry(), &scope(image, PULL), || {
+                        self.http
+                            .head(&url)
+                            .header(ACCEPT, MANIFEST_ACCEPT.join(", "))
+                    })
+                    .await?;
+                check_status(response, &url).await
+            })
+            .await?;
+        match header_str(response.headers(), DOCKER_CONTENT_DIGEST) {
+            Some(digest) => Ok(digest),
+            None => Ok(self.pull_manifest_raw(image).await?.digest),
+        }
+    }
+
+    /// 拉取blob的响应，用于以数据流读取（不校验内容）
+    pub(crate) async fn pull_blob_response(
+        &self,
+        image: &Reference,
+        digest: &str,
+    ) -> Result<Response> {
+        let url = self.blob_url(image, digest);
+        self.retry
+            .run(&url, || async {
+                let response = self
+                    .send(image.resolve_registry(), &scope(image, PULL), || {
+                        self.http.get(&url)
+                    })
+                    .await?;
+                check_status(response, &url).await
+            })
+            .await
+    }
+
+    /// 拉取blob并校验sha256
+    pub async fn pull_blob(&self, image: &Reference, digest: &str) -> Result<Vec<u8>> {
+        let url = self.blob_url(image, digest);
+        let data = self
+            .retry
+            .run(&url, || async {
+                let response = self
+                    .send(image.resolve_registry(), &scope(image, PULL), || {
+                        self.http.get(&url)
+                    })
+                    .await?;
+                let response = check_status(response, &url).await?;
+                Ok(response.bytes().await?.to_vec())
+            })
+            .await?;
+        if digest.starts_with("sha256:") {
+            let computed = format!("sha256:{}", sha256::digest(data.as_slice()));
+            if computed != digest {
+                bail!(
+                    "blob内容digest({})与请求的digest({})不一致",
+                    computed,
+                    digest
+                );
+            }
+        }
+        Ok(data)
+    }
+
+    /// 查询registry上是否已存在blob
+    pub async fn blob_exists(&self, image: &Reference, digest: &str) -> Result<bool> {
+        let url = self.blob_url(image, digest);
+        self.retry
+            .run(&url, || async {
+                let response = self
+                    .send(image.resolve_registry(), &scope(image, PUSH), || {
+                        self.http.head(&url)
+                    })
+                    .await?;
+                if response.status() == StatusCode::NOT_FOUND {
+                    return Ok(false);
+                }
+                check_status(response, &url).await?;
+                Ok(true)
+            })
+            .await
+    }
+
+    ///
+    /// 从同一registry的其它仓库挂载blob，避免重复上传。
+    /// registry不支持挂载或源仓库中没有该blob时会改为开启普通上传会话（202），
+    /// 返回该会话的上传地址，调用方须上传至该会话或以[`Self::cancel_upload`]取消
+    pub async fn mount_blob(
+        &self,
+        image: &Reference,
+        digest: &str,
+        from: &str,
+    ) -> Result<BlobMount> {
+        let url = format!(
+            "{}/{}/blobs/uploads/",
+            self.base_url(image.resolve_registry()),
+            image.repository()
+        );
+        let scope = format!("{} repository:{}:{}", scope(image, PUSH), from, PULL);
+        let response = self
+            .retry
+            .run(&url, || async {
+                let response = self
+                    .send(image.resolve_registry(), &scope, || {
+                        self.http
+                            .post(&url)
+                            .query(&[("mount", digest), ("from", from)])
+                    })
+                    .await?;
+                check_status(response, &url).await
+            })
+            .await?;
+        if response.status() == StatusCode::CREATED {
+            return Ok(BlobMount::Mounted);
+        }
+        let location = header_str(response.headers(), LOCATION.as_str())
+            .ok_or(anyhow!("registry开启上传会话时未返回Location: {}", url))?;
+        Ok(BlobMount::Upload(
+            self.absolute_url(image.resolve_registry(), &location),
+        ))
+    }
+
+    /// 取消上传会话
+    pub async fn cancel_upload(&self, image: &Reference, location: &str) -> Result<()> {
+        let response = self
+            .send(image.resolve_registry(), &scope(image, PUSH), || {
+                self.http.delete(location)
+            })
+            .await?;
+        check_status(response, location).await?;
+        debug!("cancel upload {}", location);
+        Ok(())
+    }
+
+    /// 上传blob（单次PUT完成上传）
+    pub async fn upload_blob(&self, image: &Reference, digest: &str, data: &[u8]) -> Result<()> {
+        self.upload_blob_in(image, digest, data, None).await
+    }
+
+    ///
+    /// 上传blob，`location`为已开启的上传会话（如挂载时registry开启的会话），为None时开启新会话。
+    /// 失败的上传会话不可续用，重试时重新开启会话
+    pub async fn upload_blob_in(
+        &self,
+        image: &Reference,
+        digest: &str,
+        data: &[u8],
+        location: Option<String>,
+    ) -> Result<()> {
+        let url = self.blob_url(image, digest);
+        let pending = Mutex::new(location);
+        self.retry
+            .run(&url, || async {
+                let opened = lock(&pending).take();
+                let location = match opened {
+                    Some(location) => location,
+                    None => self.start_upload(image).await?,
+                };
+                let response = self
+                    .send(image.resolve_registry(), &scope(image, PUSH), || {
+                        self.http
+                            .put(&location)
+                            .query(&[("digest", digest)])
+                            .header(CONTENT_TYPE, "application/octet-stream")
+                            .body(data.to_vec())
+                    })
+                    .await?;
+                check_status(response, &location).await
+            })
+            .await?;
+        debug!("upload blob {} ({} bytes)", digest, data.len());
+        Ok(())
+    }
+
+    ///
+    /// 以数据流上传blob，`location`为已开启的上传会话。数据流无法重发，只重试开启上传会话；
+    /// 开启上传会话时已完成鉴权，PUT请求直接携带已缓存的认证信息。
+    /// PUT失败（包括token过期返回401）时既不重试也不重新鉴权，由调用方重新提供数据上传
+    pub(crate) async fn upload_blob_stream(
+        &self,
+        image: &Reference,
+        digest: &str,
+        size: u64,
+        body: reqwest::Body,
+        location: Option<String>,
+    ) -> Result<()> {
+        let url = self.blob_url(image, digest);
+        let location = match location {
+            Some(location) => location,
+            None => self.retry.run(&url, || self.start_upload(image)).await?,
+        };
+        let mut request = self
+            .http
+            .put(&location)
+            .query(&[("digest", digest)])
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header(CONTENT_LENGTH, size)
+            .body(body);
+        let key = (image.resolve_registry().to_string(), scope(image, PUSH));
+        if let Some(authorization) = lock(&self.tokens).get(&key) {
+            request = authorization.apply(request);
+        }
+        let response = request.send().await?;
+        check_status(response, &location).await?;
+        debug!("upload blob stream {} ({} bytes)", digest, size);
+        Ok(())
+    }
+
+    /// 开启上传会话，返回上传地址
+    async fn start_upload(&self, image: &Reference) -> Result<String> {
+        let url = format!(
+            "{}/{}/blobs/uploads/",
+            self.base_url(image.resolve_registry()),
+            image.repository()
+        );
+        let response = self
+            .send(image.resolve_registry(), &scope(image, PUSH), || {
+                self.http.post(&url)
+            })
+            .await?;
+        let response = check_status(response, &url).await?;
+        let location = header_str(response.headers(), LOCATION.as_str())
+            .ok_or(anyhow!("registry开启上传会话时未返回Location: {}", url))?;
+        Ok(self.absolute_url(image.resolve_registry(), &location))
+    }
+
+    /// 上传manifest原始字节，返回manifest的URL
+    pub async fn push_manifest_raw(
+        &self,
+        image: &Reference,
+        data: &[u8],
+        media_type: &str,
+    ) -> Result<String> {
+        Ok(self.push_manifest_subject(image, data, media_type).await?.0)
+    }
+
+    ///
+    /// 上传带`subject`字段的manifest，返回manifest的URL与`OCI-Subject`响应头。
+    /// registry支持referrers API时通过该响应头表明已处理subject，否则需由客户端维护tag schema索引
+    pub async fn push_manifest_subject(
+        &self,
+        image: &Reference,
+        data: &[u8],
+        media_type: &str,
+    ) -> Result<(String, Option<String>)> {
+        let url = self.manifest_url(image);
+        let response = self
+            .retry
+            .run(&url, || async {
+                let response = self
+                    .send(image.resolve_registry(), &scope(image, PUSH), || {
+                        self.http
+                            .put(&url)
+                            .header(CONTENT_TYPE, media_type)
+                            .body(data.to_vec())
+                    })
+                    .await?;
+                check_status(response, &url).await
+            })
+            .await?;
+        let subject = header_str(response.headers(), OCI_SUBJECT);
+        let url = header_str(response.headers(), LOCATION.as_str())
+            .map(|x| self.absolute_url(image.resolve_registry(), &x))
+            .unwrap_or(url);
+        Ok((url, subject))
+    }
+
+    ///
+    /// 通过referrers API列出引用了`digest`的manifest，`artifact_type`为registry端的过滤条件（registry可能忽略）。
+    /// registry不支持referrers API（返回404）时返回None
+    pub async fn referrers(
+        &self,
+        image: &Reference,
+        digest: &str,
+        artifact_type: Option<&str>,
+    ) -> Result<Option<Vec<Referrer>>> {
+        #[derive(Deserialize)]
+        struct Index {
+            #[serde(default)]
+            manifests: Vec<Referrer>,
+        }
+        let url = format!(
+            "{}/{}/referrers/{}",
+            self.base_url(image.resolve_registry()),
+            image.repository(),
+            digest
+        );
+        let data = self
+            .retry
+            .run(&url, || async {
+                let response = self
+                    .send(image.resolve_registry(), &scope(image, PULL), || {
+                        let request = self
+                            .http
+                            .get(&url)
+                            .header(ACCEPT, OCI_IMAGE_INDEX_MEDIA_TYPE);
+                        match artifact_type {
+                            Some(artifact_type) => {
+                                request.query(&[("artifactType", artifact_type)])
+                            }
+                            None => request,
+                        }
+                    })
+                    .await?;
+                if response.status() == StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
+                let response = check_status(response, &url).await?;
+                Ok(Some(response.bytes().await?.to_vec()))
+            })
+            .await?;
+        match data {
+            Some(data) => {
+                let index: Index =
+                    serde_json::from_slice(&data).context("解析referrers列表失败")?;
+                Ok(Some(index.manifests))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// 按digest删除manifest，`image`必须包含digest
+    pub async fn delete_manifest(&self, image: &Reference) -> Result<()> {
+        if image.digest().is_none() {
+            bail!("删除manifest需要digest: {:?}", image);
+        }
+        let url = self.manifest_url(image);
+        self.retry
+            .run(&url, || async {
+                let response = self
+                    .send(image.resolve_registry(), &scope(image, DELETE), || {
+                        self.http.delete(&url)
+                    })
+                    .await?;
+                check_status(response, &url).await
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// 列出仓库的所有tag，按`Link`响应头翻页；`page_size`为每页数量
+    pub async fn list_tags(
+        &self,
+        image: &Reference,
+        page_size: Option<usize>,
+    ) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct TagList {
+            tags: Option<Vec<String>>,
+        }
+        let url = format!(
+            "{}/{}/tags/list",
+            self.base_url(image.resolve_registry()),
+            image.repository()
+        );
+        let pages = self
+            .get_pages(
+                image.resolve_registry(),
+                &scope(image, PULL),
+                url,
+                page_size,
+            )
+            .await?;
+        let mut tags = Vec::new();
+        for page in pages {
+            let list: TagList = serde_json::from_slice(&page).context("解析tag列表失败")?;
+            tags.extend(list.tags.unwrap_or_default());
+        }
+        Ok(tags)
+    }
+
+    /// 列出registry中的所有仓库（`_catalog`，需registry支持），按`Link`响应头翻页
+    pub async fn catalog(&self, registry: &str, page_size: Option<usize>) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct Catalog {
+            repositories: Option<Vec<String>>,
+        }
+        let url = format!("{}/_catalog", self.base_url(registry));
+        let pages = self
+            .get_pages(registry, "registry:catalog:*", url, page_size)
+            .await?;
+        let mut repositories = Vec::new();
+        for page in pages {
+            let list: Catalog = serde_json::from_slice(&page).context("解析仓库列表失败")?;
+            repositories.extend(list.repositories.unwrap_or_default());
+        }
+        Ok(repositories)
+    }
+
+    /// 依次请求分页列表，返回各页响应体
+    async fn get_pages(
+        &self,
+        registry: &str,
+        scope: &str,
+        url: String,
+        page_size: Option<usize>,
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut pages = Vec::new();
+        let mut next = Some(url);
+        let mut first = true;
+        while let Some(url) = next.take() {
+            let (headers, page) = self
+                .retry
+                .run(&url, || async {
+                    let response = self
+                        .send(registry, scope, || {
+                            let request = self.http.get(&url);
+                            match page_size {
+                                // 后续页的URL中已包含分页参数
+                                Some(n) if first => request.query(&[("n", n)]),
+                                _ => request,
+                            }
+                        })
+                        .await?;
+                    let response = check_status(response, &url).await?;
+                    let headers = response.headers().clone();
+                    Ok((headers, response.bytes().await?.to_vec()))
+                })
+                .await?;
+            next = header_str(&headers, LINK.as_str())
+                .and_then(|x| parse_next_link(&x))
+                .map(|x| self.absolute_url(registry, &x));
+            pages.push(page);
+            first = false;
+        }
+        Ok(pages)
+    }
+
+    /// registry返回的Location可能为相对路径
+    fn absolute_url(&self, registry: &str, location: &str) -> String {
+        if location.starts_with("http://") || location.starts_with("https://") {
+            location.to_string()
+        } else {
+            let base = self.base_url(registry);
+            let host = base.trim_end_matches("/v2");
+            format!("{}{}", host, location)
+        }
+    }
+
+    ///
+    /// 发送请求：先携带已缓存的Authorization，401时按质询完成鉴权后重试一次。
+    /// 缓存的token已过期时按该registry上次的质询先行刷新，避免多一次401往返。
+    /// `scope`为以空格分隔的token scope，`build`每次调用生成新的请求
+    async fn send<F>(&self, registry: &str, scope: &str, build: F) -> Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let key = (registry.to_string(), scope.to_string());
+        let cached = self.cached_authorization(&key).await?;
+        let mut request = build();
+        if let Some(authorization) = &cached {
+            request = authorization.apply(request);
+        }
+        let response = request.send().await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        // 没有质询或无可用的认证信息时原样返回401，由调用方转为RegistryError
+        let challenge = match header_str(response.headers(), WWW_AUTHENTICATE.as_str()) {
+            Some(challenge) => challenge,
+            None => return Ok(response),
+        };
+        lock(&self.challenges).insert(registry.to_string(), challenge.clone());
+        let authorization = match self.authorize(&challenge, scope).await? {
+            Some(authorization) => authorization,
+            None => return Ok(response),
+        };
+        lock(&self.tokens).insert(key, authorization.clone());
+        Ok(authorization.apply(build()).send().await?)
+    }
+
+    /// 取缓存的Authorization，已过期的Bearer token按上次的质询刷新，刷新失败时不携带认证
+    async fn cached_authorization(&self, key: &(String, String)) -> Result<Option<Authorization>> {
+        let cached = lock(&self.tokens).get(key).cloned();
+        match cached {
+            Some(authorization) if authorization.is_expired() => {}
+            cached => return Ok(cached),
+        }
+        lock(&self.tokens).remove(key);
+        let challenge = lock(&self.challenges).get(&key.0).cloned();
+        let challenge = match challenge {
+            Some(challenge) => challenge,
+            None => return Ok(None),
+        };
+        debug!("registry token已过期，重新获取: {} {}", key.0, key.1);
+        let refreshed = match self.authorize(&challenge, &key.1).await {
+            Ok(refreshed) => refreshed,
+            Err(e) => {
+                debug!("刷新registry token失败，按质询重新鉴权：{:?}", e);
+                return Ok(None);
+            }
+        };
+        if let Some(authorization) = &refreshed {
+            lock(&self.tokens).insert(key.clone(), authorization.clone());
+        }
+        Ok(refreshed)
+    }
+
+    /// 按`WWW-Authenticate`质询完成鉴权，要求Basic认证但未提供用户名密码时返回None
+    async fn authorize(&self, challenge: &str, scope: &str) -> Result<Option<Authorization>> {
+        let (scheme, params) = parse_challenge(challenge);
+        match scheme.to_ascii_lowercase().as_str() {
+            "basic" => match &self.auth {
+                RegistryAuth::Basic(username, password) => Ok(Some(Authorization::Basic(
+                    username.clone(),
+                    password.clone(),
+                ))),
+                RegistryAuth::Anonymous => Ok(None),
+            },
+            "bearer" => {
+                let realm = params
+                    .get("realm")
+                    .ok_or(anyhow!("Bearer质询中缺少realm: {}", challenge))?;
+                let mut query: Vec<(&str, String)> = scope
+                    .split_whitespace()
+                    .map(|x| ("scope", x.to_string()))
+                    .collect();
+                if let Some(service) = params.get("service") {
+                    query.push(("service", service.clone()));
+                }
+                let mut request = self.http.get(realm).query(&query);
+                if let RegistryAuth::Basic(username, password) = &self.auth {
+                    request = request.basic_auth(username, Some(password));
+                }
+                let response = request.send().await?;
+                let response = check_status(response, realm).await?;
+                let token: TokenResponse = serde_json::from_slice(&response.bytes().await?)
+                    .context("解析registry token失败")?;
+                // 提前十分之一有效期视为过期，留出请求途中的余量
+                let expires_in = token.expires_in.unwrap_or(DEFAULT_TOKEN_EXPIRES_IN);
+                let expires_at = Instant::now() + Duration::from_secs(expires_in).mul_f64(0.9);
+                let token = token
+                    .token
+                    .or(token.access_token)
+                    .ok_or(anyhow!("registry token响应中没有token"))?;
+                Ok(Some(Authorization::Bearer(token, expires_at)))
+            }
+            _ => bail!("不支持的registry认证方式: {}", challenge),
+        }
+    }
+}
+
+/// 仓库的token scope，如`repository:library/alpine:pull`
+fn scope(image: &Reference, action: &str) -> String {
+    format!("repository:{}:{}", image.repository(), action)
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+    /// 有效期（秒）
+    expires_in: Option<u64>,
+}
+
+/// registry返回的错误响应，可通过`anyhow::Error::downcast_ref`取得
+#[derive(Debug, Clone)]
+pub struct RegistryError {
+    pub url: String,
+    pub status: StatusCode,
+    /// 响应体中的错误码，如`BLOB_UNKNOWN`、`MANIFEST_INVALID`
+    pub codes: Vec<String>,
+    pub body: String,
+}
+
+impl RegistryError {
+    /// 认证失败（401/403）
+    pub fn is_unauthorized(&self) -> bool {
+        self.status == StatusCode::UNAUTHORIZED || self.status == StatusCode::FORBIDDEN
+    }
+    /// 是否包含指定错误码
+    pub fn has_code(&self, code: &str) -> bool {
+        self.codes.iter().any(|x| x == code)
+    }
+}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "请求{}失败[{}]: {}", self.url, self.status, self.body)
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+/// 非2xx响应转为RegistryError，附带响应体便于排查
+async fn check_status(response: Response, url: &str) -> Result<Response> {
+    #[derive(Deserialize)]
+    struct Errors {
+        errors: Vec<ErrorCode>,
+    }
+    #[derive(Deserialize)]
+    struct ErrorCode {
+        code: String,
+    }
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    let codes = serde_json::from_str::<Errors>(&body)
+        .map(|x| x.errors.into_iter().map(|x| x.code).collect())
+        .unwrap_or_default();
+    Err(RegistryError {
+        url: url.to_string(),
+        status,
+        codes,
+        body,
+    }
+    .into())
+}
+
+/// 解析`Link`响应头中`rel="next"`的地址，如`</v2/foo/tags/list?n=10&last=b>; rel="next"`
+fn parse_next_link(link: &str) -> Option<String> {
+    link.split(',').find_map(|item| {
+        let (url, params) = item.trim().split_once(';')?;
+        let is_next = params
+            .split(';')
+            .any(|x| x.trim().replace(' ', "") == r#"rel="next""# || x.trim() == "rel=next");
+        let url = url.trim().strip_prefix('<')?.strip_suffix('>')?;
+        is_next.then(|| url.to_string())
+    })
+}
+
+fn header_str(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_string())
+}
+
+/// 解析`WWW-Authenticate`：返回认证方式与参数，如`Bearer realm="..",service=".."`
+fn parse_challenge(challenge: &str) -> (String, HashMap<String, String>) {
+    let challenge = challenge.trim();
+    let (scheme, rest) = challenge.split_once(' ').unwrap_or((challenge, ""));
+    let regex = regex::Regex::new(r#"([A-Za-z_]+)=(?:"([^"]*)"|([^,\s]*))"#).unwrap();
+    let params = regex
+        .captures_iter(rest)
+        .map(|capts| {
+            let value = capts
+                .get(2)
+                .or_else(|| capts.get(3))
+                .map_or("", |x| x.as_str());
+            (capts[1].to_string(), value.to_string())
+        })
+        .collect();
+    (scheme.to_string(), params)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_challenge, parse_next_link};
+
+    #[test]
+    fn test_parse_next_link() {
+        assert_eq!(
+            parse_next_link(r#"</v2/foo/tags/list?n=2&last=b>; rel="next""#),
+            Some("/v2/foo/tags/list?n=2&last=b".to_string())
+        );
+        assert_eq!(parse_next_link(r#"</v2/foo/tags/list>; rel="prev""#), None);
+    }
+
+    #[test]
+    fn test_parse_challenge() {
+        let (scheme, params) = parse_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/alpine:pull""#,
+        );
+        assert_eq!(scheme, "Bearer");
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/alpine:pull");
+    }
+}
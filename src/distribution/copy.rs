@@ -0,0 +1,512 @@
+use crate::distribution::client::{BlobMount, RawManifest, RegistryClient};
+use crate::distribution::push::{cancel_session, BlobPush};
+use crate::distribution::session::RegistrySession;
+use crate::filesystem::FileSystem;
+use crate::image::manifest::Manifest;
+use crate::util::DigestPre;
+use anyhow::{bail, Result};
+use log::debug;
+use oci_distribution::manifest::{OciDescriptor, OCI_IMAGE_MEDIA_TYPE};
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+
+/// 复制选项
+#[derive(Debug, Default, Clone)]
+pub struct CopyOptions {
+    ///
+    /// 经本地存储中转：blob与manifest保存至本地，本地已有的blob不再下载。
+    /// 不更新images.json，需要在本地使用镜像时仍应调用pull
+    pub cache: bool,
+}
+
+/// 复制结果
+#[derive(Debug, Default)]
+pub struct CopyReport {
+    /// 复制的manifest或镜像索引的digest（带`sha256:`前缀），与源registry一致
+    pub manifest_digest: String,
+    /// 镜像索引中复制的各manifest的digest
+    pub manifests: Vec<String>,
+    /// 各blob的复制结果，键为blob的digest
+    pub blobs: Vec<(String, BlobPush)>,
+    /// 实际上传的字节数
+    pub transferred_bytes: u64,
+}
+
+impl CopyReport {
+    fn add(&mut self, digest: String, outcome: BlobPush) {
+        if let BlobPush::Uploaded(size) = &outcome {
+            self.transferred_bytes += size;
+        }
+        self.blobs.push((digest, outcome));
+    }
+}
+
+///
+/// 在registry之间复制镜像，不展开layer：manifest按原始字节复制，digest保持不变。
+/// 镜像索引连同其中所有平台的manifest一起复制
+pub async fn copy(
+    src: &Reference,
+    dst: &Reference,
+    src_auth: &RegistryAuth,
+    dst_auth: &RegistryAuth,
+    options: &CopyOptions,
+) -> Result<CopyReport> {
+    let src_session = RegistrySession::shared(src_auth)?;
+    let dst_session = RegistrySession::shared(dst_auth)?;
+    copy_with(
+        src_session.client(),
+        dst_session.client(),
+        src,
+        dst,
+        options,
+    )
+    .await
+}
+
+/// 使用指定的registry客户端复制镜像
+pub async fn copy_with(
+    src_client: &RegistryClient,
+    dst_client: &RegistryClient,
+    src: &Reference,
+    dst: &Reference,
+    options: &CopyOptions,
+) -> Result<CopyReport> {
+    debug!("复制镜像: {:?} -> {:?}", src, dst);
+    let copier = Copier {
+        src_client,
+        dst_client,
+        options: options.clone(),
+    };
+    let mut report = CopyReport::default();
+
+    let top = copier.src_client.pull_manifest_raw(src).await?;
+    report.manifest_digest = top.digest.clone();
+    let manifest = Manifest::new(top.data.clone());
+    if manifest.is_index() {
+        for entry in manifest.to_oci_index()?.manifests.iter() {
+            let src_entry = with_digest(src, &entry.digest);
+            let dst_entry = with_digest(dst, &entry.digest);
+            let raw = copier.src_client.pull_manifest_raw(&src_entry).await?;
+            if Manifest::new(raw.data.clone()).is_index() {
+                bail!("镜像索引{}中的条目{}仍为镜像索引", top.digest, entry.digest)
+            }
+            copier
+                .copy_manifest(&src_entry, &dst_entry, raw, &mut report)
+                .await?;
+            report.manifests.push(entry.digest.clone());
+        }
+        copier.push_manifest(dst, top).await?;
+    } else {
+        copier.copy_manifest(src, dst, top, &mut report).await?;
+    }
+    debug!(
+        "复制完成: {} 上传{}字节",
+        report.manifest_digest, report.transferred_bytes
+    );
+    Ok(report)
+}
+
+struct Copier<'a> {
+    src_client: &'a RegistryClient,
+    dst_client: &'a RegistryClient,
+    options: CopyOptions,
+}
+
+impl Copier<'_> {
+    /// 复制manifest引用的blob，再推送manifest
+    async fn copy_manifest(
+        &self,
+        src: &Reference,
+        dst: &Reference,
+        raw: RawManifest,
+        report: &mut CopyReport,
+    ) -> Result<()> {
+        let manifest = Manifest::new(raw.data.clone()).to_oci_manifest()?;
+        let outcome = self.copy_blob(src, dst, &manifest.config, true).await?;
+        report.add(manifest.config.digest.clone(), outcome);
+        for layer in manifest.layers.iter() {
+            let outcome = self.copy_blob(src, dst, layer, false).await?;
+            report.add(layer.digest.clone(), outcome);
+        }
+        self.push_manifest(dst, raw).await
+    }
+
+    async fn push_manifest(&self, dst: &Reference, raw: RawManifest) -> Result<()> {
+        let manifest = Manifest::new(raw.data);
+        let media_type = manifest
+            .media_type()
+            .or(raw.media_type)
+            .unwrap_or(OCI_IMAGE_MEDIA_TYPE.to_string());
+        if self.options.cache {
+            FileSystem.save_manifest(&raw.digest.get_digest()?, manifest.data())?;
+        }
+        self.dst_client
+            .push_manifest_raw(dst, manifest.data(), &media_type)
+            .await?;
+        Ok(())
+    }
+
+    async fn copy_blob(
+        &self,
+        src: &Reference,
+        dst: &Reference,
+        descriptor: &OciDescriptor,
+        is_config: bool,
+    ) -> Result<BlobPush> {
+        let digest = descriptor.digest.as_str();
+        if self.dst_client.blob_exists(dst, digest).await? {
+            debug!("blob[{}]已存在，跳过", digest);
+            return Ok(BlobPush::Exists);
+        }
+        // 挂载失败时registry开启的上传会话，用于随后的上传
+        let mut session = None;
+        if src.resolve_registry() == dst.resolve_registry() && src.repository() != dst.repository()
+        {
+            match self
+                .dst_client
+                .mount_blob(dst, digest, src.repository())
+                .await
+            {
+                Ok(BlobMount::Mounted) => {
+                    debug!("blob[{}]从{}挂载", digest, src.repository());
+                    return Ok(BlobPush::Mounted(src.repository().to_string()));
+                }
+                Ok(BlobMount::Upload(location)) => session = Some(location),
+                Err(e) => debug!("blob[{}]从{}挂载失败：{:?}", digest, src.repository(), e),
+            }
+        }
+        match self
+            .upload_blob(src, dst, descriptor, is_config, session.clone())
+            .await
+        {
+            Ok(outcome) => Ok(outcome),
+            Err(e) => {
+                // 上传失败时取消挂载开启的会话，会话已被使用时取消失败只记录日志
+                cancel_session(self.dst_client, dst, session).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn upload_blob(
+        &self,
+        src: &Reference,
+        dst: &Reference,
+        descriptor: &OciDescriptor,
+        is_config: bool,
+        session: Option<String>,
+    ) -> Result<BlobPush> {
+        let digest = descriptor.digest.as_str();
+        if self.options.cache {
+            let data = self.cached_blob(src, descriptor, is_config).await?;
+            self.dst_client
+                .upload_blob_in(dst, digest, &data, session)
+                .await?;
+            return Ok(BlobPush::Uploaded(data.len() as u64));
+        }
+        // 直接将源registry的响应体作为上传数据流，由目标registry校验digest
+        let response = self.src_client.pull_blob_response(src, digest).await?;
+        let size = response.content_length().unwrap_or(descriptor.size as u64);
+        let body = reqwest::Body::wrap_stream(response.bytes_stream());
+        if let Err(e) = self
+            .dst_client
+            .upload_blob_stream(dst, digest, size, body, session)
+            .await
+        {
+            // 数据流无法重发：上传失败（如token过期返回401、连接中断）时重新下载到内存，
+            // 按重试策略开启新会话上传
+            debug!("blob[{}]流式上传失败，改为缓冲后上传：{:?}", digest, e);
+            let data = self.src_client.pull_blob(src, digest).await?;
+            self.dst_client
+                .upload_blob_in(dst, digest, &data, None)
+                .await?;
+            return Ok(BlobPush::Uploaded(data.len() as u64));
+        }
+        Ok(BlobPush::Uploaded(size))
+    }
+
+    /// 读取本地存储中的blob，不存在或内容与digest不一致时从源registry下载并保存
+    async fn cached_blob(
+        &self,
+        src: &Reference,
+        descriptor: &OciDescriptor,
+        is_config: bool,
+    ) -> Result<Vec<u8>> {
+        let hex = descriptor.digest.get_digest()?;
+        let path = if is_config {
+            FileSystem.config_sha256()?
+        } else {
+            FileSystem.layer_blobs()?
+        }
+        .join(&hex);
+        if let Ok(data) = std::fs::read(&path) {
+            if sha256::digest(data.as_slice()) == hex {
+                debug!("blob[{}]使用本地缓存", descriptor.digest);
+                return Ok(data);
+            }
+            debug!(
+                "本地blob[{}]内容与digest不一致，重新下载",
+                descriptor.digest
+            );
+        }
+        let data = self.src_client.pull_blob(src, &descriptor.digest).await?;
+        if is_config {
+            FileSystem.save_config(&hex, &data)?;
+        } else {
+            FileSystem.save_layer(&hex, &data)?;
+        }
+        Ok(data)
+    }
+}
+
+fn with_digest(image: &Reference, digest: &str) -> Reference {
+    Reference::with_digest(
+        image.registry().to_string(),
+        image.repository().to_string(),
+        digest.to_string(),
+    )
+}
+
+#[cfg(all(test, feature = "test-registry"))]
+mod test {
+    use super::{copy_with, CopyOptions};
+    use crate::args::BuildArgs;
+    use crate::distribution::push::{push_with, BlobPush, PushOptions};
+    use crate::distribution::test_registry::{Failure, TestRegistry, TestRegistryOptions};
+    use crate::filesystem::{test_home, FileSystem};
+    use crate::image::build::config::instructions::{Copy, Dest, Kind};
+    use crate::image::build::config::{BuildConfig, PlatformCopys};
+    use crate::image::build::{build, build_test_image};
+    use crate::image::index::host_platform;
+    use crate::image::manifest::Manifest;
+    use crate::util::cancel::Cancel;
+    use crate::util::DigestPre;
+    use hyper::{Method, StatusCode};
+    use oci_distribution::secrets::RegistryAuth;
+    use oci_distribution::Reference;
+
+    /// 推送本地镜像到测试registry
+    async fn push(registry: &TestRegistry, image: &Reference, name: &str) -> Reference {
+        let client = registry.client(RegistryAuth::Anonymous).unwrap();
+        let target = registry.reference(name);
+        push_with(
+            &client,
+            image,
+            &target,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+        .unwrap();
+        target
+    }
+
+    #[tokio::test]
+    async fn test_copy_mount() {
+        let (image, digest) = build_test_image("localhost/test/copy-mount:1").await;
+        let registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let client = registry.client(RegistryAuth::Anonymous).unwrap();
+        let target = registry.reference("test/app:1");
+        push_with(
+            &client,
+            &image,
+            &target,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+        .unwrap();
+
+        // 同一registry的不同仓库之间通过挂载复制
+        let copied = registry.reference("test/copied:1");
+        let report = copy_with(&client, &client, &target, &copied, &CopyOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(report.manifest_digest, digest);
+        assert_eq!(report.transferred_bytes, 0);
+        assert!(report
+            .blobs
+            .iter()
+            .all(|(_, x)| matches!(x, BlobPush::Mounted(_))));
+        registry.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_copy_stream() {
+        let (image, digest) = build_test_image("localhost/test/copy-stream:1").await;
+        let src_registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let dst_registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let src_client = src_registry.client(RegistryAuth::Anonymous).unwrap();
+        let dst_client = dst_registry.client(RegistryAuth::Anonymous).unwrap();
+        let src = push(&src_registry, &image, "test/src:1").await;
+
+        // 跨registry复制时blob以数据流上传
+        let dst = dst_registry.reference("test/dst:1");
+        let report = copy_with(
+            &src_client,
+            &dst_client,
+            &src,
+            &dst,
+            &CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.manifest_digest, digest);
+        assert!(report.transferred_bytes > 0);
+        assert!(report
+            .blobs
+            .iter()
+            .all(|(blob, x)| matches!(x, BlobPush::Uploaded(_))
+                && dst_registry.has_blob("test/dst", blob)));
+        assert_eq!(
+            dst_registry.manifest("test/dst", "1"),
+            src_registry.manifest("test/src", "1")
+        );
+
+        // 数据流上传失败（如token过期）时缓冲后重新上传
+        dst_registry.fail(Failure {
+            method: Some(Method::PUT),
+            path: "/blobs/uploads/".to_string(),
+            status: StatusCode::UNAUTHORIZED,
+            times: 1,
+        });
+        let retried = dst_registry.reference("test/retried:1");
+        let report = copy_with(
+            &src_client,
+            &dst_client,
+            &src,
+            &retried,
+            &CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.manifest_digest, digest);
+        assert!(report
+            .blobs
+            .iter()
+            .all(|(blob, _)| dst_registry.has_blob("test/retried", blob)));
+        src_registry.shutdown().await.unwrap();
+        dst_registry.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_copy_cache() {
+        let (image, digest) = build_test_image("localhost/test/copy-cache:1").await;
+        let src_registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let dst_registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let src = push(&src_registry, &image, "test/src:1").await;
+
+        // 本地缺少config、layer内容损坏：都从源registry下载并保存
+        let manifest = Manifest::load(&digest.get_digest().unwrap())
+            .unwrap()
+            .to_oci_manifest()
+            .unwrap();
+        let config_hex = manifest.config.digest.get_digest().unwrap();
+        let layer_hex = manifest.layers[0].digest.get_digest().unwrap();
+        let config_path = FileSystem.config_sha256().unwrap().join(&config_hex);
+        let layer_path = FileSystem.layer_blobs().unwrap().join(&layer_hex);
+        std::fs::remove_file(&config_path).unwrap();
+        std::fs::write(&layer_path, b"corrupted").unwrap();
+
+        let dst = dst_registry.reference("test/dst:1");
+        let options = CopyOptions { cache: true };
+        let report = copy_with(
+            &src_registry.client(RegistryAuth::Anonymous).unwrap(),
+            &dst_registry.client(RegistryAuth::Anonymous).unwrap(),
+            &src,
+            &dst,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.manifest_digest, digest);
+        assert_eq!(
+            sha256::digest(std::fs::read(&config_path).unwrap().as_slice()),
+            config_hex
+        );
+        assert_eq!(
+            sha256::digest(std::fs::read(&layer_path).unwrap().as_slice()),
+            layer_hex
+        );
+        assert!(dst_registry.has_blob("test/dst", &manifest.layers[0].digest));
+        src_registry.shutdown().await.unwrap();
+        dst_registry.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_copy_index() {
+        test_home();
+        let src_dir = tempfile::tempdir().unwrap();
+        let app = src_dir.path().join("app");
+        std::fs::write(&app, "copy index").unwrap();
+        let host = host_platform();
+        let mut other = host.clone();
+        other.architecture = "other".to_string();
+        let image: Reference = "localhost/test/copy-index:1".parse().unwrap();
+        let args = BuildArgs {
+            config: BuildConfig {
+                kind: Kind::App,
+                copys: Vec::new(),
+                cmd: Dest::try_from("/bin/app".to_string()).unwrap(),
+                reproducible: false,
+                squash: false,
+                platforms: [host, other]
+                    .into_iter()
+                    .map(|platform| PlatformCopys {
+                        platform,
+                        copys: vec![Copy(
+                            app.clone(),
+                            "/bin/app".to_string().try_into().unwrap(),
+                        )],
+                    })
+                    .collect(),
+                wasm_artifact: false,
+            },
+            image: image.clone(),
+        };
+        let index_digest = build(&args).await.unwrap().sha256_pre();
+        let src_registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let dst_registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let src = push(&src_registry, &image, "test/src:1").await;
+
+        // 镜像索引连同各平台的manifest一起复制，digest不变
+        let dst = dst_registry.reference("test/dst:1");
+        let report = copy_with(
+            &src_registry.client(RegistryAuth::Anonymous).unwrap(),
+            &dst_registry.client(RegistryAuth::Anonymous).unwrap(),
+            &src,
+            &dst,
+            &CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.manifest_digest, index_digest);
+        assert_eq!(report.manifests.len(), 2);
+        assert_eq!(
+            dst_registry.manifest("test/dst", "1"),
+            src_registry.manifest("test/src", "1")
+        );
+        for child in report.manifests.iter() {
+            assert_eq!(
+                dst_registry.manifest("test/dst", child),
+                src_registry.manifest("test/src", child)
+            );
+        }
+        src_registry.shutdown().await.unwrap();
+        dst_registry.shutdown().await.unwrap();
+    }
+}
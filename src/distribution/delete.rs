@@ -0,0 +1,139 @@
+use crate::distribution::client::{RegistryClient, RegistryError};
+use crate::distribution::session::RegistrySession;
+use anyhow::Result;
+use log::debug;
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use reqwest::StatusCode;
+
+/// registry不支持删除（未开启删除或返回`UNSUPPORTED`），可通过`anyhow::Error::downcast_ref`判断
+#[derive(Debug)]
+pub struct DeleteUnsupported(pub RegistryError);
+
+impl std::fmt::Display for DeleteUnsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "registry不支持删除manifest：{}", self.0)
+    }
+}
+
+impl std::error::Error for DeleteUnsupported {}
+
+///
+/// 删除远端manifest：tag引用先解析为digest再按digest删除，返回被删除的digest。
+/// 同一digest的其它tag也会随之失效
+pub async fn delete(image: &Reference, auth: &RegistryAuth) -> Result<String> {
+    let session = RegistrySession::shared(auth)?;
+    let client = session.client();
+    delete_with(client, image).await
+}
+
+/// 使用指定的registry客户端删除远端manifest
+pub async fn delete_with(client: &RegistryClient, image: &Reference) -> Result<String> {
+    let digest = match image.digest() {
+        Some(digest) => digest.to_string(),
+        None => client.fetch_manifest_digest(image).await?,
+    };
+    let target = Reference::with_digest(
+        image.registry().to_string(),
+        image.repository().to_string(),
+        digest.clone(),
+    );
+    debug!("删除manifest: {:?}", target);
+    client
+        .delete_manifest(&target)
+        .await
+        .map_err(|e| match e.downcast_ref::<RegistryError>() {
+            Some(registry)
+                if registry.status == StatusCode::METHOD_NOT_ALLOWED
+                    || registry.has_code("UNSUPPORTED") =>
+            {
+                DeleteUnsupported(registry.clone()).into()
+            }
+            _ => e,
+        })?;
+    Ok(digest)
+}
+
+#[cfg(all(test, feature = "test-registry"))]
+mod test {
+    use super::{delete_with, DeleteUnsupported};
+    use crate::distribution::retry::RetryPolicy;
+    use crate::distribution::test_registry::{Failure, TestRegistry, TestRegistryOptions};
+    use crate::util::DigestPre;
+    use hyper::{Method, StatusCode};
+    use oci_distribution::manifest::{IMAGE_CONFIG_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE};
+    use oci_distribution::secrets::RegistryAuth;
+    use std::time::Duration;
+
+    async fn push_manifest(registry: &TestRegistry, tag: &str) -> String {
+        let client = registry.client(RegistryAuth::Anonymous).unwrap();
+        let image = registry.reference(&format!("test/delete:{}", tag));
+        let config = b"{}";
+        let config_digest = sha256::digest(config.as_slice()).sha256_pre();
+        client
+            .upload_blob(&image, &config_digest, config)
+            .await
+            .unwrap();
+        let manifest = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_IMAGE_MEDIA_TYPE,
+            "config": {
+                "mediaType": IMAGE_CONFIG_MEDIA_TYPE,
+                "digest": config_digest,
+                "size": config.len(),
+            },
+            "layers": [],
+        }))
+        .unwrap();
+        client
+            .push_manifest_raw(&image, &manifest, OCI_IMAGE_MEDIA_TYPE)
+            .await
+            .unwrap();
+        sha256::digest(manifest.as_slice()).sha256_pre()
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let client = registry
+            .client(RegistryAuth::Anonymous)
+            .unwrap()
+            .with_retry(policy);
+        let digest = push_manifest(&registry, "1").await;
+
+        // tag引用解析为digest后删除，临时性错误按重试策略重试
+        registry.fail(Failure {
+            method: Some(Method::DELETE),
+            path: "/manifests/".to_string(),
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            times: 1,
+        });
+        let image = registry.reference("test/delete:1");
+        assert_eq!(delete_with(&client, &image).await.unwrap(), digest);
+        assert!(registry.manifest("test/delete", &digest).is_none());
+        assert!(registry.manifest("test/delete", "1").is_none());
+        assert!(delete_with(&client, &image).await.is_err());
+        registry.shutdown().await.unwrap();
+
+        let registry = TestRegistry::start(TestRegistryOptions {
+            allow_delete: false,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let client = registry.client(RegistryAuth::Anonymous).unwrap();
+        let digest = push_manifest(&registry, "1").await;
+        let e = delete_with(&client, &registry.reference("test/delete:1"))
+            .await
+            .unwrap_err();
+        assert!(e.downcast_ref::<DeleteUnsupported>().is_some(), "{:?}", e);
+        assert!(registry.manifest("test/delete", &digest).is_some());
+        registry.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+use crate::distribution::session::RegistrySession;
+use anyhow::Result;
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+
+/// 列出远端仓库的所有tag（忽略`image`中的tag与digest）
+pub async fn list_tags(image: &Reference, auth: &RegistryAuth) -> Result<Vec<String>> {
+    let session = RegistrySession::shared(auth)?;
+    let client = session.client();
+    client.list_tags(image, None).await
+}
+
+/// 列出registry中的所有仓库，registry未开放`_catalog`时返回错误
+pub async fn catalog(registry: &str, auth: &RegistryAuth) -> Result<Vec<String>> {
+    let session = RegistrySession::shared(auth)?;
+    let client = session.client();
+    client.catalog(registry, None).await
+}
+
+#[cfg(all(test, feature = "test-registry"))]
+mod test {
+    use crate::distribution::test_registry::{TestRegistry, TestRegistryOptions};
+    use crate::util::DigestPre;
+    use hyper::Method;
+    use oci_distribution::manifest::{IMAGE_CONFIG_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE};
+    use oci_distribution::secrets::RegistryAuth;
+
+    #[tokio::test]
+    async fn test_pagination() {
+        let registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let client = registry.client(RegistryAuth::Anonymous).unwrap();
+        let config = b"{}";
+        let config_digest = sha256::digest(config.as_slice()).sha256_pre();
+        let manifest = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_IMAGE_MEDIA_TYPE,
+            "config": {
+                "mediaType": IMAGE_CONFIG_MEDIA_TYPE,
+                "digest": config_digest,
+                "size": config.len(),
+            },
+            "layers": [],
+        }))
+        .unwrap();
+        let repositories = ["test/a", "test/b", "test/c"];
+        let tags = ["1", "2", "3", "latest"];
+        for repository in repositories {
+            let image = registry.reference(&format!("{}:1", repository));
+            client
+                .upload_blob(&image, &config_digest, config)
+                .await
+                .unwrap();
+            for tag in tags {
+                let image = registry.reference(&format!("{}:{}", repository, tag));
+                client
+                    .push_manifest_raw(&image, &manifest, OCI_IMAGE_MEDIA_TYPE)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let image = registry.reference("test/b:1");
+        let listed = client.list_tags(&image, Some(1)).await.unwrap();
+        assert_eq!(listed, tags);
+        let listed = client.catalog(&registry.host(), Some(1)).await.unwrap();
+        assert_eq!(listed, repositories);
+        // 每页一条，最后一页没有Link
+        let requests = registry.requests();
+        let pages = |prefix: &str| {
+            requests
+                .iter()
+                .filter(|(method, path)| method == Method::GET && path.starts_with(prefix))
+                .count()
+        };
+        assert_eq!(pages("/v2/test/b/tags/list"), tags.len());
+        assert_eq!(pages("/v2/_catalog"), repositories.len());
+
+        // 不分页时一次返回全部
+        assert_eq!(client.list_tags(&image, None).await.unwrap(), tags);
+        registry.shutdown().await.unwrap();
+    }
+}
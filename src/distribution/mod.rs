@@ -1,2 +1,14 @@
+pub mod artifact;
+pub mod client;
+pub mod copy;
+pub mod delete;
+pub mod list;
 pub mod pull;
 pub mod push;
+pub mod retry;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod session;
+#[cfg(feature = "test-registry")]
+pub mod test_registry;
+pub mod update;
@@ -1,43 +1,128 @@
+use crate::distribution::client::RegistryClient;
+use crate::distribution::session::RegistrySession;
 use crate::filesystem::FileSystem;
+use crate::image::config::ConfigFile;
+use crate::image::index::{host_platform, platform_string, select_manifest};
+use crate::image::layer::LayerMetadata;
+use crate::image::manifest::Manifest;
+use crate::image::wasm;
 use crate::image::Repositories;
+use crate::util::cancel::Cancel;
 use crate::util::DigestPre;
-use anyhow::{Context, Result};
-use log::{debug, warn};
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use oci_distribution::manifest::Platform;
 use oci_distribution::secrets::RegistryAuth;
-use oci_distribution::{Client, Reference};
-use sha256::digest_bytes;
+use oci_distribution::Reference;
 
+/// 拉取镜像，镜像索引（OCI image index / Docker manifest list）按本机平台选择manifest
 pub async fn pull(image: &Reference, auth: &RegistryAuth) -> Result<String> {
+    pull_platform(image, auth, &host_platform()).await
+}
+
+///
+/// 拉取镜像，镜像索引按指定平台选择manifest。
+/// 镜像索引与选中的manifest都保存至本地，images.json记录索引的digest及所选平台；
+/// 返回images.json中记录的digest（不带`sha256:`前缀）
+pub async fn pull_platform(
+    image: &Reference,
+    auth: &RegistryAuth,
+    platform: &Platform,
+) -> Result<String> {
+    let session = RegistrySession::shared(auth)?;
+    let client = session.client();
+    pull_with(client, image, platform, &Cancel::new()).await
+}
+
+///
+/// 使用指定的registry客户端拉取镜像（如以HTTP访问网关内置的registry）。
+/// 取消或超时时立即中止进行中的请求并返回[`crate::util::cancel::Cancelled`]错误：
+/// 已完整写入的blob保留在本地（可由gc清理），不写入部分文件，images.json保持不变
+pub async fn pull_with(
+    client: &RegistryClient,
+    image: &Reference,
+    platform: &Platform,
+    cancel: &Cancel,
+) -> Result<String> {
     // pull镜像清单
     // pull镜像的config
     // pull layer
-    let client_config = oci_distribution::client::ClientConfig {
-        protocol: oci_distribution::client::ClientProtocol::Https,
-        ..Default::default()
+    // 镜像清单按registry返回的原始字节保存，本地digest与registry一致
+    let top = cancel.run(client.pull_manifest_raw(image)).await?;
+    let top_digest = top.digest.clone();
+    let top_manifest = Manifest::new(top.data);
+    top_manifest.check_schema()?;
+    let (index, manifest, manifest_digest, manifest_data) = if top_manifest.is_index() {
+        let index = top_manifest.to_oci_index()?;
+        let entry = select_manifest(&index, platform)
+            .ok_or(anyhow!(
+                "镜像索引{:?}中没有平台{}的manifest",
+                image,
+                platform_string(platform)
+            ))?
+            .clone();
+        debug!(
+            "镜像索引选择平台{}的manifest: {}",
+            platform_string(platform),
+            entry.digest
+        );
+        let reference = Reference::with_digest(
+            image.registry().to_string(),
+            image.repository().to_string(),
+            entry.digest.clone(),
+        );
+        let raw = cancel.run(client.pull_manifest_raw(&reference)).await?;
+        let manifest = Manifest::new(raw.data);
+        manifest.check_schema()?;
+        if manifest.is_index() {
+            bail!("镜像索引{}中的条目{}仍为镜像索引", top_digest, entry.digest)
+        }
+        (
+            Some(top_manifest),
+            manifest.to_oci_manifest()?,
+            entry.digest,
+            manifest,
+        )
+    } else {
+        let manifest = top_manifest.to_oci_manifest()?;
+        (None, manifest, top_digest.clone(), top_manifest)
     };
-    let mut client = Client::new(client_config);
-    let (manifest, _digest) = client.pull_image_manifest(&image, &auth).await?;
 
     let config_digest = manifest.config.digest.get_digest()?;
-    if !FileSystem.exist_config(&config_digest)? {
+    let config_data = if !FileSystem.exist_config(&config_digest)? {
         debug!("config[{}] is pulling……", config_digest);
-        let mut out = Vec::new();
-        client
-            .pull_blob(&image, &manifest.config.digest, &mut out)
+        let out = cancel
+            .run(client.pull_blob(image, &manifest.config.digest))
             .await
             .context("pull config失败")?;
         FileSystem.save_config(&config_digest, out.as_slice())?;
+        out
     } else {
-        debug!("config[{}] is found in local", manifest.config.digest)
-    }
+        debug!("config[{}] is found in local", manifest.config.digest);
+        std::fs::read(FileSystem.config_sha256()?.join(&config_digest))?
+    };
+    // wasm artifact的layer为模块文件本身而非tar，没有diff_ids，也不记录layer元数据
+    let diff_ids = if wasm::is_wasm_artifact(&manifest) {
+        wasm::module_layer(&manifest)?;
+        Vec::new()
+    } else {
+        let diff_ids = ConfigFile::diff_ids(&config_data)?;
+        if diff_ids.len() != manifest.layers.len() {
+            bail!(
+                "config中diff_ids数量({})与manifest中layer数量({})不一致",
+                diff_ids.len(),
+                manifest.layers.len()
+            );
+        }
+        diff_ids
+    };
 
     for item in manifest.layers.iter() {
         let layer_digest = item.digest.get_digest()?;
         if !FileSystem.exist_layer(&layer_digest)? {
             debug!("layer[{}] is pulling……", layer_digest);
-            let mut out = Vec::new();
-            client
-                .pull_blob(&image, &item.digest, &mut out)
+            let out = cancel
+                .run(client.pull_blob(image, &item.digest))
                 .await
                 .context("pull layer失败")?;
             FileSystem.save_layer(&layer_digest, out.as_slice())?;
@@ -45,22 +130,168 @@ pub async fn pull(image: &Reference, auth: &RegistryAuth) -> Result<String> {
             debug!("layer[{}] is found in local", layer_digest)
         }
     }
-    //
-    let manifest_data = serde_json::to_vec(&manifest)?;
-    let manifest_digest = digest_bytes(manifest_data.as_slice());
-    let manifest_path = FileSystem
-        .manifest_sha256()
-        .and_then(|x| {
-            if let Err(e) = std::fs::create_dir_all(&x) {
-                warn!("创建文件夹{:?}失败{:?}", x, e);
-            }
-            Ok(x)
-        })?
-        .join(manifest_digest.as_str());
-    std::fs::write(manifest_path, manifest_data)?;
+    // 记录layer元数据：校验diff_id并建立diff_id与blob digest的对应关系
+    cancel.check()?;
+    let mut parent_chain_id: Option<String> = None;
+    for (item, diff_id) in manifest.layers.iter().zip(diff_ids.iter()) {
+        let layer =
+            LayerMetadata::from_blob(&item.digest, &item.media_type, parent_chain_id.as_deref())?;
+        if &layer.diff_id != diff_id {
+            bail!(
+                "layer[{}]的diff_id({})与config中记录的({})不一致",
+                item.digest,
+                layer.diff_id,
+                diff_id
+            );
+        }
+        layer.save()?;
+        parent_chain_id = Some(layer.chain_id);
+    }
+    // 按registry返回的digest保存，镜像索引中的条目才能对应到本地的manifest
+    FileSystem.save_manifest(&manifest_digest.get_digest()?, manifest_data.data())?;
 
-    let mut repo = Repositories::init()?;
-    repo.update_and_save(&image, manifest_digest.sha256_pre())?;
+    // 最后的检查点，此后只更新images.json
+    cancel.check()?;
+    if let Some(index) = &index {
+        FileSystem.save_manifest(&top_digest.get_digest()?, index.data())?;
+    }
+    Repositories::modify(|repo| {
+        repo.update(image, top_digest.clone());
+        if index.is_some() {
+            repo.update_platform(image, platform.clone());
+        }
+    })?;
+
+    top_digest.get_digest()
+}
 
-    Ok(manifest_digest)
+#[cfg(all(test, feature = "test-registry"))]
+mod test {
+    use super::pull_with;
+    use crate::distribution::push::{push_with, PushOptions};
+    use crate::distribution::test_registry::{TestRegistry, TestRegistryOptions};
+    use crate::filesystem::FileSystem;
+    use crate::image::build::build_test_image;
+    use crate::image::index::host_platform;
+    use crate::image::Repositories;
+    use crate::util::cancel::{Cancel, Cancelled};
+    use crate::util::DigestPre;
+    use flate2::write::GzEncoder;
+    use oci_distribution::manifest::{IMAGE_LAYER_GZIP_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE};
+    use oci_distribution::secrets::RegistryAuth;
+    use std::io::Write;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_pull_timeout() {
+        let (image, digest) = build_test_image("localhost/test/registry-timeout:1").await;
+        let registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let client = registry.client(RegistryAuth::Anonymous).unwrap();
+        let target = registry.reference("test/timeout:1");
+        push_with(
+            &client,
+            &image,
+            &target,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+        .unwrap();
+        let pulled = pull_with(&client, &target, &host_platform(), &Cancel::new())
+            .await
+            .unwrap();
+        assert_eq!(pulled.sha256_pre(), digest);
+        // 新版本追加一个本地没有的layer
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        let content = format!("timeout-{}", registry.host()).into_bytes();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "etc/timeout", content.as_slice())
+            .unwrap();
+        let tar = tar.into_inner().unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&tar).unwrap();
+        let layer = encoder.finish().unwrap();
+        let layer_digest = sha256::digest(layer.as_slice()).sha256_pre();
+        client
+            .upload_blob(&target, &layer_digest, &layer)
+            .await
+            .unwrap();
+        let mut manifest: serde_json::Value =
+            serde_json::from_slice(&registry.manifest("test/timeout", "1").unwrap()).unwrap();
+        let config = client
+            .pull_blob(&target, manifest["config"]["digest"].as_str().unwrap())
+            .await
+            .unwrap();
+        let mut config: serde_json::Value = serde_json::from_slice(&config).unwrap();
+        // 本库构建的config以rootf记录diff_ids
+        let rootfs_key = if config.get("rootfs").is_some() {
+            "rootfs"
+        } else {
+            "rootf"
+        };
+        config[rootfs_key]["diff_ids"]
+            .as_array_mut()
+            .unwrap()
+            .push(sha256::digest(tar.as_slice()).sha256_pre().into());
+        let config = serde_json::to_vec(&config).unwrap();
+        let config_digest = sha256::digest(config.as_slice()).sha256_pre();
+        client
+            .upload_blob(&target, &config_digest, &config)
+            .await
+            .unwrap();
+        manifest["config"]["digest"] = config_digest.clone().into();
+        manifest["config"]["size"] = config.len().into();
+        manifest["layers"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::json!({
+                "mediaType": IMAGE_LAYER_GZIP_MEDIA_TYPE,
+                "digest": layer_digest,
+                "size": layer.len(),
+            }));
+        let manifest = serde_json::to_vec(&manifest).unwrap();
+        client
+            .push_manifest_raw(&target, &manifest, OCI_IMAGE_MEDIA_TYPE)
+            .await
+            .unwrap();
+
+        // 每个请求耗时1秒：manifest与config拉取完成后，在拉取新layer时超时
+        registry.set_latency(Some(Duration::from_secs(1)));
+        let cancel = Cancel::with_timeout(Duration::from_millis(2500));
+        let e = pull_with(&client, &target, &host_platform(), &cancel)
+            .await
+            .unwrap_err();
+        assert_eq!(e.downcast_ref::<Cancelled>(), Some(&Cancelled::TimedOut));
+        assert_eq!(
+            Repositories::init().unwrap().image_digest(&target),
+            Some(digest)
+        );
+        // blob完整下载后才写入，不会留下不完整的文件
+        let layer_hex = layer_digest.get_digest().unwrap();
+        assert!(!FileSystem.exist_layer(&layer_hex).unwrap());
+        let config_path = FileSystem
+            .config_sha256()
+            .unwrap()
+            .join(config_digest.get_digest().unwrap());
+        if let Ok(data) = std::fs::read(config_path) {
+            assert_eq!(data, config);
+        }
+
+        // 不再超时后重新拉取成功
+        registry.set_latency(None);
+        pull_with(&client, &target, &host_platform(), &Cancel::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            Repositories::init().unwrap().image_digest(&target),
+            Some(sha256::digest(manifest.as_slice()).sha256_pre())
+        );
+        assert!(FileSystem.exist_layer(&layer_hex).unwrap());
+        registry.shutdown().await.unwrap();
+    }
 }
@@ -1,50 +1,712 @@
-use crate::image::config::ConfigFileAndData;
-use crate::image::manifest::{load_layer, Manifest};
+use crate::distribution::client::{BlobMount, RegistryClient, RegistryError};
+use crate::distribution::session::RegistrySession;
+use crate::filesystem::FileSystem;
+use crate::image::manifest::Manifest;
 use crate::image::Repositories;
+use crate::util::cancel::Cancel;
 use crate::util::DigestPre;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use log::debug;
-use oci_distribution::client::{Config, ImageLayer};
+use oci_distribution::manifest::{OciDescriptor, OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE};
 use oci_distribution::secrets::RegistryAuth;
-use oci_distribution::{manifest, Client, Reference};
+use oci_distribution::Reference;
+use serde::Deserialize;
+use std::collections::HashMap;
 
-pub async fn push(image: &Reference, auth: &RegistryAuth) -> Result<()> {
+/// 单个blob的推送结果
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlobPush {
+    /// registry上已存在，跳过
+    Exists,
+    /// 从同一registry的其它仓库挂载
+    Mounted(String),
+    /// 上传，记录上传的字节数
+    Uploaded(u64),
+}
+
+/// 推送选项
+#[derive(Debug, Default, Clone)]
+pub struct PushOptions {
+    ///
+    /// 将Docker schema2 manifest转为OCI格式后推送：只改写manifest中的媒体类型，
+    /// blob原样推送，manifest digest随之改变。本地存储不变
+    pub convert_to_oci: bool,
+}
+
+/// 推送结果
+#[derive(Debug, Default)]
+pub struct PushReport {
+    /// 推送的manifest digest（带`sha256:`前缀），未转换格式时与本地digest一致
+    pub manifest_digest: String,
+    /// registry上manifest的URL
+    pub manifest_url: String,
+    /// config及各layer的推送结果，键为blob的digest
+    pub blobs: Vec<(String, BlobPush)>,
+    /// 实际上传的字节数
+    pub transferred_bytes: u64,
+}
+
+/// 推送失败的原因
+#[derive(Debug)]
+pub enum PushError {
+    /// 本地未找到镜像
+    ImageNotFound(String),
+    /// 本地缺少blob（记录blob的digest），或registry推送manifest时报告blob不存在（记录registry的错误信息）
+    MissingBlob(String),
+    /// registry认证失败（401/403）
+    Unauthorized(RegistryError),
+    /// registry拒绝请求
+    Rejected(RegistryError),
+    Other(anyhow::Error),
+}
+
+impl std::fmt::Display for PushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PushError::ImageNotFound(image) => write!(f, "本地未找到镜像{}", image),
+            PushError::MissingBlob(digest) => write!(f, "缺少blob: {}", digest),
+            PushError::Unauthorized(e) => write!(f, "registry认证失败：{}", e),
+            PushError::Rejected(e) => write!(f, "registry拒绝推送：{}", e),
+            PushError::Other(e) => write!(f, "推送失败：{:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for PushError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PushError::Unauthorized(e) | PushError::Rejected(e) => Some(e),
+            PushError::Other(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for PushError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<PushError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        match e.downcast_ref::<RegistryError>() {
+            Some(registry) if registry.is_unauthorized() => {
+                PushError::Unauthorized(registry.clone())
+            }
+            Some(registry)
+                if registry.has_code("BLOB_UNKNOWN")
+                    || registry.has_code("MANIFEST_BLOB_UNKNOWN") =>
+            {
+                PushError::MissingBlob(registry.body.clone())
+            }
+            Some(registry) => PushError::Rejected(registry.clone()),
+            None => PushError::Other(e),
+        }
+    }
+}
+
+/// 推送本地镜像，registry上已存在的blob不重复上传
+pub async fn push(image: &Reference, auth: &RegistryAuth) -> Result<PushReport, PushError> {
+    push_to(image, image, auth).await
+}
+
+/// 将本地镜像推送为另一个镜像（可为不同的tag、仓库或registry）
+pub async fn push_to(
+    image: &Reference,
+    target: &Reference,
+    auth: &RegistryAuth,
+) -> Result<PushReport, PushError> {
+    let session = RegistrySession::shared(auth)?;
+    let client = session.client();
+    push_with(
+        client,
+        image,
+        target,
+        &PushOptions::default(),
+        &Cancel::new(),
+    )
+    .await
+}
+
+///
+/// 使用指定的registry客户端推送本地镜像。
+/// 取消或超时时中止进行中的请求，错误为`PushError::Other`，可从中取得[`crate::util::cancel::Cancelled`]；
+/// 推送不修改本地存储
+pub async fn push_with(
+    client: &RegistryClient,
+    image: &Reference,
+    target: &Reference,
+    options: &PushOptions,
+    cancel: &Cancel,
+) -> Result<PushReport, PushError> {
+    Ok(push_image(client, image, target, &[], options, cancel).await?)
+}
+
+///
+/// 将本地镜像依次推送到多个目标，返回与`targets`顺序一致的结果。
+/// 同一registry中已推送过的仓库作为后续目标的blob挂载源，避免重复上传
+pub async fn push_all(
+    image: &Reference,
+    targets: &[Reference],
+    auth: &RegistryAuth,
+) -> Result<Vec<Result<PushReport, PushError>>, PushError> {
+    let session = RegistrySession::shared(auth)?;
+    let client = session.client();
+    push_all_with(
+        client,
+        image,
+        targets,
+        &PushOptions::default(),
+        &Cancel::new(),
+    )
+    .await
+}
+
+///
+/// 使用指定的registry客户端将本地镜像推送到多个目标。
+/// 取消或超时时返回错误，不再推送其余目标
+pub async fn push_all_with(
+    client: &RegistryClient,
+    image: &Reference,
+    targets: &[Reference],
+    options: &PushOptions,
+    cancel: &Cancel,
+) -> Result<Vec<Result<PushReport, PushError>>, PushError> {
+    let mut pushed: Vec<Reference> = Vec::new();
+    let mut results = Vec::with_capacity(targets.len());
+    for target in targets.iter() {
+        cancel.check()?;
+        let result = push_image(client, image, target, &pushed, options, cancel).await;
+        if result.is_ok() {
+            pushed.push(target.clone());
+        }
+        results.push(result.map_err(PushError::from));
+    }
+    Ok(results)
+}
+
+///
+/// 推送本地镜像`image`到`target`，`pushed`为本次已推送成功的目标。
+/// 镜像索引先逐个推送本地存在的子manifest及其blob，再原样推送索引，digest保持不变；
+/// 本地没有的子manifest（如只拉取了本机平台）跳过，registry要求其存在时推送索引失败
+async fn push_image(
+    client: &RegistryClient,
+    image: &Reference,
+    target: &Reference,
+    pushed: &[Reference],
+    options: &PushOptions,
+    cancel: &Cancel,
+) -> Result<PushReport> {
     // 读取images.json
     // 读取镜像的config、layer
-    // 拼接镜像清单
-    // push
+    // 逐个推送blob：已存在则跳过，能挂载则挂载，否则上传
+    // 推送manifest原始字节
     debug!("开始查找本地镜像……");
     let repo = Repositories::init()?;
     let manifest_digest = repo
-        .image_digest(&image)
-        .ok_or(anyhow!("本地未找到镜像{:?}", image))?
+        .image_digest(image)
+        .ok_or(PushError::ImageNotFound(image.whole()))?
         .get_digest()?;
-    debug!("");
-    let image_manifest = Manifest::load(manifest_digest.as_str())?.to_oci_manifest()?;
+    let mut manifest = Manifest::load(manifest_digest.as_str())?;
+    let mut sources = mount_sources(&repo, target)?;
+    let mut report = PushReport::default();
+    if manifest.is_index() {
+        // 索引按digest引用子manifest，不转换格式
+        for entry in manifest.to_oci_index()?.manifests.iter() {
+            let child = match Manifest::load(&entry.digest.get_digest()?) {
+                Ok(child) => child,
+                Err(_) => {
+                    debug!("本地没有镜像索引中的manifest[{}]，跳过", entry.digest);
+                    continue;
+                }
+            };
+            let child_target = Reference::with_digest(
+                target.registry().to_string(),
+                target.repository().to_string(),
+                entry.digest.clone(),
+            );
+            push_manifest(
+                client,
+                &child,
+                &child_target,
+                pushed,
+                &mut sources,
+                &mut report,
+                cancel,
+            )
+            .await?;
+        }
+        let media_type = manifest
+            .media_type()
+            .unwrap_or(OCI_IMAGE_INDEX_MEDIA_TYPE.to_string());
+        report.manifest_url = cancel
+            .run(client.push_manifest_raw(target, manifest.data(), &media_type))
+            .await?;
+    } else {
+        if options.convert_to_oci {
+            if let Some(converted) = manifest.to_oci()? {
+                debug!("manifest转换为OCI格式后推送");
+                manifest = converted;
+            }
+        }
+        report.manifest_url = push_manifest(
+            client,
+            &manifest,
+            target,
+            pushed,
+            &mut sources,
+            &mut report,
+            cancel,
+        )
+        .await?;
+    }
+    report.manifest_digest = sha256::digest(manifest.data()).sha256_pre();
+    debug!(
+        "推送完成: {} 上传{}字节",
+        report.manifest_url, report.transferred_bytes
+    );
+    Ok(report)
+}
 
-    debug!("加载镜像config文件……");
-    let config = ConfigFileAndData::load(&image_manifest.config.digest.get_digest()?)?;
-    let ConfigFileAndData { file: _, data } = config;
+/// 推送单个镜像manifest引用的config、layer及manifest原始字节，返回manifest的URL
+async fn push_manifest(
+    client: &RegistryClient,
+    manifest: &Manifest,
+    target: &Reference,
+    pushed: &[Reference],
+    sources: &mut HashMap<String, Vec<String>>,
+    report: &mut PushReport,
+    cancel: &Cancel,
+) -> Result<String> {
+    let image_manifest = manifest.to_oci_manifest()?;
+    for other in pushed
+        .iter()
+        .filter(|x| x.registry() == target.registry() && x.repository() != target.repository())
+    {
+        for blob in std::iter::once(&image_manifest.config).chain(image_manifest.layers.iter()) {
+            let repositories = sources.entry(blob.digest.clone()).or_default();
+            if !repositories.iter().any(|x| x == other.repository()) {
+                // 刚推送过的仓库一定有该blob，优先挂载
+                repositories.insert(0, other.repository().to_string());
+            }
+        }
+    }
 
-    debug!("加载镜像layer文件……");
-    let layers = load_layer(&image_manifest.layers)?;
-    let layers: Vec<ImageLayer> = layers.into_iter().map(|x| x.into()).collect();
+    debug!("推送镜像config文件……");
+    let config_path = FileSystem
+        .config_sha256()?
+        .join(image_manifest.config.digest.get_digest()?);
+    let outcome = cancel
+        .run(push_blob(
+            client,
+            target,
+            &image_manifest.config,
+            &config_path,
+            sources,
+        ))
+        .await?;
+    report.add(image_manifest.config.digest.clone(), outcome);
 
-    let config = Config {
-        data: data,
-        media_type: manifest::IMAGE_CONFIG_MEDIA_TYPE.to_string(),
-        annotations: None,
-    };
+    debug!("推送镜像layer文件……");
+    for layer in image_manifest.layers.iter() {
+        let layer_path = FileSystem.layer_blobs()?.join(layer.digest.get_digest()?);
+        let outcome = cancel
+            .run(push_blob(client, target, layer, &layer_path, sources))
+            .await?;
+        report.add(layer.digest.clone(), outcome);
+    }
+
+    let media_type = image_manifest
+        .media_type
+        .clone()
+        .unwrap_or(OCI_IMAGE_MEDIA_TYPE.to_string());
+    cancel
+        .run(client.push_manifest_raw(target, manifest.data(), &media_type))
+        .await
+}
 
-    let client_config = oci_distribution::client::ClientConfig {
-        protocol: oci_distribution::client::ClientProtocol::Https,
-        ..Default::default()
+impl PushReport {
+    pub(crate) fn add(&mut self, digest: String, outcome: BlobPush) {
+        if let BlobPush::Uploaded(size) = &outcome {
+            self.transferred_bytes += size;
+        }
+        self.blobs.push((digest, outcome));
+    }
+}
+
+async fn push_blob(
+    client: &RegistryClient,
+    image: &Reference,
+    descriptor: &OciDescriptor,
+    path: &std::path::Path,
+    sources: &HashMap<String, Vec<String>>,
+) -> Result<BlobPush> {
+    let digest = descriptor.digest.as_str();
+    push_blob_data(client, image, digest, sources, || {
+        if !path.exists() {
+            bail!(PushError::MissingBlob(digest.to_string()));
+        }
+        std::fs::read(path).map_err(|e| anyhow!("读取blob{:?}失败：{}", path, e))
+    })
+    .await
+}
+
+///
+/// 推送单个blob：registry上已存在时跳过，能从`sources`中的仓库挂载时挂载，
+/// 否则由`read`读取数据上传（复用挂载失败时registry开启的上传会话）
+pub(crate) async fn push_blob_data(
+    client: &RegistryClient,
+    image: &Reference,
+    digest: &str,
+    sources: &HashMap<String, Vec<String>>,
+    read: impl FnOnce() -> Result<Vec<u8>>,
+) -> Result<BlobPush> {
+    if client.blob_exists(image, digest).await? {
+        debug!("blob[{}]已存在，跳过", digest);
+        return Ok(BlobPush::Exists);
+    }
+    // 挂载失败时registry开启的上传会话，只保留一个用于上传
+    let mut session: Option<String> = None;
+    for from in sources.get(digest).into_iter().flatten() {
+        match client.mount_blob(image, digest, from).await {
+            Ok(BlobMount::Mounted) => {
+                debug!("blob[{}]从{}挂载", digest, from);
+                cancel_session(client, image, session.take()).await;
+                return Ok(BlobPush::Mounted(from.clone()));
+            }
+            Ok(BlobMount::Upload(location)) => {
+                debug!("blob[{}]无法从{}挂载", digest, from);
+                cancel_session(client, image, session.replace(location)).await;
+            }
+            Err(e) => debug!("blob[{}]从{}挂载失败：{:?}", digest, from, e),
+        }
+    }
+    let data = match read() {
+        Ok(data) => data,
+        Err(e) => {
+            cancel_session(client, image, session).await;
+            return Err(e);
+        }
     };
-    let mut client = Client::new(client_config);
-    let _response = client
-        .push(&image, &layers, config, &auth, Some(image_manifest))
+    client.upload_blob_in(image, digest, &data, session).await?;
+    Ok(BlobPush::Uploaded(data.len() as u64))
+}
+
+/// 取消不再使用的上传会话，失败只记录日志
+pub(crate) async fn cancel_session(
+    client: &RegistryClient,
+    image: &Reference,
+    location: Option<String>,
+) {
+    if let Some(location) = location {
+        if let Err(e) = client.cancel_upload(image, &location).await {
+            debug!("取消上传会话{}失败：{:?}", location, e);
+        }
+    }
+}
+
+///
+/// 可用于挂载的源仓库：images.json中与目标同一registry、不同仓库的镜像引用的blob。
+/// 返回blob digest到仓库名的映射
+fn mount_sources(repo: &Repositories, target: &Reference) -> Result<HashMap<String, Vec<String>>> {
+    #[derive(Deserialize)]
+    struct Blobs {
+        config: Option<OciDescriptor>,
+        #[serde(default)]
+        layers: Vec<OciDescriptor>,
+    }
+    let mut sources: HashMap<String, Vec<String>> = HashMap::new();
+    for (full_name, digest) in repo.images() {
+        let (registry, repository) = match full_name.split_once('/') {
+            Some(name) => name,
+            None => continue,
+        };
+        if registry != target.registry() || repository == target.repository() {
+            continue;
+        }
+        let digest = digest.get_digest()?;
+        let manifest = match Manifest::load(&digest) {
+            Ok(manifest) => manifest,
+            Err(_) => continue,
+        };
+        let mut manifests = vec![manifest];
+        if manifests[0].is_index() {
+            manifests = manifests[0]
+                .to_oci_index()?
+                .manifests
+                .iter()
+                .filter_map(|x| Manifest::load(&x.digest.get_digest().ok()?).ok())
+                .collect();
+        }
+        for manifest in manifests {
+            let blobs: Blobs = match serde_json::from_slice(manifest.data()) {
+                Ok(blobs) => blobs,
+                Err(_) => continue,
+            };
+            for blob in blobs.config.into_iter().chain(blobs.layers) {
+                let repositories = sources.entry(blob.digest).or_default();
+                if !repositories.iter().any(|x| x == repository) {
+                    repositories.push(repository.to_string());
+                }
+            }
+        }
+    }
+    Ok(sources)
+}
+
+#[cfg(test)]
+mod test {
+    use super::PushError;
+    use crate::distribution::client::RegistryError;
+    use reqwest::StatusCode;
+
+    fn registry_error(status: StatusCode, code: &str) -> anyhow::Error {
+        RegistryError {
+            url: "https://localhost/v2/test/manifests/1".to_string(),
+            status,
+            codes: vec![code.to_string()],
+            body: String::new(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_push_error() {
+        let e = PushError::from(registry_error(StatusCode::UNAUTHORIZED, "UNAUTHORIZED"));
+        assert!(matches!(e, PushError::Unauthorized(_)));
+        let e = PushError::from(registry_error(
+            StatusCode::BAD_REQUEST,
+            "MANIFEST_BLOB_UNKNOWN",
+        ));
+        assert!(matches!(e, PushError::MissingBlob(_)));
+        let e = PushError::from(registry_error(StatusCode::BAD_REQUEST, "MANIFEST_INVALID"));
+        assert!(matches!(e, PushError::Rejected(_)));
+        let e = PushError::from(anyhow::Error::from(PushError::MissingBlob(
+            "sha256:1".to_string(),
+        )));
+        assert!(matches!(e, PushError::MissingBlob(digest) if digest == "sha256:1"));
+    }
+}
+
+#[cfg(all(test, feature = "test-registry"))]
+mod registry_test {
+    use super::{push_with, BlobPush, PushError, PushOptions};
+    use crate::args::BuildArgs;
+    use crate::distribution::client::BlobMount;
+    use crate::distribution::pull::pull_with;
+    use crate::distribution::test_registry::{TestAuth, TestRegistry, TestRegistryOptions};
+    use crate::filesystem::test_home;
+    use crate::image::build::config::instructions::{Copy, Dest, Kind};
+    use crate::image::build::config::{BuildConfig, PlatformCopys};
+    use crate::image::build::{build, build_test_image};
+    use crate::image::index::host_platform;
+    use crate::image::manifest::Manifest;
+    use crate::image::Repositories;
+    use crate::util::cancel::Cancel;
+    use crate::util::DigestPre;
+    use hyper::Method;
+    use oci_distribution::secrets::RegistryAuth;
+    use oci_distribution::Reference;
+
+    #[tokio::test]
+    async fn test_push() {
+        let (image, digest) = build_test_image("localhost/test/registry-push:1").await;
+        let registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let client = registry.client(RegistryAuth::Anonymous).unwrap();
+        let target = registry.reference("test/app:1");
+        let report = push_with(
+            &client,
+            &image,
+            &target,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.manifest_digest, digest);
+        assert!(report.transferred_bytes > 0);
+        let report = push_with(
+            &client,
+            &image,
+            &target,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.transferred_bytes, 0);
+        assert!(registry.manifest("test/app", "1").is_some());
+        registry.shutdown().await.unwrap();
+
+        // 源仓库中没有blob时挂载开启的上传会话用于上传，不另开会话
+        let registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let client = registry.client(RegistryAuth::Anonymous).unwrap();
+        let mut repo = Repositories::init().unwrap();
+        repo.update_and_save(&registry.reference("test/ghost:1"), digest.clone())
+            .unwrap();
+        let fresh = registry.reference("test/fresh:1");
+        let report = push_with(
+            &client,
+            &image,
+            &fresh,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report
+            .blobs
+            .iter()
+            .all(|(_, x)| matches!(x, BlobPush::Uploaded(_))));
+        let sessions = registry
+            .requests()
+            .iter()
+            .filter(|(method, path)| method == Method::POST && path.starts_with("/v2/test/fresh/"))
+            .count();
+        assert_eq!(sessions, report.blobs.len());
+        assert_eq!(registry.uploads(), 0);
+
+        // 不再使用的会话被取消
+        let blob = &report.blobs[0].0;
+        let other = registry.reference("test/other:1");
+        match client.mount_blob(&other, blob, "test/ghost").await.unwrap() {
+            BlobMount::Upload(location) => {
+                assert_eq!(registry.uploads(), 1);
+                client.cancel_upload(&other, &location).await.unwrap();
+            }
+            BlobMount::Mounted => panic!("test/ghost中没有blob"),
+        }
+        assert_eq!(registry.uploads(), 0);
+        registry.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_push_unauthorized() {
+        let (image, _) = build_test_image("localhost/test/registry-auth:1").await;
+        let registry = TestRegistry::start(TestRegistryOptions {
+            auth: TestAuth::Bearer {
+                username: "user".to_string(),
+                password: "secret".to_string(),
+            },
+            ..Default::default()
+        })
         .await
-        .map(|push_response| push_response.manifest_url)
-        .expect("Cannot push");
-    Ok(())
+        .unwrap();
+        let target = registry.reference("test/auth:1");
+        let client = registry
+            .client(RegistryAuth::Basic("user".to_string(), "wrong".to_string()))
+            .unwrap();
+        let e = push_with(
+            &client,
+            &image,
+            &target,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e, PushError::Unauthorized(_)), "{:?}", e);
+
+        let client = registry
+            .client(RegistryAuth::Basic(
+                "user".to_string(),
+                "secret".to_string(),
+            ))
+            .unwrap();
+        push_with(
+            &client,
+            &image,
+            &target,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+        .unwrap();
+        assert!(registry
+            .requests()
+            .iter()
+            .any(|(_, path)| path.starts_with("/token")));
+        registry.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_push_index() {
+        test_home();
+        let src = tempfile::tempdir().unwrap();
+        let app = src.path().join("app");
+        std::fs::write(&app, "multi-platform push").unwrap();
+        let host = host_platform();
+        let mut other = host.clone();
+        other.architecture = "other".to_string();
+        let image: Reference = "localhost/test/push-index:1".parse().unwrap();
+        let args = BuildArgs {
+            config: BuildConfig {
+                kind: Kind::App,
+                copys: Vec::new(),
+                cmd: Dest::try_from("/bin/app".to_string()).unwrap(),
+                reproducible: false,
+                squash: false,
+                platforms: [host.clone(), other.clone()]
+                    .into_iter()
+                    .map(|platform| PlatformCopys {
+                        platform,
+                        copys: vec![Copy(
+                            app.clone(),
+                            "/bin/app".to_string().try_into().unwrap(),
+                        )],
+                    })
+                    .collect(),
+                wasm_artifact: false,
+            },
+            image: image.clone(),
+        };
+        let index_digest = build(&args).await.unwrap();
+        let index = Manifest::load(&index_digest).unwrap();
+
+        let registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let client = registry.client(RegistryAuth::Anonymous).unwrap();
+        let target = registry.reference("test/multi:1");
+        let report = push_with(
+            &client,
+            &image,
+            &target,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+        .unwrap();
+        // 索引原样推送，各平台的manifest与blob先于索引推送
+        assert_eq!(report.manifest_digest, index_digest.sha256_pre());
+        assert_eq!(
+            registry.manifest("test/multi", "1").unwrap(),
+            index.data().to_vec()
+        );
+        for entry in index.to_oci_index().unwrap().manifests.iter() {
+            let child = registry.manifest("test/multi", &entry.digest).unwrap();
+            let child = Manifest::new(child).to_oci_manifest().unwrap();
+            for blob in std::iter::once(&child.config).chain(child.layers.iter()) {
+                assert!(registry.has_blob("test/multi", &blob.digest));
+            }
+        }
+
+        // 按各平台拉取，本地记录的digest仍为索引的digest
+        for platform in [host, other] {
+            let pulled = pull_with(&client, &target, &platform, &Cancel::new())
+                .await
+                .unwrap();
+            assert_eq!(pulled, index_digest);
+            let resolved = Manifest::resolve(&pulled, &platform).unwrap();
+            let manifest = Manifest::load(&resolved)
+                .unwrap()
+                .to_oci_manifest()
+                .unwrap();
+            assert!(!manifest.layers.is_empty());
+        }
+        registry.shutdown().await.unwrap();
+    }
 }
@@ -0,0 +1,316 @@
+use crate::distribution::client::RegistryError;
+use log::warn;
+use reqwest::StatusCode;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// 一次重试的信息，供调用方展示进度
+#[derive(Debug, Clone)]
+pub struct RetryEvent {
+    /// 重试的请求，一般为URL
+    pub operation: String,
+    /// 刚失败的是第几次尝试（从1开始）
+    pub attempt: u32,
+    pub max_attempts: u32,
+    /// 下次尝试前的等待时间
+    pub delay: Duration,
+    /// 本次失败的原因
+    pub error: String,
+}
+
+/// 重试回调
+pub type RetryCallback = Arc<dyn Fn(&RetryEvent) + Send + Sync>;
+
+///
+/// 临时性registry错误的重试策略：按指数退避（可加随机抖动）重试，
+/// 应用于每个manifest与blob请求。可重试的错误见[`is_retryable`]
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// 最多尝试次数（含首次），为1时不重试
+    pub max_attempts: u32,
+    /// 首次重试前的等待时间，之后每次翻倍
+    pub initial_backoff: Duration,
+    /// 单次等待时间上限
+    pub max_backoff: Duration,
+    /// 是否在等待时间上加随机抖动，避免多个客户端同时重试
+    pub jitter: bool,
+    /// 每次重试前调用，可用于展示进度，见[`RetryPolicy::on_retry`]
+    pub on_retry: Option<RetryCallback>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            jitter: true,
+            on_retry: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .field("on_retry", &self.on_retry.is_some())
+            .finish()
+    }
+}
+
+impl RetryPolicy {
+    /// 不重试
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// 每次重试前调用，可用于展示进度
+    pub fn on_retry(mut self, callback: impl Fn(&RetryEvent) + Send + Sync + 'static) -> Self {
+        self.on_retry = Some(Arc::new(callback));
+        self
+    }
+
+    /// 第`attempt`次尝试失败后的等待时间
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(31);
+        let base = self
+            .initial_backoff
+            .saturating_mul(1 << exp)
+            .min(self.max_backoff);
+        if !self.jitter {
+            return base;
+        }
+        // 等待时间取[base/2, base)中的随机值
+        let random = RandomState::new().build_hasher().finish();
+        let half = base / 2;
+        half + half.mul_f64((random % 1000) as f64 / 1000.0)
+    }
+
+    /// 执行`op`，遇到可重试的错误时按策略重试，返回最后一次的结果
+    pub(crate) async fn run<T, F, Fut>(&self, operation: &str, op: F) -> anyhow::Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let e = match op().await {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+            if attempt >= self.max_attempts || !is_retryable(&e) {
+                return Err(e);
+            }
+            let event = RetryEvent {
+                operation: operation.to_string(),
+                attempt,
+                max_attempts: self.max_attempts,
+                delay: self.backoff(attempt),
+                error: format!("{}", e),
+            };
+            warn!(
+                "请求{}失败（第{}/{}次），{:?}后重试：{}",
+                event.operation, event.attempt, event.max_attempts, event.delay, event.error
+            );
+            if let Some(callback) = &self.on_retry {
+                callback(&event);
+            }
+            tokio::time::sleep(event.delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+///
+/// 是否为临时性错误：registry返回408、429、500、502、503、504，
+/// 或连接失败、超时。其它请求错误（如请求构造错误、已发出请求后连接中断）不重试，
+/// 重试前者不会成功，后者对非幂等请求不安全
+pub fn is_retryable(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        if let Some(registry) = cause.downcast_ref::<RegistryError>() {
+            return is_retryable_status(registry.status);
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return e.is_timeout() || e.is_connect() || e.status().is_some_and(is_retryable_status);
+        }
+        false
+    })
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_retryable, RetryPolicy};
+    use crate::distribution::client::RegistryError;
+    use reqwest::StatusCode;
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff_and_classification() {
+        let policy = RetryPolicy {
+            jitter: false,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(500));
+        assert_eq!(policy.backoff(3), Duration::from_secs(2));
+        assert_eq!(policy.backoff(10), Duration::from_secs(10));
+        let jittered = RetryPolicy::default().backoff(2);
+        assert!(jittered >= Duration::from_millis(500) && jittered < Duration::from_secs(1));
+
+        let error = |status| -> anyhow::Error {
+            RegistryError {
+                url: "http://localhost/v2/".to_string(),
+                status,
+                codes: Vec::new(),
+                body: String::new(),
+            }
+            .into()
+        };
+        assert!(is_retryable(&error(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(is_retryable(&error(StatusCode::TOO_MANY_REQUESTS)));
+        assert!(!is_retryable(&error(StatusCode::NOT_FOUND)));
+        assert!(!is_retryable(&error(StatusCode::UNAUTHORIZED)));
+        assert!(!is_retryable(&anyhow::anyhow!("digest不一致")));
+    }
+
+    #[tokio::test]
+    async fn test_request_errors() {
+        // 连接失败可重试
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let e = reqwest::get(format!("http://{}/v2/", addr))
+            .await
+            .unwrap_err();
+        assert!(is_retryable(&e.into()));
+        // 请求构造错误重试也不会成功
+        let e = reqwest::Client::new()
+            .get("http://localhost/v2/")
+            .header("bad header", "value")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(!is_retryable(&e.into()));
+
+        // 回调可直接以结构体字面量设置
+        let policy = RetryPolicy {
+            on_retry: Some(std::sync::Arc::new(|_| {})),
+            ..RetryPolicy::none()
+        };
+        assert!(policy.on_retry.is_some());
+    }
+}
+
+#[cfg(all(test, feature = "test-registry"))]
+mod registry_test {
+    use super::RetryPolicy;
+    use crate::distribution::client::BlobMount;
+    use crate::distribution::pull::pull_with;
+    use crate::distribution::push::{push_with, PushOptions};
+    use crate::distribution::test_registry::{Failure, TestRegistry, TestRegistryOptions};
+    use crate::image::build::build_test_image;
+    use crate::image::index::host_platform;
+    use crate::image::manifest::Manifest;
+    use crate::util::cancel::Cancel;
+    use hyper::{Method, StatusCode};
+    use oci_distribution::secrets::RegistryAuth;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_retry_requests() {
+        let (image, _) = build_test_image("localhost/test/registry-retry:1").await;
+        let registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let target = registry.reference("test/retry:1");
+        let retries = Arc::new(AtomicU32::new(0));
+        let counter = retries.clone();
+        let mut policy = RetryPolicy::default().on_retry(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        policy.initial_backoff = Duration::from_millis(10);
+        let client = registry
+            .client(RegistryAuth::Anonymous)
+            .unwrap()
+            .with_retry(policy);
+        push_with(
+            &client,
+            &image,
+            &target,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+        .unwrap();
+
+        // 临时性错误按重试策略重试
+        let failure = Failure {
+            method: Some(Method::GET),
+            path: "/manifests/".to_string(),
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            times: 1,
+        };
+        registry.fail(failure.clone());
+        pull_with(&client, &target, &host_platform(), &Cancel::new())
+            .await
+            .unwrap();
+        assert_eq!(retries.load(Ordering::SeqCst), 1);
+
+        // 挂载同样按重试策略重试
+        let config = Manifest::new(registry.manifest("test/retry", "1").unwrap())
+            .to_oci_manifest()
+            .unwrap()
+            .config
+            .digest;
+        registry.fail(Failure {
+            method: Some(Method::POST),
+            path: "/blobs/uploads/".to_string(),
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            times: 1,
+        });
+        let mounted = registry.reference("test/retry-mounted:1");
+        assert_eq!(
+            client
+                .mount_blob(&mounted, &config, "test/retry")
+                .await
+                .unwrap(),
+            BlobMount::Mounted
+        );
+        assert_eq!(retries.load(Ordering::SeqCst), 2);
+
+        let client = client.with_retry(RetryPolicy::none());
+        registry.fail(failure);
+        assert!(
+            pull_with(&client, &target, &host_platform(), &Cancel::new())
+                .await
+                .is_err()
+        );
+        pull_with(&client, &target, &host_platform(), &Cancel::new())
+            .await
+            .unwrap();
+        registry.shutdown().await.unwrap();
+    }
+}
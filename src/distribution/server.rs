@@ -0,0 +1,1166 @@
+use crate::distribution::artifact::Referrer;
+use crate::distribution::client::OCI_SUBJECT;
+use crate::filesystem::FileSystem;
+use crate::image::config::ConfigFile;
+use crate::image::layer::LayerMetadata;
+use crate::image::manifest::Manifest;
+use crate::image::Repositories;
+use crate::util::{lock, DigestPre};
+use anyhow::{Context, Result};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{
+    HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LINK, LOCATION, RANGE,
+};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::{debug, warn};
+use oci_distribution::manifest::{OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE};
+use oci_distribution::Reference;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// 推送到本服务的镜像在images.json中记录的registry名，如`localhost/app/foo:1`
+pub const LOCAL_REGISTRY: &str = "localhost";
+
+/// 服务端选项
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// 监听地址，端口为0时由系统分配
+    pub addr: SocketAddr,
+    /// 是否开放推送接口
+    pub allow_push: bool,
+}
+
+///
+/// 以本地存储（FileSystem）为后端的OCI distribution服务，供同一站点的其它网关拉取镜像。
+/// 不做认证；仓库名可省略registry（`app/foo`匹配本地的`registry/app/foo`），
+/// 按digest获取manifest或blob时不区分仓库
+pub struct RegistryServer {
+    local_addr: SocketAddr,
+    shutdown: tokio::sync::oneshot::Sender<()>,
+    handle: tokio::task::JoinHandle<hyper::Result<()>>,
+}
+
+impl RegistryServer {
+    /// 启动服务
+    pub async fn serve(options: ServerOptions) -> Result<Self> {
+        let registry = Arc::new(Registry::new(Arc::new(LocalStorage), options.allow_push));
+        Self::bind(options.addr, move |req| handle(registry.clone(), req))
+    }
+    /// 监听`addr`，请求交给`handler`处理
+    pub(super) fn bind<F, R>(addr: SocketAddr, handler: F) -> Result<Self>
+    where
+        F: Fn(Request<Body>) -> R + Clone + Send + Sync + 'static,
+        R: Future<Output = Response<Body>> + Send + 'static,
+    {
+        let make_service = make_service_fn(move |_| {
+            let handler = handler.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let response = handler(req);
+                    async move { Ok::<_, Infallible>(response.await) }
+                }))
+            }
+        });
+        let server = hyper::Server::try_bind(&addr)
+            .with_context(|| format!("监听{}失败", addr))?
+            .serve(make_service);
+        let local_addr = server.local_addr();
+        let (shutdown, rx) = tokio::sync::oneshot::channel::<()>();
+        let handle = tokio::spawn(server.with_graceful_shutdown(async {
+            rx.await.ok();
+        }));
+        debug!("registry服务已启动: {}", local_addr);
+        Ok(Self {
+            local_addr,
+            shutdown,
+            handle,
+        })
+    }
+    /// 实际监听的地址
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+    /// 停止服务，等待处理中的请求完成
+    pub async fn shutdown(self) -> Result<()> {
+        self.shutdown.send(()).ok();
+        self.handle.await??;
+        Ok(())
+    }
+}
+
+/// manifest原始字节及其媒体类型、digest（带`sha256:`前缀）
+pub(super) struct StoredManifest {
+    pub(super) data: Vec<u8>,
+    pub(super) media_type: String,
+    pub(super) digest: String,
+}
+
+/// blob内容：存储中的文件或内存中的数据
+pub(super) enum Blob {
+    File(PathBuf),
+    #[cfg(feature = "test-registry")]
+    Data(Bytes),
+}
+
+///
+/// registry服务的存储后端。路由、分页、上传会话与digest校验由服务统一处理，
+/// 存储只负责按仓库读写manifest与blob。方法在阻塞线程池中调用
+pub(super) trait Storage: Send + Sync + 'static {
+    /// 所有仓库名，已排序
+    fn catalog(&self) -> std::result::Result<Vec<String>, ApiError>;
+    /// 仓库的tag，已排序；仓库不存在时为空
+    fn tags(&self, name: &str) -> std::result::Result<Vec<String>, ApiError>;
+    /// 按tag或digest查找manifest
+    fn manifest(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> std::result::Result<Option<StoredManifest>, ApiError>;
+    /// 仓库中的blob
+    fn blob(&self, name: &str, digest: &str) -> std::result::Result<Option<Blob>, ApiError>;
+    /// 从仓库`from`挂载blob到仓库`name`，不能挂载时返回false
+    fn mount_blob(
+        &self,
+        name: &str,
+        digest: &str,
+        from: Option<&str>,
+    ) -> std::result::Result<bool, ApiError>;
+    /// 上传会话写入的临时文件
+    fn upload_file(&self) -> std::result::Result<NamedTempFile, ApiError>;
+    /// 保存digest已校验的上传数据
+    fn save_blob(
+        &self,
+        name: &str,
+        digest: &str,
+        file: NamedTempFile,
+    ) -> std::result::Result<(), ApiError>;
+    ///
+    /// 保存digest已校验的manifest，`reference`为tag时同时打tag。
+    /// 返回已记录为referrer的subject digest
+    fn put_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+        digest: &str,
+        data: &[u8],
+    ) -> std::result::Result<Option<String>, ApiError>;
+    /// 按digest删除manifest，默认不支持
+    fn delete_manifest(&self, _name: &str, _digest: &str) -> std::result::Result<(), ApiError> {
+        Err(unsupported())
+    }
+    /// 引用了`digest`的manifest，不支持referrers API时返回None
+    fn referrers(
+        &self,
+        _name: &str,
+        _digest: &str,
+    ) -> std::result::Result<Option<Vec<Referrer>>, ApiError> {
+        Ok(None)
+    }
+}
+
+/// 路由共享的状态：存储后端与进行中的上传会话
+pub(super) struct Registry {
+    storage: Arc<dyn Storage>,
+    allow_push: bool,
+    uploads: Mutex<HashMap<String, Upload>>,
+    next_upload: AtomicU64,
+}
+
+impl Registry {
+    pub(super) fn new(storage: Arc<dyn Storage>, allow_push: bool) -> Self {
+        Self {
+            storage,
+            allow_push,
+            uploads: Mutex::new(HashMap::new()),
+            next_upload: AtomicU64::new(0),
+        }
+    }
+    /// 未完成（未上传完也未取消）的上传会话数
+    #[cfg(feature = "test-registry")]
+    pub(super) fn pending_uploads(&self) -> usize {
+        lock(&self.uploads).len()
+    }
+}
+
+/// 上传会话：数据写入存储提供的临时文件，不在内存中缓存；会话结束或取消时临时文件被删除
+struct Upload {
+    file: NamedTempFile,
+    size: u64,
+}
+
+/// OCI distribution规范定义的错误响应
+pub(super) struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    /// 401时返回的WWW-Authenticate
+    challenge: Option<String>,
+}
+
+impl ApiError {
+    pub(super) fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            challenge: None,
+        }
+    }
+    #[cfg(feature = "test-registry")]
+    pub(super) fn with_challenge(mut self, challenge: String) -> Self {
+        self.challenge = Some(challenge);
+        self
+    }
+    pub(super) fn response(&self) -> Response<Body> {
+        let body = serde_json::json!({
+            "errors": [{"code": self.code, "message": self.message}]
+        });
+        let mut response = Response::new(Body::from(body.to_string()));
+        *response.status_mut() = self.status;
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        if let Some(challenge) = self.challenge.as_ref().and_then(|x| x.parse().ok()) {
+            response
+                .headers_mut()
+                .insert(hyper::header::WWW_AUTHENTICATE, challenge);
+        }
+        response
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        warn!("registry服务内部错误：{:?}", e);
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "UNKNOWN",
+            format!("{}", e),
+        )
+    }
+}
+
+pub(super) type ApiResult = std::result::Result<Response<Body>, ApiError>;
+
+enum Route<'a> {
+    Base,
+    Catalog,
+    Tags(&'a str),
+    Manifest(&'a str, &'a str),
+    Referrers(&'a str, &'a str),
+    Uploads(&'a str, Option<&'a str>),
+    Blob(&'a str, &'a str),
+}
+
+fn parse_route(path: &str) -> Option<Route<'_>> {
+    let rest = path.strip_prefix("/v2")?;
+    if rest.is_empty() || rest == "/" {
+        return Some(Route::Base);
+    }
+    let rest = rest.strip_prefix('/')?;
+    if rest == "_catalog" {
+        return Some(Route::Catalog);
+    }
+    if let Some(name) = rest.strip_suffix("/tags/list") {
+        return Some(Route::Tags(name));
+    }
+    if let Some((name, reference)) = rest.rsplit_once("/manifests/") {
+        return Some(Route::Manifest(name, reference));
+    }
+    if let Some((name, digest)) = rest.rsplit_once("/referrers/") {
+        return Some(Route::Referrers(name, digest));
+    }
+    if let Some(name) = rest
+        .strip_suffix("/blobs/uploads/")
+        .or_else(|| rest.strip_suffix("/blobs/uploads"))
+    {
+        return Some(Route::Uploads(name, None));
+    }
+    if let Some((name, uuid)) = rest.rsplit_once("/blobs/uploads/") {
+        return Some(Route::Uploads(name, Some(uuid)));
+    }
+    if let Some((name, digest)) = rest.rsplit_once("/blobs/") {
+        return Some(Route::Blob(name, digest));
+    }
+    None
+}
+
+/// 处理`/v2/`下的请求
+pub(super) async fn handle(registry: Arc<Registry>, req: Request<Body>) -> Response<Body> {
+    debug!("registry服务: {} {}", req.method(), req.uri());
+    with_api_version(route(registry, req).await.unwrap_or_else(|e| e.response()))
+}
+
+/// 添加distribution API版本响应头
+pub(super) fn with_api_version(mut response: Response<Body>) -> Response<Body> {
+    response.headers_mut().insert(
+        "Docker-Distribution-API-Version",
+        HeaderValue::from_static("registry/2.0"),
+    );
+    response
+}
+
+async fn route(registry: Arc<Registry>, req: Request<Body>) -> ApiResult {
+    let path = req.uri().path().to_string();
+    let query = parse_query(req.uri().query());
+    let method = req.method().clone();
+    let head = method == Method::HEAD;
+    let range = req
+        .headers()
+        .get(RANGE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_string());
+    let route = parse_route(&path).ok_or(ApiError::new(
+        StatusCode::NOT_FOUND,
+        "NAME_UNKNOWN",
+        "未知的请求路径",
+    ))?;
+    let storage = registry.storage.clone();
+    // 存储的读写为同步文件I/O（解析layer元数据还需解压整个blob），在阻塞线程池中执行
+    match (route, method) {
+        (Route::Base, Method::GET | Method::HEAD) => Ok(json_response(StatusCode::OK, "{}")),
+        (Route::Catalog, Method::GET) => blocking(move || catalog(&*storage, &query)).await,
+        (Route::Tags(name), Method::GET) => {
+            let name = name.to_string();
+            blocking(move || tags_list(&*storage, &name, &query)).await
+        }
+        (Route::Manifest(name, reference), Method::GET | Method::HEAD) => {
+            let (name, reference) = (name.to_string(), reference.to_string());
+            blocking(move || get_manifest(&*storage, &name, &reference, head)).await
+        }
+        (Route::Referrers(name, digest), Method::GET) => {
+            let (name, digest) = (name.to_string(), digest.to_string());
+            blocking(move || referrers(&*storage, &name, &digest, &query)).await
+        }
+        (Route::Blob(name, digest), Method::GET | Method::HEAD) => {
+            let (name, digest) = (name.to_string(), digest.to_string());
+            blocking(move || get_blob(&*storage, &name, &digest, range.as_deref(), head)).await
+        }
+        (Route::Manifest(name, reference), Method::PUT) if registry.allow_push => {
+            let (name, reference) = (name.to_string(), reference.to_string());
+            let data = read_body(req).await?;
+            blocking(move || put_manifest(&*storage, &name, &reference, data)).await
+        }
+        (Route::Manifest(name, reference), Method::DELETE) if registry.allow_push => {
+            let (name, reference) = (name.to_string(), reference.to_string());
+            blocking(move || storage.delete_manifest(&name, &reference)).await?;
+            Ok(status_response(StatusCode::ACCEPTED))
+        }
+        (Route::Uploads(name, None), Method::POST) if registry.allow_push => {
+            start_upload(&registry, name, &query, req.into_body()).await
+        }
+        (Route::Uploads(name, Some(uuid)), Method::PATCH) if registry.allow_push => {
+            patch_upload(&registry, name, uuid, req.into_body()).await
+        }
+        (Route::Uploads(name, Some(uuid)), Method::PUT) if registry.allow_push => {
+            finish_upload(&registry, name, uuid, &query, req.into_body()).await
+        }
+        (Route::Uploads(_, Some(uuid)), Method::DELETE) if registry.allow_push => {
+            cancel_upload(&registry, uuid)
+        }
+        _ => Err(unsupported()),
+    }
+}
+
+/// 在阻塞线程池中执行请求处理
+async fn blocking<T, F>(f: F) -> std::result::Result<T, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> std::result::Result<T, ApiError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(anyhow::Error::from)?
+}
+
+fn catalog(storage: &dyn Storage, query: &HashMap<String, String>) -> ApiResult {
+    let (names, next) = paginate(storage.catalog()?, query, "/v2/_catalog");
+    let body = serde_json::json!({ "repositories": names });
+    list_response(&body, next)
+}
+
+fn tags_list(storage: &dyn Storage, name: &str, query: &HashMap<String, String>) -> ApiResult {
+    let tags = storage.tags(name)?;
+    if tags.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "NAME_UNKNOWN",
+            format!("仓库{}不存在", name),
+        ));
+    }
+    let path = format!("/v2/{}/tags/list", name);
+    let (tags, next) = paginate(tags, query, &path);
+    let body = serde_json::json!({"name": name, "tags": tags});
+    list_response(&body, next)
+}
+
+///
+/// 按`n`、`last`参数分页（`items`已排序），
+/// 返回当前页及下一页的`Link`响应头
+fn paginate(
+    items: Vec<String>,
+    query: &HashMap<String, String>,
+    path: &str,
+) -> (Vec<String>, Option<String>) {
+    let mut items: Vec<String> = match query.get("last") {
+        Some(last) => items.into_iter().filter(|x| x > last).collect(),
+        None => items,
+    };
+    let mut next = None;
+    if let Some(n) = query.get("n").and_then(|x| x.parse::<usize>().ok()) {
+        if items.len() > n {
+            items.truncate(n);
+            next = items
+                .last()
+                .map(|last| format!("<{}?n={}&last={}>; rel=\"next\"", path, n, last));
+        }
+    }
+    (items, next)
+}
+
+fn list_response(body: &serde_json::Value, next: Option<String>) -> ApiResult {
+    let mut response = json_response(StatusCode::OK, &body.to_string());
+    if let Some(next) = next {
+        response.headers_mut().insert(LINK, header_value(&next)?);
+    }
+    Ok(response)
+}
+
+fn get_manifest(storage: &dyn Storage, name: &str, reference: &str, head: bool) -> ApiResult {
+    let manifest = storage.manifest(name, reference)?.ok_or(ApiError::new(
+        StatusCode::NOT_FOUND,
+        "MANIFEST_UNKNOWN",
+        format!("manifest {}:{}不存在", name, reference),
+    ))?;
+    let size = manifest.data.len();
+    let body = if head {
+        Body::empty()
+    } else {
+        Body::from(manifest.data)
+    };
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, header_value(&manifest.media_type)?);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+    headers.insert("Docker-Content-Digest", header_value(&manifest.digest)?);
+    Ok(response)
+}
+
+/// referrers API：返回引用了`digest`的manifest列表，支持按`artifactType`过滤
+fn referrers(
+    storage: &dyn Storage,
+    name: &str,
+    digest: &str,
+    query: &HashMap<String, String>,
+) -> ApiResult {
+    let artifact_type = query.get("artifactType");
+    let manifests: Vec<Referrer> = storage
+        .referrers(name, digest)?
+        .ok_or(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "NAME_UNKNOWN",
+            "不支持referrers API",
+        ))?
+        .into_iter()
+        .filter(|x| artifact_type.is_none() || x.artifact_type.as_ref() == artifact_type)
+        .collect();
+    let body = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_IMAGE_INDEX_MEDIA_TYPE,
+        "manifests": manifests,
+    });
+    let mut response = json_response(StatusCode::OK, &body.to_string());
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(OCI_IMAGE_INDEX_MEDIA_TYPE),
+    );
+    if artifact_type.is_some() {
+        headers.insert(
+            "OCI-Filters-Applied",
+            HeaderValue::from_static("artifactType"),
+        );
+    }
+    Ok(response)
+}
+
+fn get_blob(
+    storage: &dyn Storage,
+    name: &str,
+    digest: &str,
+    range: Option<&str>,
+    head: bool,
+) -> ApiResult {
+    let blob = storage.blob(name, digest)?.ok_or(ApiError::new(
+        StatusCode::NOT_FOUND,
+        "BLOB_UNKNOWN",
+        format!("blob {}不存在", digest),
+    ))?;
+    let len = match &blob {
+        Blob::File(path) => std::fs::metadata(path).map_err(anyhow::Error::from)?.len(),
+        #[cfg(feature = "test-registry")]
+        Blob::Data(data) => data.len() as u64,
+    };
+    let (start, end) = match range {
+        Some(range) => parse_range(range, len).ok_or(ApiError::new(
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            "BLOB_UNKNOWN",
+            format!("无效的Range: {}", range),
+        ))?,
+        None => (0, len),
+    };
+    let body = match blob {
+        _ if head => Body::empty(),
+        Blob::File(path) => file_body(path, start, end),
+        #[cfg(feature = "test-registry")]
+        Blob::Data(data) => Body::from(data.slice(start as usize..end as usize)),
+    };
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(end - start));
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert("Docker-Content-Digest", header_value(digest)?);
+    if range.is_some() {
+        headers.insert(
+            CONTENT_RANGE,
+            header_value(&format!("bytes {}-{}/{}", start, end - 1, len))?,
+        );
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+    }
+    Ok(response)
+}
+
+/// 逐块读取文件[start, end)作为响应体
+fn file_body(path: PathBuf, start: u64, end: u64) -> Body {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("打开blob{:?}失败：{:?}", path, e);
+                sender.abort();
+                return;
+            }
+        };
+        if file.seek(SeekFrom::Start(start)).await.is_err() {
+            sender.abort();
+            return;
+        }
+        let mut remaining = end - start;
+        let mut buf = vec![0u8; 64 * 1024];
+        while remaining > 0 {
+            let size = buf.len().min(remaining as usize);
+            match file.read(&mut buf[..size]).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    if sender
+                        .send_data(Bytes::copy_from_slice(&buf[..n]))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                    remaining -= n as u64;
+                }
+                Err(e) => {
+                    warn!("读取blob{:?}失败：{:?}", path, e);
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+    body
+}
+
+async fn start_upload(
+    registry: &Registry,
+    name: &str,
+    query: &HashMap<String, String>,
+    body: Body,
+) -> ApiResult {
+    if let Some(digest) = query.get("mount") {
+        let storage = registry.storage.clone();
+        let (target, mount, from) = (name.to_string(), digest.clone(), query.get("from").cloned());
+        if blocking(move || storage.mount_blob(&target, &mount, from.as_deref())).await? {
+            return blob_created(name, digest);
+        }
+    }
+    let storage = registry.storage.clone();
+    let mut upload = blocking(move || {
+        Ok(Upload {
+            file: storage.upload_file()?,
+            size: 0,
+        })
+    })
+    .await?;
+    write_body(&mut upload, body).await?;
+    // 单次POST完成上传
+    if let Some(digest) = query.get("digest") {
+        store_blob(registry, name, digest, upload).await?;
+        return blob_created(name, digest);
+    }
+    let uuid = format!(
+        "{:x}-{:x}",
+        chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+        registry.next_upload.fetch_add(1, Ordering::SeqCst)
+    );
+    let size = upload.size;
+    lock(&registry.uploads).insert(uuid.clone(), upload);
+    upload_accepted(name, &uuid, size)
+}
+
+async fn patch_upload(registry: &Registry, name: &str, uuid: &str, body: Body) -> ApiResult {
+    // 写入期间会话不在表中，同一会话的并发请求返回BLOB_UPLOAD_UNKNOWN
+    let mut upload = lock(&registry.uploads)
+        .remove(uuid)
+        .ok_or(upload_unknown(uuid))?;
+    let written = write_body(&mut upload, body).await;
+    let size = upload.size;
+    lock(&registry.uploads).insert(uuid.to_string(), upload);
+    written?;
+    upload_accepted(name, uuid, size)
+}
+
+async fn finish_upload(
+    registry: &Registry,
+    name: &str,
+    uuid: &str,
+    query: &HashMap<String, String>,
+    body: Body,
+) -> ApiResult {
+    let digest = query.get("digest").ok_or(ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "DIGEST_INVALID",
+        "缺少digest参数",
+    ))?;
+    let mut upload = lock(&registry.uploads)
+        .remove(uuid)
+        .ok_or(upload_unknown(uuid))?;
+    write_body(&mut upload, body).await?;
+    store_blob(registry, name, digest, upload).await?;
+    blob_created(name, digest)
+}
+
+fn cancel_upload(registry: &Registry, uuid: &str) -> ApiResult {
+    lock(&registry.uploads)
+        .remove(uuid)
+        .ok_or(upload_unknown(uuid))?;
+    Ok(status_response(StatusCode::NO_CONTENT))
+}
+
+/// 将请求体逐块追加到上传会话的临时文件
+async fn write_body(upload: &mut Upload, mut body: Body) -> std::result::Result<(), ApiError> {
+    let file = upload
+        .file
+        .as_file()
+        .try_clone()
+        .map_err(anyhow::Error::from)?;
+    let mut file = tokio::fs::File::from_std(file);
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "BLOB_UPLOAD_INVALID",
+                format!("读取请求体失败：{}", e),
+            )
+        })?;
+        file.write_all(&chunk).await.map_err(anyhow::Error::from)?;
+        upload.size += chunk.len() as u64;
+    }
+    file.flush().await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// 校验上传的数据后交给存储保存，校验失败时临时文件被删除
+async fn store_blob(
+    registry: &Registry,
+    name: &str,
+    digest: &str,
+    upload: Upload,
+) -> std::result::Result<(), ApiError> {
+    let hex = digest_hex(digest)?;
+    let storage = registry.storage.clone();
+    let (name, digest) = (name.to_string(), digest.to_string());
+    blocking(move || {
+        let mut hasher = Sha256::new();
+        let mut file = upload.file.reopen().map_err(anyhow::Error::from)?;
+        std::io::copy(&mut file, &mut hasher).map_err(anyhow::Error::from)?;
+        if format!("{:x}", hasher.finalize()) != hex {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "DIGEST_INVALID",
+                format!("上传内容与digest {}不一致", digest),
+            ));
+        }
+        storage.save_blob(&name, &hex.sha256_pre(), upload.file)
+    })
+    .await
+}
+
+fn put_manifest(storage: &dyn Storage, name: &str, reference: &str, data: Bytes) -> ApiResult {
+    let digest = sha256::digest(data.as_ref()).sha256_pre();
+    if reference.contains(':') && reference != digest {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "DIGEST_INVALID",
+            format!("manifest内容digest({})与{}不一致", digest, reference),
+        ));
+    }
+    let subject = storage.put_manifest(name, reference, &digest, &data)?;
+    debug!(
+        "registry服务收到manifest: {}:{} -> {}",
+        name, reference, digest
+    );
+    let mut response = status_response(StatusCode::CREATED);
+    let headers = response.headers_mut();
+    headers.insert(
+        LOCATION,
+        header_value(&format!("/v2/{}/manifests/{}", name, digest))?,
+    );
+    headers.insert("Docker-Content-Digest", header_value(&digest)?);
+    if let Some(subject) = subject {
+        headers.insert(OCI_SUBJECT, header_value(&subject)?);
+    }
+    Ok(response)
+}
+
+///
+/// 本地存储：manifest与tag取自imagedb，blob取自configdb与layerdb。
+/// 不区分仓库，按digest存在即可读取或挂载
+struct LocalStorage;
+
+impl Storage for LocalStorage {
+    fn catalog(&self) -> std::result::Result<Vec<String>, ApiError> {
+        Ok(Repositories::init()?.repository_names())
+    }
+
+    fn tags(&self, name: &str) -> std::result::Result<Vec<String>, ApiError> {
+        Ok(Repositories::init()?.tags(name))
+    }
+
+    fn manifest(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> std::result::Result<Option<StoredManifest>, ApiError> {
+        let digest = if reference.contains(':') {
+            reference.to_string()
+        } else {
+            match Repositories::init()?.find_tag(name, reference) {
+                Some(digest) => digest,
+                None => return Ok(None),
+            }
+        };
+        let hex = digest_hex(&digest)?;
+        let manifest = match Manifest::load(&hex) {
+            Ok(manifest) => manifest,
+            Err(_) => return Ok(None),
+        };
+        let media_type = manifest.media_type().unwrap_or_else(|| {
+            if manifest.is_index() {
+                OCI_IMAGE_INDEX_MEDIA_TYPE.to_string()
+            } else {
+                OCI_IMAGE_MEDIA_TYPE.to_string()
+            }
+        });
+        Ok(Some(StoredManifest {
+            data: manifest.data().to_vec(),
+            media_type,
+            digest: hex.sha256_pre(),
+        }))
+    }
+
+    fn blob(&self, _name: &str, digest: &str) -> std::result::Result<Option<Blob>, ApiError> {
+        Ok(blob_path(digest)?.map(Blob::File))
+    }
+
+    fn mount_blob(
+        &self,
+        _name: &str,
+        digest: &str,
+        _from: Option<&str>,
+    ) -> std::result::Result<bool, ApiError> {
+        Ok(blob_path(digest)?.is_some())
+    }
+
+    fn upload_file(&self) -> std::result::Result<NamedTempFile, ApiError> {
+        // 与blob同目录，保存时直接重命名；非digest文件名不会被gc当作blob
+        Ok(NamedTempFile::new_in(FileSystem.layer_blobs()?).map_err(anyhow::Error::from)?)
+    }
+
+    fn save_blob(
+        &self,
+        _name: &str,
+        digest: &str,
+        file: NamedTempFile,
+    ) -> std::result::Result<(), ApiError> {
+        file.persist(FileSystem.layer_blobs()?.join(digest_hex(digest)?))
+            .map_err(|e| anyhow::Error::from(e.error))?;
+        Ok(())
+    }
+
+    fn put_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+        digest: &str,
+        data: &[u8],
+    ) -> std::result::Result<Option<String>, ApiError> {
+        let manifest = Manifest::new(data.to_vec());
+        if manifest.is_index() {
+            let index = manifest.to_oci_index().map_err(manifest_invalid)?;
+            for entry in index.manifests.iter() {
+                if !FileSystem.exist_manifest(&digest_hex(&entry.digest)?)? {
+                    return Err(blob_unknown(&entry.digest));
+                }
+            }
+        } else {
+            let image_manifest = manifest.to_oci_manifest().map_err(manifest_invalid)?;
+            // 上传时无法区分config与layer，blob统一保存在layerdb中，config需复制到configdb
+            let config_hex = digest_hex(&image_manifest.config.digest)?;
+            let path = blob_path(&image_manifest.config.digest)?
+                .ok_or(blob_unknown(&image_manifest.config.digest))?;
+            let config = std::fs::read(path).map_err(anyhow::Error::from)?;
+            if !FileSystem.exist_config(&config_hex)? {
+                FileSystem.save_config(&config_hex, &config)?;
+            }
+            for layer in image_manifest.layers.iter() {
+                if !FileSystem.exist_layer(&digest_hex(&layer.digest)?)? {
+                    return Err(blob_unknown(&layer.digest));
+                }
+            }
+            // 非镜像config（无rootfs）不记录layer元数据
+            if let Ok(diff_ids) = ConfigFile::diff_ids(&config) {
+                if diff_ids.len() == image_manifest.layers.len() {
+                    let mut parent: Option<String> = None;
+                    for (layer, diff_id) in image_manifest.layers.iter().zip(diff_ids.iter()) {
+                        let metadata = LayerMetadata::from_blob(
+                            &layer.digest,
+                            &layer.media_type,
+                            parent.as_deref(),
+                        )?;
+                        if &metadata.diff_id != diff_id {
+                            return Err(manifest_invalid(anyhow::anyhow!(
+                                "layer[{}]的diff_id({})与config中记录的({})不一致",
+                                layer.digest,
+                                metadata.diff_id,
+                                diff_id
+                            )));
+                        }
+                        metadata.save()?;
+                        parent = Some(metadata.chain_id);
+                    }
+                }
+            }
+        }
+        FileSystem.save_manifest(&digest_hex(digest)?, data)?;
+        if !reference.contains(':') {
+            let image = Reference::with_tag(
+                LOCAL_REGISTRY.to_string(),
+                name.to_string(),
+                reference.to_string(),
+            );
+            Repositories::modify(|repo| repo.update(&image, digest.to_string()))?;
+        }
+        Ok(None)
+    }
+}
+
+/// 本地存储中的blob：config或layer
+fn blob_path(digest: &str) -> std::result::Result<Option<PathBuf>, ApiError> {
+    let hex = digest_hex(digest)?;
+    for dir in [FileSystem.config_sha256()?, FileSystem.layer_blobs()?] {
+        let path = dir.join(&hex);
+        if path.is_file() {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// digest转为本地文件名，只接受sha256，防止路径穿越
+fn digest_hex(digest: &str) -> std::result::Result<String, ApiError> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) if hex.len() == 64 && hex.chars().all(|x| x.is_ascii_hexdigit()) => {
+            Ok(hex.to_ascii_lowercase())
+        }
+        _ => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "DIGEST_INVALID",
+            format!("不支持的digest: {}", digest),
+        )),
+    }
+}
+
+/// 解析`Range: bytes=start-end`，返回[start, end)
+fn parse_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (len.checked_sub(suffix.min(len))?, len)
+        }
+        (start, "") => (start.parse().ok()?, len),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.saturating_add(1).min(len),
+        ),
+    };
+    (start < end && start < len).then_some((start, end))
+}
+
+/// 解析查询参数（百分号解码）
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|x| !x.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+async fn read_body(req: Request<Body>) -> std::result::Result<Bytes, ApiError> {
+    hyper::body::to_bytes(req.into_body()).await.map_err(|e| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "BLOB_UPLOAD_INVALID",
+            format!("读取请求体失败：{}", e),
+        )
+    })
+}
+
+pub(super) fn json_response(status: StatusCode, body: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn blob_created(name: &str, digest: &str) -> ApiResult {
+    let mut response = status_response(StatusCode::CREATED);
+    let headers = response.headers_mut();
+    headers.insert(
+        LOCATION,
+        header_value(&format!("/v2/{}/blobs/{}", name, digest))?,
+    );
+    headers.insert("Docker-Content-Digest", header_value(digest)?);
+    Ok(response)
+}
+
+fn upload_accepted(name: &str, uuid: &str, size: u64) -> ApiResult {
+    let mut response = status_response(StatusCode::ACCEPTED);
+    let headers = response.headers_mut();
+    headers.insert(
+        LOCATION,
+        header_value(&format!("/v2/{}/blobs/uploads/{}", name, uuid))?,
+    );
+    headers.insert("Docker-Upload-UUID", header_value(uuid)?);
+    headers.insert(
+        RANGE,
+        header_value(&format!("0-{}", size.saturating_sub(1)))?,
+    );
+    Ok(response)
+}
+
+fn upload_unknown(uuid: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "BLOB_UPLOAD_UNKNOWN",
+        format!("上传会话{}不存在", uuid),
+    )
+}
+
+pub(super) fn blob_unknown(digest: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "MANIFEST_BLOB_UNKNOWN",
+        format!("blob {}不存在", digest),
+    )
+}
+
+pub(super) fn manifest_invalid(e: anyhow::Error) -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "MANIFEST_INVALID",
+        format!("{}", e),
+    )
+}
+
+fn unsupported() -> ApiError {
+    ApiError::new(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "UNSUPPORTED",
+        "不支持的操作",
+    )
+}
+
+pub(super) fn header_value(value: &str) -> std::result::Result<HeaderValue, ApiError> {
+    HeaderValue::from_str(value).map_err(|e| anyhow::Error::from(e).into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RegistryServer, ServerOptions};
+    use crate::args::BuildArgs;
+    use crate::distribution::client::RegistryClient;
+    use crate::filesystem::test_home;
+    use crate::image::build::build;
+    use crate::image::build::config::instructions::{Copy, Dest, Kind};
+    use crate::image::build::config::BuildConfig;
+    use crate::image::manifest::Manifest;
+    use crate::util::DigestPre;
+    use oci_distribution::client::ClientProtocol;
+    use oci_distribution::secrets::RegistryAuth;
+    use oci_distribution::Reference;
+
+    #[tokio::test]
+    async fn test_serve_local_store() {
+        test_home();
+        let src = tempfile::tempdir().unwrap();
+        let app = src.path().join("app");
+        std::fs::write(&app, "app").unwrap();
+        let args = BuildArgs {
+            config: BuildConfig {
+                kind: Kind::App,
+                copys: vec![Copy(app, "/bin/app".to_string().try_into().unwrap())],
+                cmd: Dest::try_from("/bin/app".to_string()).unwrap(),
+                reproducible: false,
+                squash: false,
+                platforms: Vec::new(),
+                wasm_artifact: false,
+            },
+            image: "localhost/test/server:1".parse().unwrap(),
+        };
+        let manifest_digest = build(&args).await.unwrap();
+
+        let server = RegistryServer::serve(ServerOptions {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            allow_push: true,
+        })
+        .await
+        .unwrap();
+        let addr = server.local_addr();
+        let client = RegistryClient::new(ClientProtocol::Http, RegistryAuth::Anonymous).unwrap();
+        let image: Reference = format!("{}/test/server:1", addr).parse().unwrap();
+        let raw = client.pull_manifest_raw(&image).await.unwrap();
+        assert_eq!(raw.digest, manifest_digest.sha256_pre());
+        assert_eq!(client.list_tags(&image, None).await.unwrap(), vec!["1"]);
+
+        let manifest = Manifest::new(raw.data).to_oci_manifest().unwrap();
+        let layer = &manifest.layers[0];
+        client.pull_blob(&image, &layer.digest).await.unwrap();
+        let response = reqwest::Client::new()
+            .get(format!(
+                "http://{}/v2/test/server/blobs/{}",
+                addr, layer.digest
+            ))
+            .header("Range", "bytes=0-9")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.bytes().await.unwrap().len(), 10);
+
+        let target: Reference = format!("{}/test/pushed:1", addr).parse().unwrap();
+        let data = b"pushed".to_vec();
+        let digest = sha256::digest(data.as_slice()).sha256_pre();
+        assert!(!client.blob_exists(&target, &digest).await.unwrap());
+        client.upload_blob(&target, &digest, &data).await.unwrap();
+        assert!(client.blob_exists(&target, &digest).await.unwrap());
+
+        // 分块上传写入临时文件，完成时校验digest
+        let http = reqwest::Client::new();
+        let uploads = format!("http://{}/v2/test/pushed/blobs/uploads/", addr);
+        let chunks: [&[u8]; 2] = [b"chunk-1,", b"chunk-2"];
+        let data = chunks.concat();
+        let digest = sha256::digest(data.as_slice()).sha256_pre();
+        for expected in [
+            digest.as_str(),
+            "sha256:0000000000000000000000000000000000000000000000000000000000000000",
+        ] {
+            let response = http.post(&uploads).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+            let mut location = response.headers()["Location"].to_str().unwrap().to_string();
+            for chunk in chunks {
+                let response = http
+                    .patch(format!("http://{}{}", addr, location))
+                    .body(chunk)
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+                location = response.headers()["Location"].to_str().unwrap().to_string();
+            }
+            let response = http
+                .put(format!("http://{}{}?digest={}", addr, location, expected))
+                .send()
+                .await
+                .unwrap();
+            if expected == digest {
+                assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+            } else {
+                assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+            }
+        }
+        assert_eq!(client.pull_blob(&target, &digest).await.unwrap(), data);
+
+        // 仓库列表取自images.json，仓库名省略registry
+        let catalog = client.catalog(&addr.to_string(), None).await.unwrap();
+        assert!(
+            catalog.contains(&"test/server".to_string()),
+            "{:?}",
+            catalog
+        );
+        server.shutdown().await.unwrap();
+    }
+}
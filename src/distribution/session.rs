@@ -0,0 +1,246 @@
+use crate::distribution::artifact::{
+    pull_artifact_with, push_artifact_with, referrers_with, Artifact, PulledArtifact, Referrer,
+};
+use crate::distribution::client::RegistryClient;
+use crate::distribution::delete::delete_with;
+use crate::distribution::pull::pull_with;
+use crate::distribution::push::{push_all_with, push_with, PushError, PushOptions, PushReport};
+use crate::distribution::update::{check_update_with, UpdateCheck};
+use crate::image::index::host_platform;
+use crate::util::cancel::Cancel;
+use crate::util::lock;
+use anyhow::Result;
+use oci_distribution::client::ClientProtocol;
+use oci_distribution::manifest::Platform;
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// 共享会话按用户名、密码区分，匿名为`None`
+type SessionKey = Option<(String, String)>;
+
+///
+/// 长期持有的registry会话：保存访问协议、认证信息、已获取的token与http连接池，
+/// 供多次pull、push、tag列表等操作共享，避免每次调用都重新鉴权、重新建立TLS连接。
+/// token过期后在下次请求前自动刷新。克隆后共享同一会话。
+/// 需要取消或超时控制时，将`client()`传给`pull_with`、`push_with`等函数
+#[derive(Clone)]
+pub struct RegistrySession {
+    client: Arc<RegistryClient>,
+}
+
+impl RegistrySession {
+    pub fn new(protocol: ClientProtocol, auth: RegistryAuth) -> Result<Self> {
+        Ok(Self {
+            client: Arc::new(RegistryClient::new(protocol, auth)?),
+        })
+    }
+
+    /// 以HTTPS访问registry的会话
+    pub fn https(auth: RegistryAuth) -> Result<Self> {
+        Self::new(ClientProtocol::Https, auth)
+    }
+
+    ///
+    /// 进程内按认证信息共享的HTTPS会话，`pull`、`push`等不传入会话的便捷函数使用，
+    /// 多次调用之间共享token与http连接池
+    pub fn shared(auth: &RegistryAuth) -> Result<Self> {
+        static SESSIONS: OnceLock<Mutex<HashMap<SessionKey, RegistrySession>>> = OnceLock::new();
+        let key = match auth {
+            RegistryAuth::Anonymous => None,
+            RegistryAuth::Basic(username, password) => Some((username.clone(), password.clone())),
+        };
+        let mut sessions = lock(SESSIONS.get_or_init(Default::default));
+        if let Some(session) = sessions.get(&key) {
+            return Ok(session.clone());
+        }
+        let session = Self::https(auth.clone())?;
+        sessions.insert(key, session.clone());
+        Ok(session)
+    }
+
+    /// 以自定义的客户端（如设置了重试策略）创建会话
+    pub fn from_client(client: RegistryClient) -> Self {
+        Self {
+            client: Arc::new(client),
+        }
+    }
+
+    /// 会话使用的registry客户端，可传给各`*_with`函数
+    pub fn client(&self) -> &RegistryClient {
+        &self.client
+    }
+
+    /// 拉取镜像，镜像索引按本机平台选择manifest，见[`crate::distribution::pull::pull`]
+    pub async fn pull(&self, image: &Reference) -> Result<String> {
+        pull_with(&self.client, image, &host_platform(), &Cancel::new()).await
+    }
+
+    /// 拉取镜像，镜像索引按指定平台选择manifest
+    pub async fn pull_platform(&self, image: &Reference, platform: &Platform) -> Result<String> {
+        pull_with(&self.client, image, platform, &Cancel::new()).await
+    }
+
+    /// 推送本地镜像
+    pub async fn push(&self, image: &Reference) -> Result<PushReport, PushError> {
+        push_with(
+            &self.client,
+            image,
+            image,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+    }
+
+    /// 将本地镜像推送为另一个镜像
+    pub async fn push_to(
+        &self,
+        image: &Reference,
+        target: &Reference,
+    ) -> Result<PushReport, PushError> {
+        push_with(
+            &self.client,
+            image,
+            target,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+    }
+
+    /// 将本地镜像依次推送到多个目标，见[`crate::distribution::push::push_all`]
+    pub async fn push_all(
+        &self,
+        image: &Reference,
+        targets: &[Reference],
+    ) -> Result<Vec<Result<PushReport, PushError>>, PushError> {
+        push_all_with(
+            &self.client,
+            image,
+            targets,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+    }
+
+    /// 检查镜像是否有更新
+    pub async fn check_update(&self, image: &Reference) -> Result<UpdateCheck> {
+        check_update_with(&self.client, image).await
+    }
+
+    /// 列出远端仓库的所有tag
+    pub async fn list_tags(&self, image: &Reference) -> Result<Vec<String>> {
+        self.client.list_tags(image, None).await
+    }
+
+    /// 列出registry中的所有仓库
+    pub async fn catalog(&self, registry: &str) -> Result<Vec<String>> {
+        self.client.catalog(registry, None).await
+    }
+
+    /// 删除远端manifest，返回被删除的digest
+    pub async fn delete(&self, image: &Reference) -> Result<String> {
+        delete_with(&self.client, image).await
+    }
+
+    /// 推送artifact，见[`crate::distribution::artifact::push_artifact_with`]
+    pub async fn push_artifact(
+        &self,
+        target: &Reference,
+        artifact: &Artifact,
+    ) -> Result<PushReport, PushError> {
+        push_artifact_with(&self.client, target, artifact, &Cancel::new()).await
+    }
+
+    /// 拉取artifact的文件到`dir`
+    pub async fn pull_artifact(&self, image: &Reference, dir: &Path) -> Result<PulledArtifact> {
+        pull_artifact_with(&self.client, image, dir, &Cancel::new()).await
+    }
+
+    /// 列出引用了本地镜像的artifact
+    pub async fn referrers(
+        &self,
+        image: &Reference,
+        artifact_type: Option<&str>,
+    ) -> Result<Vec<Referrer>> {
+        referrers_with(&self.client, image, artifact_type).await
+    }
+}
+
+#[cfg(all(test, feature = "test-registry"))]
+mod test {
+    use super::RegistrySession;
+    use crate::distribution::test_registry::{TestAuth, TestRegistry, TestRegistryOptions};
+    use oci_distribution::secrets::RegistryAuth;
+    use std::time::Duration;
+
+    #[test]
+    fn test_shared_session() {
+        let basic = |password: &str| RegistryAuth::Basic("user".to_string(), password.to_string());
+        let anonymous = RegistrySession::shared(&RegistryAuth::Anonymous).unwrap();
+        let user = RegistrySession::shared(&basic("secret")).unwrap();
+        assert!(std::ptr::eq(
+            anonymous.client(),
+            RegistrySession::shared(&RegistryAuth::Anonymous)
+                .unwrap()
+                .client()
+        ));
+        assert!(std::ptr::eq(
+            user.client(),
+            RegistrySession::shared(&basic("secret")).unwrap().client()
+        ));
+        assert!(!std::ptr::eq(anonymous.client(), user.client()));
+        assert!(!std::ptr::eq(
+            user.client(),
+            RegistrySession::shared(&basic("other")).unwrap().client()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_session_reuses_and_refreshes_token() {
+        let registry = TestRegistry::start(TestRegistryOptions {
+            auth: TestAuth::Bearer {
+                username: "user".to_string(),
+                password: "secret".to_string(),
+            },
+            token_expires_in: Some(Duration::from_secs(1)),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let session = registry
+            .session(RegistryAuth::Basic(
+                "user".to_string(),
+                "secret".to_string(),
+            ))
+            .unwrap();
+        let image = registry.reference("test/session:1");
+        let token_requests = || {
+            registry
+                .requests()
+                .iter()
+                .filter(|(_, path)| path.starts_with("/token"))
+                .count()
+        };
+
+        // 仓库为空，tag列表返回404；首次请求经401质询获取token，之后同一scope复用token
+        assert!(session.list_tags(&image).await.is_err());
+        assert!(session.clone().list_tags(&image).await.is_err());
+        assert_eq!(token_requests(), 1);
+
+        // token过期后先刷新再请求，不再经过401
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        let before = registry.requests().len();
+        assert!(session.list_tags(&image).await.is_err());
+        let requests = registry.requests();
+        let paths: Vec<&str> = requests[before..].iter().map(|x| x.1.as_str()).collect();
+        assert_eq!(paths.len(), 2, "{:?}", paths);
+        assert!(paths[0].starts_with("/token"));
+        assert_eq!(paths[1], "/v2/test/session/tags/list");
+        registry.shutdown().await.unwrap();
+    }
+}
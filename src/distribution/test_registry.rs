@@ -0,0 +1,484 @@
+use crate::distribution::artifact::Referrer;
+use crate::distribution::client::RegistryClient;
+use crate::distribution::server::{
+    self, blob_unknown, json_response, manifest_invalid, with_api_version, ApiError, ApiResult,
+    Blob, Registry, RegistryServer, Storage, StoredManifest,
+};
+use crate::distribution::session::RegistrySession;
+use crate::util::lock;
+use anyhow::Result;
+use base64::Engine;
+use hyper::body::Bytes;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use oci_distribution::client::ClientProtocol;
+use oci_distribution::manifest::{ImageIndexEntry, OciDescriptor, OCI_IMAGE_MEDIA_TYPE};
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+
+/// 测试registry的认证方式
+#[derive(Debug, Clone, Default)]
+pub enum TestAuth {
+    #[default]
+    Anonymous,
+    /// 要求Basic认证
+    Basic { username: String, password: String },
+    /// 要求Bearer token，token由`/token`以Basic认证签发
+    Bearer { username: String, password: String },
+}
+
+/// 注入的失败：匹配的请求直接返回`status`，共生效`times`次
+#[derive(Debug, Clone)]
+pub struct Failure {
+    /// 为None时匹配所有方法
+    pub method: Option<Method>,
+    /// 请求路径包含该字符串时匹配
+    pub path: String,
+    pub status: StatusCode,
+    pub times: usize,
+}
+
+/// 测试registry选项
+#[derive(Debug, Clone)]
+pub struct TestRegistryOptions {
+    pub auth: TestAuth,
+    /// 每个请求处理前的延迟
+    pub latency: Option<Duration>,
+    /// 是否支持删除manifest
+    pub allow_delete: bool,
+    /// 签发的Bearer token有效期，None时token不过期且响应中不带`expires_in`
+    pub token_expires_in: Option<Duration>,
+    /// 是否支持referrers API，为false时客户端需使用tag schema记录referrers
+    pub referrers: bool,
+    pub failures: Vec<Failure>,
+}
+
+impl Default for TestRegistryOptions {
+    fn default() -> Self {
+        Self {
+            auth: TestAuth::Anonymous,
+            latency: None,
+            allow_delete: true,
+            token_expires_in: None,
+            referrers: true,
+            failures: Vec::new(),
+        }
+    }
+}
+
+///
+/// 进程内的内存OCI registry，监听127.0.0.1的随机端口，用于离线测试pull、push、copy等。
+/// 路由与上传会话复用[`RegistryServer`]，本身只负责认证、延迟与失败注入及内存存储。
+/// 不校验token的scope；blob按仓库记录，跨仓库挂载要求源仓库中存在该blob
+pub struct TestRegistry {
+    server: RegistryServer,
+    shared: Arc<Shared>,
+    storage: Arc<MemoryStorage>,
+}
+
+impl TestRegistry {
+    pub async fn start(options: TestRegistryOptions) -> Result<Self> {
+        let storage = Arc::new(MemoryStorage {
+            allow_delete: options.allow_delete,
+            referrers: options.referrers,
+            store: Mutex::new(Store::default()),
+        });
+        let shared = Arc::new(Shared {
+            options: Mutex::new(options),
+            registry: Arc::new(Registry::new(storage.clone(), true)),
+            requests: Mutex::new(Vec::new()),
+            tokens: Mutex::new(HashMap::new()),
+            next_token: AtomicU64::new(0),
+        });
+        let handler_shared = shared.clone();
+        let server = RegistryServer::bind("127.0.0.1:0".parse()?, move |req| {
+            handle(handler_shared.clone(), req)
+        })?;
+        Ok(Self {
+            server,
+            shared,
+            storage,
+        })
+    }
+    /// registry地址，如`127.0.0.1:34567`
+    pub fn host(&self) -> String {
+        self.server.local_addr().to_string()
+    }
+    /// 本registry上的镜像引用，`name`如`test/app:1`
+    pub fn reference(&self, name: &str) -> Reference {
+        format!("{}/{}", self.host(), name)
+            .parse()
+            .expect("非法的镜像名")
+    }
+    /// 以HTTP访问本registry的客户端
+    pub fn client(&self, auth: RegistryAuth) -> Result<RegistryClient> {
+        RegistryClient::new(ClientProtocol::Http, auth)
+    }
+    /// 以HTTP访问本registry的会话
+    pub fn session(&self, auth: RegistryAuth) -> Result<RegistrySession> {
+        RegistrySession::new(ClientProtocol::Http, auth)
+    }
+    /// 追加注入的失败
+    pub fn fail(&self, failure: Failure) {
+        lock(&self.shared.options).failures.push(failure);
+    }
+    /// 修改请求延迟
+    pub fn set_latency(&self, latency: Option<Duration>) {
+        lock(&self.shared.options).latency = latency;
+    }
+    /// 已收到的请求：方法与路径（含查询参数）
+    pub fn requests(&self) -> Vec<(Method, String)> {
+        lock(&self.shared.requests).clone()
+    }
+    /// 仓库中是否存在blob
+    pub fn has_blob(&self, repository: &str, digest: &str) -> bool {
+        lock(&self.storage.store).repo_has_blob(repository, digest)
+    }
+    /// 未完成（未上传完也未取消）的上传会话数
+    pub fn uploads(&self) -> usize {
+        self.shared.registry.pending_uploads()
+    }
+    /// 按tag或digest获取manifest原始字节
+    pub fn manifest(&self, repository: &str, reference: &str) -> Option<Vec<u8>> {
+        let store = lock(&self.storage.store);
+        let digest = store.resolve(repository, reference)?;
+        store.manifests.get(&digest).map(|x| x.0.clone())
+    }
+    pub async fn shutdown(self) -> Result<()> {
+        self.server.shutdown().await
+    }
+}
+
+struct Shared {
+    options: Mutex<TestRegistryOptions>,
+    registry: Arc<Registry>,
+    requests: Mutex<Vec<(Method, String)>>,
+    /// 已签发的token及其过期时间
+    tokens: Mutex<HashMap<String, Option<Instant>>>,
+    next_token: AtomicU64,
+}
+
+async fn handle(shared: Arc<Shared>, req: Request<Body>) -> Response<Body> {
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map(|x| x.to_string())
+        .unwrap_or_default();
+    lock(&shared.requests).push((req.method().clone(), path_and_query));
+    let latency = lock(&shared.options).latency;
+    if let Some(latency) = latency {
+        tokio::time::sleep(latency).await;
+    }
+    if let Some(status) = take_failure(&shared, req.method(), req.uri().path()) {
+        return with_api_version(ApiError::new(status, "UNKNOWN", "注入的失败").response());
+    }
+    if req.uri().path() == "/token" {
+        return issue_token(&shared, &req).unwrap_or_else(|e| e.response());
+    }
+    if let Err(e) = authorize(&shared, &req) {
+        return with_api_version(e.response());
+    }
+    server::handle(shared.registry.clone(), req).await
+}
+
+fn take_failure(shared: &Shared, method: &Method, path: &str) -> Option<StatusCode> {
+    let mut options = lock(&shared.options);
+    let index = options.failures.iter().position(|x| {
+        x.times > 0 && x.method.as_ref().is_none_or(|m| m == method) && path.contains(&x.path)
+    })?;
+    let failure = &mut options.failures[index];
+    failure.times -= 1;
+    Some(failure.status)
+}
+
+/// 签发Bearer token，要求Basic认证
+fn issue_token(shared: &Shared, req: &Request<Body>) -> ApiResult {
+    let options = lock(&shared.options).clone();
+    let (username, password) = match &options.auth {
+        TestAuth::Bearer { username, password } => (username, password),
+        _ => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "UNSUPPORTED",
+                "/token",
+            ))
+        }
+    };
+    if !basic_matches(req, username, password) {
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "UNAUTHORIZED",
+            "用户名或密码错误",
+        ));
+    }
+    let token = format!("token-{}", shared.next_token.fetch_add(1, Ordering::SeqCst));
+    let expires_at = options.token_expires_in.map(|x| Instant::now() + x);
+    lock(&shared.tokens).insert(token.clone(), expires_at);
+    let body = match options.token_expires_in {
+        Some(expires_in) => {
+            serde_json::json!({ "token": token, "expires_in": expires_in.as_secs() })
+        }
+        None => serde_json::json!({ "token": token }),
+    };
+    Ok(json_response(StatusCode::OK, &body.to_string()))
+}
+
+fn authorize(shared: &Shared, req: &Request<Body>) -> Result<(), ApiError> {
+    let auth = lock(&shared.options).auth.clone();
+    let (authorized, challenge) = match &auth {
+        TestAuth::Anonymous => return Ok(()),
+        TestAuth::Basic { username, password } => (
+            basic_matches(req, username, password),
+            r#"Basic realm="test-registry""#.to_string(),
+        ),
+        TestAuth::Bearer { .. } => {
+            let authorized = req
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| x.strip_prefix("Bearer "))
+                .is_some_and(|token| match lock(&shared.tokens).get(token) {
+                    Some(Some(expires_at)) => Instant::now() < *expires_at,
+                    Some(None) => true,
+                    None => false,
+                });
+            let host = req
+                .headers()
+                .get(hyper::header::HOST)
+                .and_then(|x| x.to_str().ok())
+                .unwrap_or_default();
+            (
+                authorized,
+                format!(
+                    r#"Bearer realm="http://{}/token",service="test-registry""#,
+                    host
+                ),
+            )
+        }
+    };
+    if authorized {
+        return Ok(());
+    }
+    Err(
+        ApiError::new(StatusCode::UNAUTHORIZED, "UNAUTHORIZED", "需要认证")
+            .with_challenge(challenge),
+    )
+}
+
+fn basic_matches(req: &Request<Body>, username: &str, password: &str) -> bool {
+    let expected = format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password))
+    );
+    req.headers()
+        .get(AUTHORIZATION)
+        .is_some_and(|x| x.as_bytes() == expected.as_bytes())
+}
+
+/// 内存存储
+struct MemoryStorage {
+    allow_delete: bool,
+    referrers: bool,
+    store: Mutex<Store>,
+}
+
+#[derive(Default)]
+struct Store {
+    blobs: HashMap<String, Bytes>,
+    /// 各仓库拥有的blob
+    repo_blobs: HashMap<String, HashSet<String>>,
+    /// digest -> (内容, 媒体类型)
+    manifests: HashMap<String, (Vec<u8>, String)>,
+    /// 仓库 -> tag -> digest
+    tags: HashMap<String, BTreeMap<String, String>>,
+    /// (仓库, subject digest) -> 引用了该manifest的artifact
+    referrers: HashMap<(String, String), Vec<Referrer>>,
+}
+
+impl Store {
+    fn repo_has_blob(&self, repository: &str, digest: &str) -> bool {
+        self.repo_blobs
+            .get(repository)
+            .is_some_and(|x| x.contains(digest))
+    }
+    fn resolve(&self, repository: &str, reference: &str) -> Option<String> {
+        if reference.contains(':') {
+            Some(reference.to_string())
+        } else {
+            self.tags.get(repository)?.get(reference).cloned()
+        }
+    }
+    fn add_blob(&mut self, repository: &str, digest: &str) {
+        self.repo_blobs
+            .entry(repository.to_string())
+            .or_default()
+            .insert(digest.to_string());
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn catalog(&self) -> Result<Vec<String>, ApiError> {
+        let store = lock(&self.store);
+        let mut repositories: Vec<String> = store.repo_blobs.keys().cloned().collect();
+        repositories.extend(store.tags.keys().cloned());
+        repositories.sort();
+        repositories.dedup();
+        Ok(repositories)
+    }
+
+    fn tags(&self, name: &str) -> Result<Vec<String>, ApiError> {
+        let store = lock(&self.store);
+        Ok(store
+            .tags
+            .get(name)
+            .map(|x| x.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn manifest(&self, name: &str, reference: &str) -> Result<Option<StoredManifest>, ApiError> {
+        let store = lock(&self.store);
+        Ok(store.resolve(name, reference).and_then(|digest| {
+            let (data, media_type) = store.manifests.get(&digest)?;
+            Some(StoredManifest {
+                data: data.clone(),
+                media_type: media_type.clone(),
+                digest,
+            })
+        }))
+    }
+
+    fn blob(&self, name: &str, digest: &str) -> Result<Option<Blob>, ApiError> {
+        let store = lock(&self.store);
+        if !store.repo_has_blob(name, digest) {
+            return Ok(None);
+        }
+        Ok(store.blobs.get(digest).cloned().map(Blob::Data))
+    }
+
+    fn mount_blob(&self, name: &str, digest: &str, from: Option<&str>) -> Result<bool, ApiError> {
+        let mut store = lock(&self.store);
+        if !from.is_some_and(|from| store.repo_has_blob(from, digest)) {
+            return Ok(false);
+        }
+        store.add_blob(name, digest);
+        Ok(true)
+    }
+
+    fn upload_file(&self) -> Result<NamedTempFile, ApiError> {
+        Ok(NamedTempFile::new().map_err(anyhow::Error::from)?)
+    }
+
+    fn save_blob(&self, name: &str, digest: &str, file: NamedTempFile) -> Result<(), ApiError> {
+        let data = std::fs::read(file.path()).map_err(anyhow::Error::from)?;
+        let mut store = lock(&self.store);
+        store.blobs.insert(digest.to_string(), data.into());
+        store.add_blob(name, digest);
+        Ok(())
+    }
+
+    fn put_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+        digest: &str,
+        data: &[u8],
+    ) -> Result<Option<String>, ApiError> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Refs {
+            media_type: Option<String>,
+            artifact_type: Option<String>,
+            subject: Option<OciDescriptor>,
+            annotations: Option<HashMap<String, String>>,
+            config: Option<OciDescriptor>,
+            #[serde(default)]
+            layers: Vec<OciDescriptor>,
+            #[serde(default)]
+            manifests: Vec<ImageIndexEntry>,
+        }
+        let refs: Refs =
+            serde_json::from_slice(data).map_err(|e| manifest_invalid(anyhow::Error::from(e)))?;
+        let mut store = lock(&self.store);
+        for blob in refs.config.iter().chain(refs.layers.iter()) {
+            if !store.repo_has_blob(name, &blob.digest) {
+                return Err(blob_unknown(&blob.digest));
+            }
+        }
+        for entry in refs.manifests.iter() {
+            if !store.manifests.contains_key(&entry.digest) {
+                return Err(blob_unknown(&entry.digest));
+            }
+        }
+        let media_type = refs.media_type.unwrap_or(OCI_IMAGE_MEDIA_TYPE.to_string());
+        let subject = refs.subject.filter(|_| self.referrers).map(|subject| {
+            let list = store
+                .referrers
+                .entry((name.to_string(), subject.digest.clone()))
+                .or_default();
+            if !list.iter().any(|x| x.digest == digest) {
+                list.push(Referrer {
+                    media_type: media_type.clone(),
+                    digest: digest.to_string(),
+                    size: data.len() as i64,
+                    artifact_type: refs
+                        .artifact_type
+                        .or(refs.config.as_ref().map(|x| x.media_type.clone())),
+                    annotations: refs.annotations,
+                });
+            }
+            subject.digest
+        });
+        store
+            .manifests
+            .insert(digest.to_string(), (data.to_vec(), media_type));
+        if !reference.contains(':') {
+            store
+                .tags
+                .entry(name.to_string())
+                .or_default()
+                .insert(reference.to_string(), digest.to_string());
+        }
+        Ok(subject)
+    }
+
+    fn delete_manifest(&self, name: &str, digest: &str) -> Result<(), ApiError> {
+        if !self.allow_delete {
+            return Err(ApiError::new(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "UNSUPPORTED",
+                "不支持删除",
+            ));
+        }
+        let mut store = lock(&self.store);
+        if !digest.contains(':') || store.manifests.remove(digest).is_none() {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "MANIFEST_UNKNOWN",
+                digest.to_string(),
+            ));
+        }
+        if let Some(tags) = store.tags.get_mut(name) {
+            tags.retain(|_, x| x != digest);
+        }
+        Ok(())
+    }
+
+    fn referrers(&self, name: &str, digest: &str) -> Result<Option<Vec<Referrer>>, ApiError> {
+        if !self.referrers {
+            return Ok(None);
+        }
+        let store = lock(&self.store);
+        Ok(Some(
+            store
+                .referrers
+                .get(&(name.to_string(), digest.to_string()))
+                .cloned()
+                .unwrap_or_default(),
+        ))
+    }
+}
@@ -0,0 +1,244 @@
+use crate::distribution::client::RegistryClient;
+use crate::distribution::session::RegistrySession;
+use crate::filesystem::FileSystem;
+use crate::image::index::{host_platform, platform_string, select_manifest};
+use crate::image::manifest::Manifest;
+use crate::image::Repositories;
+use crate::util::DigestPre;
+use anyhow::{anyhow, bail, Result};
+use log::debug;
+use oci_distribution::manifest::OciDescriptor;
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+
+/// 镜像更新检查结果
+#[derive(Debug)]
+pub struct UpdateCheck {
+    /// images.json中记录的digest（带`sha256:`前缀），本地没有该镜像时为None
+    pub local_digest: Option<String>,
+    /// registry上的manifest digest（带`sha256:`前缀）
+    pub remote_digest: String,
+    /// 是否有更新
+    pub update_available: bool,
+    /// 更新需要下载的layer（本地已存在的blob不计入）
+    pub missing_layers: Vec<OciDescriptor>,
+    /// 需要下载的layer总大小
+    pub download_size: i64,
+}
+
+///
+/// 检查镜像是否有更新：请求registry上的manifest digest并与images.json中的记录比较。
+/// 有更新时再拉取manifest计算需要下载的layer；不写入本地存储
+pub async fn check_update(image: &Reference, auth: &RegistryAuth) -> Result<UpdateCheck> {
+    let session = RegistrySession::shared(auth)?;
+    let client = session.client();
+    check_update_with(client, image).await
+}
+
+/// 使用指定的registry客户端检查镜像更新
+pub async fn check_update_with(client: &RegistryClient, image: &Reference) -> Result<UpdateCheck> {
+    let repo = Repositories::init()?;
+    let local_digest = repo.image_digest(image);
+    let remote_digest = client.fetch_manifest_digest(image).await?;
+    debug!(
+        "检查镜像更新{:?}: 本地={:?} 远端={}",
+        image, local_digest, remote_digest
+    );
+    if local_digest.as_ref() == Some(&remote_digest) {
+        return Ok(UpdateCheck {
+            local_digest,
+            remote_digest,
+            update_available: false,
+            missing_layers: Vec::new(),
+            download_size: 0,
+        });
+    }
+
+    let remote = Manifest::new(client.pull_manifest_raw(image).await?.data);
+    let manifest = if remote.is_index() {
+        // 与pull一致：按记录的平台（默认本机平台）选择manifest
+        let platform = repo
+            .image_platform(image)
+            .cloned()
+            .unwrap_or_else(host_platform);
+        let index = remote.to_oci_index()?;
+        let entry = select_manifest(&index, &platform).ok_or(anyhow!(
+            "镜像索引{:?}中没有平台{}的manifest",
+            image,
+            platform_string(&platform)
+        ))?;
+        let reference = Reference::with_digest(
+            image.registry().to_string(),
+            image.repository().to_string(),
+            entry.digest.clone(),
+        );
+        let manifest = Manifest::new(client.pull_manifest_raw(&reference).await?.data);
+        if manifest.is_index() {
+            bail!(
+                "镜像索引{}中的条目{}仍为镜像索引",
+                remote_digest,
+                entry.digest
+            )
+        }
+        manifest.to_oci_manifest()?
+    } else {
+        remote.to_oci_manifest()?
+    };
+
+    let mut missing_layers = Vec::new();
+    for layer in manifest.layers.into_iter() {
+        if !FileSystem.exist_layer(&layer.digest.get_digest()?)? {
+            missing_layers.push(layer);
+        }
+    }
+    let download_size = missing_layers.iter().map(|x| x.size).sum();
+    Ok(UpdateCheck {
+        local_digest,
+        remote_digest,
+        update_available: true,
+        missing_layers,
+        download_size,
+    })
+}
+
+#[cfg(all(test, feature = "test-registry"))]
+mod test {
+    use super::check_update_with;
+    use crate::args::BuildArgs;
+    use crate::distribution::pull::pull_with;
+    use crate::distribution::push::{push_with, PushOptions};
+    use crate::distribution::test_registry::{TestRegistry, TestRegistryOptions};
+    use crate::filesystem::{test_home, FileSystem};
+    use crate::image::build::build;
+    use crate::image::build::config::instructions::{Copy, Dest, Kind};
+    use crate::image::build::config::BuildConfig;
+    use crate::image::index::host_platform;
+    use crate::image::Repositories;
+    use crate::util::cancel::Cancel;
+    use crate::util::DigestPre;
+    use oci_distribution::manifest::{
+        IMAGE_LAYER_GZIP_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE,
+    };
+    use oci_distribution::secrets::RegistryAuth;
+    use oci_distribution::Reference;
+
+    #[tokio::test]
+    async fn test_check_update() {
+        test_home();
+        let src = tempfile::tempdir().unwrap();
+        let app = src.path().join("app");
+        std::fs::write(&app, "update").unwrap();
+        let image: Reference = "localhost/test/update-src:1".parse().unwrap();
+        let args = BuildArgs {
+            config: BuildConfig {
+                kind: Kind::App,
+                copys: vec![Copy(app, "/bin/app".to_string().try_into().unwrap())],
+                cmd: Dest::try_from("/bin/app".to_string()).unwrap(),
+                reproducible: false,
+                squash: false,
+                platforms: Vec::new(),
+                wasm_artifact: false,
+            },
+            image: image.clone(),
+        };
+        build(&args).await.unwrap();
+        let registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let client = registry.client(RegistryAuth::Anonymous).unwrap();
+        let target = registry.reference("test/update:1");
+        let report = push_with(
+            &client,
+            &image,
+            &target,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+        .unwrap();
+        let v1 = report.manifest_digest;
+        pull_with(&client, &target, &host_platform(), &Cancel::new())
+            .await
+            .unwrap();
+
+        // 无更新
+        let check = check_update_with(&client, &target).await.unwrap();
+        assert!(!check.update_available);
+        assert_eq!(check.local_digest.as_ref(), Some(&v1));
+        assert!(check.missing_layers.is_empty());
+
+        // 新版本在原有layer之上追加一个本地没有的layer
+        let layer = format!("layer-{}", registry.host()).into_bytes();
+        let layer_digest = sha256::digest(layer.as_slice()).sha256_pre();
+        client
+            .upload_blob(&target, &layer_digest, &layer)
+            .await
+            .unwrap();
+        let mut manifest: serde_json::Value =
+            serde_json::from_slice(&registry.manifest("test/update", "1").unwrap()).unwrap();
+        manifest["layers"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::json!({
+                "mediaType": IMAGE_LAYER_GZIP_MEDIA_TYPE,
+                "digest": layer_digest,
+                "size": layer.len(),
+            }));
+        let v2_data = serde_json::to_vec(&manifest).unwrap();
+        let v2 = sha256::digest(v2_data.as_slice()).sha256_pre();
+        client
+            .push_manifest_raw(&target, &v2_data, OCI_IMAGE_MEDIA_TYPE)
+            .await
+            .unwrap();
+        let check = check_update_with(&client, &target).await.unwrap();
+        assert!(check.update_available);
+        assert_eq!(check.remote_digest, v2);
+        assert_eq!(check.missing_layers.len(), 1);
+        assert_eq!(check.missing_layers[0].digest, layer_digest);
+        assert_eq!(check.download_size, layer.len() as i64);
+
+        // 镜像索引按images.json记录的平台选择manifest
+        let mut other = host_platform();
+        other.architecture = "other".to_string();
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_IMAGE_INDEX_MEDIA_TYPE,
+            "manifests": [
+                {"mediaType": OCI_IMAGE_MEDIA_TYPE, "digest": v1, "size": 1, "platform": host_platform()},
+                {"mediaType": OCI_IMAGE_MEDIA_TYPE, "digest": v2, "size": v2_data.len(), "platform": other},
+            ]
+        });
+        let indexed = registry.reference("test/update:index");
+        client
+            .push_manifest_raw(
+                &indexed,
+                &serde_json::to_vec(&index).unwrap(),
+                OCI_IMAGE_INDEX_MEDIA_TYPE,
+            )
+            .await
+            .unwrap();
+        Repositories::modify(|repo| {
+            repo.update(&indexed, v1.clone());
+            repo.update_platform(&indexed, other);
+        })
+        .unwrap();
+        let check = check_update_with(&client, &indexed).await.unwrap();
+        assert!(check.update_available);
+        assert_eq!(check.missing_layers.len(), 1);
+        assert_eq!(check.missing_layers[0].digest, layer_digest);
+
+        // 检查更新不写入本地存储
+        let repo = Repositories::init().unwrap();
+        assert_eq!(repo.image_digest(&target), Some(v1.clone()));
+        assert_eq!(repo.image_digest(&indexed), Some(v1));
+        assert!(!FileSystem
+            .exist_layer(&layer_digest.get_digest().unwrap())
+            .unwrap());
+        assert!(!FileSystem
+            .manifest_sha256()
+            .unwrap()
+            .join(v2.get_digest().unwrap())
+            .exists());
+        registry.shutdown().await.unwrap();
+    }
+}
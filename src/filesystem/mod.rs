@@ -1,5 +1,6 @@
 use anyhow::{anyhow, Result};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 pub mod snapshot;
 
@@ -10,13 +11,13 @@ pub struct FileSystem;
 ///       ├──imagedb
 ///       │  ├──images.json
 ///       │  ├──sha256
-///       │  │  ├──image的manifest文件；文件名为文件的sha256摘要
+///       │  │  ├──image的manifest文件或镜像索引（image index）；文件名为文件的sha256摘要
 ///       ├──configdb
 ///       │  ├──sha256
 ///       │  │  ├──image的config文件；文件名为文件的sha256摘要
 ///       ├──layerdb
-///       │  ├──contents?（待实现）
-///       │  │  │  layer的描述文件（Descriptor）；文件名为文件的sha256摘要
+///       │  ├──contents
+///       │  │  ├──layer的元数据（diff_id、blob digest、媒体类型、大小）；文件名为layer的chain ID
 ///       │  ├──blobs
 ///       │  │  ├──sha256
 ///       │  │  │  ├──image的layer文件
@@ -33,7 +34,7 @@ impl FileSystem {
     /// | Windows | `{FOLDERID_Profile}` | C:\Users\Alice |
     pub fn home(&self) -> Result<PathBuf> {
         let path = dirs::home_dir()
-            .and_then(|path| Some(path.join(".hpmq")))
+            .map(|path| path.join(".hpmq"))
             .ok_or(anyhow!("找不到HOME路径"))?;
         std::fs::create_dir_all(&path)?;
         Ok(path)
@@ -74,6 +75,10 @@ impl FileSystem {
         Ok(path)
     }
 
+    pub fn exist_manifest(&self, sha256_digest: &String) -> Result<bool> {
+        let manifest_path = self.manifest_sha256()?;
+        Ok(manifest_path.join(sha256_digest).exists())
+    }
     pub fn exist_config(&self, sha256_digest: &String) -> Result<bool> {
         let config_path = self.config_sha256()?;
         Ok(config_path.join(sha256_digest).exists())
@@ -89,14 +94,35 @@ impl FileSystem {
 
     pub fn save_config(&self, sha256_digest: &String, data: &[u8]) -> Result<()> {
         let config_path = self.config_sha256()?;
-        Ok(std::fs::write(config_path.join(sha256_digest), data)?)
+        write_atomic(&config_path.join(sha256_digest), data)
     }
     pub fn save_manifest(&self, sha256_digest: &String, data: &[u8]) -> Result<()> {
         let manifest_path = self.manifest_sha256()?;
-        Ok(std::fs::write(manifest_path.join(sha256_digest), data)?)
+        write_atomic(&manifest_path.join(sha256_digest), data)
     }
     pub fn save_layer(&self, sha256_digest: &String, data: &[u8]) -> Result<()> {
         let config_path = self.layer_blobs()?;
-        Ok(std::fs::write(config_path.join(sha256_digest), data)?)
+        write_atomic(&config_path.join(sha256_digest), data)
     }
 }
+
+///
+/// 先写入同目录下的临时文件再重命名，并发读取的一方不会读到写了一半的文件。
+/// 临时文件名不是digest，不会被gc当作blob处理
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = path.parent().ok_or(anyhow!("路径{:?}没有上级目录", path))?;
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    tmp.write_all(data)?;
+    tmp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// 测试使用独立的HOME目录，避免污染本机的镜像仓库
+#[cfg(test)]
+pub(crate) fn test_home() {
+    static HOME: std::sync::Once = std::sync::Once::new();
+    HOME.call_once(|| {
+        let home = tempfile::tempdir().expect("无法创建临时文件夹").keep();
+        std::env::set_var("HOME", home);
+    });
+}
@@ -2,17 +2,19 @@ mod blob_writer;
 
 use crate::filesystem::snapshot::blob_writer::BlobWriter;
 use crate::image::build::config::instructions::Dest;
-use crate::util::copy_dir;
+use crate::util::cancel::Cancel;
+use crate::util::{copy_dir, copy_metadata};
 use anyhow::{anyhow, bail, Context, Result};
 use jwalk::WalkDirGeneric;
 use log::{debug, error};
-use oci_spec::image::{Descriptor, DescriptorBuilder, MediaType};
+use oci_spec::image::{Descriptor, MediaType};
 
-use chrono::Utc;
+use chrono::{DateTime, TimeZone, Utc};
+use filetime::FileTime;
 use seahash::SeaHasher;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsString;
 use std::hash::Hasher;
 use std::path::{Path, PathBuf};
@@ -34,16 +36,20 @@ impl Snapshot {
         Ok(Self { path, dest_dir })
     }
     pub fn new() -> Result<Self> {
-        let path = tempdir().context("无法创建临时文件夹")?.into_path();
+        let path = tempdir().context("无法创建临时文件夹")?.keep();
         debug!("Snapshot {:?}", path);
         Self::init(path)
     }
     pub async fn init_by_self(&self) -> Result<Self> {
-        let path = tempdir().context("无法创建临时文件夹")?.into_path();
-        // let dir = std::fs::read_dir(&self.path)?;
-        debug!("Snapshot {:?}", path);
-        copy_dir(self.path.clone(), path.clone()).await?;
-        Self::init(path)
+        let snapshot = Self::new()?;
+        self.copy_to(&snapshot, &Cancel::new()).await?;
+        Ok(snapshot)
+    }
+    ///
+    /// 将自身内容复制到`target`。取消时返回[`crate::util::cancel::Cancelled`]错误，
+    /// 已复制的部分留在`target`中，由调用方删除
+    pub async fn copy_to(&self, target: &Snapshot, cancel: &Cancel) -> Result<()> {
+        copy_dir(self.path.clone(), target.path.clone(), cancel).await
     }
     pub fn copy_in(&self, src: impl Into<PathBuf>, dst: &Dest) -> Result<()> {
         let src_path = src.into();
@@ -63,6 +69,8 @@ impl Snapshot {
         }
         debug!("{:?} -> {:?}", src_path, dst_path);
         std::fs::copy(&src_path, &dst_path).context("copy_in报错")?;
+        // 保留源文件的修改时间等元数据，使重复复制相同文件不产生变更
+        copy_metadata(&src_path, &dst_path).context("copy_in同步元数据报错")?;
         Ok(())
     }
     pub fn file_exist(&self, file: impl Into<PathBuf>) -> bool {
@@ -78,13 +86,11 @@ impl Snapshot {
             .skip_hidden(false)
             .process_read_dir(|_depth, _path, _read_dir_state, children| {
                 children.iter_mut().flatten().for_each(|dir_entry| {
-                    if !dir_entry.file_type.is_dir() {
-                        dir_entry.client_state = SnapshotEntry::new(
-                            &dir_entry.path(),
-                            &dir_entry.file_type(),
-                            dir_entry.metadata().ok(),
-                        );
-                    }
+                    dir_entry.client_state = SnapshotEntry::new(
+                        &dir_entry.path(),
+                        &dir_entry.file_type(),
+                        dir_entry.metadata().ok(),
+                    );
                 })
             })
             .into_iter()
@@ -95,7 +101,7 @@ impl Snapshot {
                     let relative_path = path
                         .strip_prefix(&source_dir)
                         .expect("Should always be able to strip the root dir");
-                    match relative_path == PathBuf::from("") {
+                    match relative_path == "" {
                         true => None, // This is the entry for the dir itself so ignore it
                         false => Some((
                             relative_path.to_string_lossy().to_string(), // Should be lossless on Linux (and MacOS)
@@ -112,10 +118,15 @@ impl Snapshot {
     }
 
     /// Create a set of changes by determining the difference between two snapshots
+    ///
+    /// Entries are compared on their full metadata (file type, mode, ownership, mtime and
+    /// extended attributes) as well as their content fingerprint or symlink target.
     pub fn diff(&self, new_other: &Snapshot) -> ChangeSet {
+        let old_entries = self.entries();
+        let new_entries = new_other.entries();
         let mut changes = Vec::new();
-        for (path, entry) in self.entries().iter() {
-            match new_other.entries().get(path) {
+        for (path, entry) in old_entries.iter() {
+            match new_entries.get(path) {
                 Some(other_entry) => {
                     if entry != other_entry {
                         changes.push(Change::Modified(path.into()))
@@ -124,8 +135,8 @@ impl Snapshot {
                 None => changes.push(Change::Removed(path.into())),
             }
         }
-        for path in new_other.entries().keys() {
-            if !self.entries().contains_key(path) {
+        for path in new_entries.keys() {
+            if !old_entries.contains_key(path) {
                 changes.push(Change::Added(path.into()))
             }
         }
@@ -171,11 +182,172 @@ pub enum Change {
     Removed(String),
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+impl Change {
+    /// The path, relative to the snapshot root, that the change applies to
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Added(path) | Change::Modified(path) | Change::Removed(path) => path,
+        }
+    }
+}
+
+/// Options controlling how a [`ChangeSet`] is written as a layer
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LayerOptions {
+    /// Normalise the layer for reproducible builds using this timestamp (seconds since the Unix epoch)
+    ///
+    /// When set, every entry's mtime is set to this timestamp, ownership is reset to `0:0`,
+    /// extended attributes (which often carry host specific labels) are dropped, and the
+    /// `io.stencila.layer.created` annotation uses this timestamp instead of the current time.
+    pub source_date_epoch: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SnapshotEntryMetadata {
+    /// The type of the entry
+    file_type: SnapshotFileType,
+
+    /// The owner's user id
     uid: u32,
+
+    /// The owner's group id
     gid: u32,
-    readonly: bool,
+
+    /// The permission bits (including setuid, setgid and sticky bits)
+    mode: u32,
+
+    /// The modification time, seconds since the Unix epoch
+    ///
+    /// Only whole seconds are kept because that is all a tar header records; comparing the
+    /// sub-second part would report every unpacked-then-rescanned entry as modified.
+    mtime: i64,
+
+    /// Extended attributes, ordered by name
+    xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SnapshotFileType {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+impl From<&fs::FileType> for SnapshotFileType {
+    fn from(file_type: &fs::FileType) -> Self {
+        if file_type.is_symlink() {
+            Self::Symlink
+        } else if file_type.is_dir() {
+            Self::Directory
+        } else if file_type.is_file() {
+            Self::File
+        } else {
+            Self::Other
+        }
+    }
+}
+
+impl SnapshotEntryMetadata {
+    /// Read the metadata of the entry at `path` without following symlinks
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let metadata = fs::symlink_metadata(path)?;
+        Ok(Self::new(path, &metadata))
+    }
+
+    /// Create the metadata of the entry at `path` from its filesystem metadata
+    fn new(path: &Path, metadata: &fs::Metadata) -> Self {
+        let file_type = SnapshotFileType::from(&metadata.file_type());
+
+        #[cfg(target_family = "unix")]
+        let (uid, gid, mode) = {
+            use std::os::unix::prelude::MetadataExt;
+            (metadata.uid(), metadata.gid(), metadata.mode() & 0o7777)
+        };
+
+        #[cfg(not(target_family = "unix"))]
+        let (uid, gid, mode) = {
+            let mode = match (file_type, metadata.permissions().readonly()) {
+                (SnapshotFileType::Directory, _) => 0o755,
+                (_, true) => 0o444,
+                (_, false) => 0o644,
+            };
+            (1000u32, 1000u32, mode)
+        };
+
+        let mtime = FileTime::from_last_modification_time(metadata);
+
+        Self {
+            file_type,
+            uid,
+            gid,
+            mode,
+            mtime: mtime.unix_seconds(),
+            xattrs: Self::read_xattrs(path),
+        }
+    }
+
+    #[cfg(target_family = "unix")]
+    fn read_xattrs(path: &Path) -> BTreeMap<String, Vec<u8>> {
+        let mut xattrs = BTreeMap::new();
+        match xattr::list(path) {
+            Ok(names) => {
+                for name in names {
+                    match xattr::get(path, &name) {
+                        Ok(Some(value)) => {
+                            xattrs.insert(name.to_string_lossy().to_string(), value);
+                        }
+                        Ok(None) => {}
+                        Err(error) => debug!(
+                            "While reading xattr `{:?}` of `{}`: {}",
+                            name,
+                            path.display(),
+                            error
+                        ),
+                    }
+                }
+            }
+            Err(error) => debug!("While listing xattrs of `{}`: {}", path.display(), error),
+        }
+        xattrs
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    fn read_xattrs(_path: &Path) -> BTreeMap<String, Vec<u8>> {
+        BTreeMap::new()
+    }
+
+    pub fn file_type(&self) -> SnapshotFileType {
+        self.file_type
+    }
+
+    /// Strip the host specific parts of the metadata for a reproducible layer
+    fn normalize(&mut self, source_date_epoch: i64) {
+        self.uid = 0;
+        self.gid = 0;
+        self.mtime = source_date_epoch;
+        self.xattrs.clear();
+    }
+
+    /// Create a tar header carrying this metadata
+    ///
+    /// The entry type and size are set from the metadata; the path and checksum are left
+    /// to the archive builder.
+    fn tar_header(&self, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(match self.file_type {
+            SnapshotFileType::File => tar::EntryType::Regular,
+            SnapshotFileType::Directory => tar::EntryType::Directory,
+            SnapshotFileType::Symlink => tar::EntryType::Symlink,
+            SnapshotFileType::Other => tar::EntryType::Regular,
+        });
+        header.set_mode(self.mode);
+        header.set_uid(self.uid.into());
+        header.set_gid(self.gid.into());
+        header.set_mtime(self.mtime.max(0) as u64);
+        header.set_size(size);
+        header
+    }
 }
 // impl From<Snapshot> for oci_images_util::snapshot::Snapshot {
 //     fn from(val: Snapshot) -> Self {
@@ -186,22 +358,7 @@ pub struct SnapshotEntryMetadata {
 impl SnapshotEntry {
     /// Create a new snapshot entry
     fn new(path: &Path, file_type: &fs::FileType, metadata: Option<fs::Metadata>) -> Self {
-        let metadata = metadata.map(|metadata| {
-            #[cfg(target_family = "unix")]
-            let (uid, gid) = {
-                use std::os::unix::prelude::MetadataExt;
-                (metadata.uid(), metadata.gid())
-            };
-
-            #[cfg(not(target_family = "unix"))]
-            let (uid, gid) = (1000u32, 1000u32);
-
-            SnapshotEntryMetadata {
-                uid,
-                gid,
-                readonly: metadata.permissions().readonly(),
-            }
-        });
+        let metadata = metadata.map(|metadata| SnapshotEntryMetadata::new(path, &metadata));
 
         let fingerprint = if file_type.is_file() {
             match Self::file_fingerprint::<SeaHasher>(path) {
@@ -284,6 +441,77 @@ impl ChangeSet {
         }
     }
 
+    /// Append a file, directory or symlink to the archive
+    ///
+    /// The tar header is built from the entry's [`SnapshotEntryMetadata`] so that the mode bits,
+    /// ownership and mtime are preserved. Extended attributes are written as a preceding PAX
+    /// extended header using the `SCHILY.xattr.` keys.
+    fn append_entry<W: io::Write>(
+        archive: &mut tar::Builder<W>,
+        source_path: &Path,
+        dest_path: &Path,
+        options: &LayerOptions,
+    ) -> io::Result<()> {
+        let mut metadata = SnapshotEntryMetadata::read(source_path)?;
+        if let Some(source_date_epoch) = options.source_date_epoch {
+            metadata.normalize(source_date_epoch);
+        }
+        if !metadata.xattrs.is_empty() {
+            Self::append_xattrs(archive, &metadata.xattrs)?;
+        }
+        match metadata.file_type {
+            SnapshotFileType::File => {
+                let file = fs::File::open(source_path)?;
+                let mut header = metadata.tar_header(file.metadata()?.len());
+                archive.append_data(&mut header, dest_path, file)
+            }
+            SnapshotFileType::Directory => {
+                let mut header = metadata.tar_header(0);
+                archive.append_data(&mut header, dest_path, io::empty())
+            }
+            SnapshotFileType::Symlink => {
+                let target = fs::read_link(source_path)?;
+                let mut header = metadata.tar_header(0);
+                archive.append_link(&mut header, dest_path, target)
+            }
+            SnapshotFileType::Other if options.source_date_epoch.is_some() => {
+                // Device files and fifos cannot be normalised through `append_path_with_name`
+                debug!("Skipping special file `{}`", source_path.display());
+                Ok(())
+            }
+            SnapshotFileType::Other => archive.append_path_with_name(source_path, dest_path),
+        }
+    }
+
+    /// Append a PAX extended header carrying the extended attributes of the next entry
+    fn append_xattrs<W: io::Write>(
+        archive: &mut tar::Builder<W>,
+        xattrs: &BTreeMap<String, Vec<u8>>,
+    ) -> io::Result<()> {
+        let mut data = Vec::new();
+        for (name, value) in xattrs {
+            let key = format!("SCHILY.xattr.{}", name);
+            // Each record is `<length> <key>=<value>\n` where the length includes its own digits
+            let rest = key.len() + value.len() + 3;
+            let mut len = rest + 1;
+            while len != rest + len.to_string().len() {
+                len = rest + len.to_string().len();
+            }
+            data.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+            data.extend_from_slice(value);
+            data.push(b'\n');
+        }
+
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::XHeader);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        // Readers only use the records; the name of the PAX header entry itself is informational
+        header.set_path("PaxHeaders.0/xattrs")?;
+        header.set_cksum();
+        archive.append(&header, data.as_slice())
+    }
+
     /// Creates an OCI layer for the set of changes
     ///
     /// This implements the [Representing Changes](https://github.com/opencontainers/image-spec/blob/main/layer.md#representing-changes)
@@ -300,20 +528,17 @@ impl ChangeSet {
     /// # Arguments
     ///
     /// - `layout_dir`: the image directory to write the layer to (to the `blob/sha256` subdirectory)
+    /// - `options`: how entries are normalised, see [`LayerOptions`]
+    ///
+    /// Returns `None`, without writing anything, when there are no changes.
     pub fn write_layer<P: AsRef<Path>>(
         mut self,
         layout_dir: P,
         media_type: &MediaType,
-    ) -> Result<(String, Descriptor)> {
+        options: &LayerOptions,
+    ) -> Result<Option<(String, Descriptor)>> {
         if self.items.is_empty() {
-            return Ok((
-                "<empty>".to_string(),
-                DescriptorBuilder::default()
-                    .media_type(media_type.clone())
-                    .digest("<none>")
-                    .size(0)
-                    .build()?,
-            ));
+            return Ok(None);
         }
 
         log::info!(
@@ -367,7 +592,7 @@ impl ChangeSet {
                 // MediaType::ImageLayerZstd => LayerEncoder::Zstd(
                 //     zstd::stream::Encoder::new(&mut blob_writer, 4)?.auto_finish(),
                 // ),
-                _ => bail!("Unhandled media type for layer: {}", media_type.to_string()),
+                _ => bail!("Unhandled media type for layer: {}", media_type),
             };
 
             let mut layer_writer = LayerWriter {
@@ -376,7 +601,8 @@ impl ChangeSet {
             };
 
             let mut archive = tar::Builder::new(&mut layer_writer);
-            archive.mode(HeaderMode::Deterministic);
+            archive.mode(HeaderMode::Complete);
+            archive.follow_symlinks(false);
 
             // Add an entry for the `dest_dir` (and any of its parent) so that ownership (and other
             // metadata) of `source_dir` is maintained. If not done then there are issues with non-root
@@ -387,7 +613,10 @@ impl ChangeSet {
                 archive.append_path_with_name(&self.source_dir, &path)?;
             }
 
-            self.items.sort();
+            // Order entries by path so that parents precede their children and the archive
+            // does not depend on the order in which changes were detected
+            self.items
+                .sort_by(|a, b| a.path().cmp(b.path()).then_with(|| a.cmp(b)));
             debug!("changes: {:?}", self.items);
             // Add each change
             for change in self.items {
@@ -396,34 +625,8 @@ impl ChangeSet {
                         let source_path = self.source_dir.join(path);
                         let dest_path = self.dest_dir.join(path);
 
-                        let result = if source_path.is_symlink() {
-                            match fs::read_link(&source_path).and_then(|target| {
-                                fs::metadata(&source_path).map(|metadata| (target, metadata))
-                            }) {
-                                Ok((target, _metadata)) => {
-                                    #[cfg(target_family = "unix")]
-                                    let (uid, gid) = {
-                                        use std::os::unix::prelude::MetadataExt;
-                                        (_metadata.uid(), _metadata.gid())
-                                    };
-
-                                    #[cfg(not(target_family = "unix"))]
-                                    let (uid, gid) = (1000u32, 1000u32);
-
-                                    let mut header = tar::Header::new_gnu();
-                                    header.set_uid(uid.into());
-                                    header.set_gid(gid.into());
-                                    header.set_entry_type(tar::EntryType::Symlink);
-                                    header.set_size(0);
-                                    archive.append_link(&mut header, dest_path, target)
-                                }
-                                Err(error) => Err(error),
-                            }
-                        } else {
-                            debug!("source_path={:?} dest_path={:?}", source_path, dest_path);
-
-                            archive.append_path_with_name(source_path, dest_path)
-                        };
+                        let result =
+                            Self::append_entry(&mut archive, &source_path, &dest_path, options);
 
                         if let Err(error) = result {
                             debug!(
@@ -453,6 +656,7 @@ impl ChangeSet {
 
                         let mut header = tar::Header::new_gnu();
                         header.set_path(path_buf)?;
+                        header.set_mtime(options.source_date_epoch.unwrap_or(0).max(0) as u64);
                         header.set_size(0);
                         header.set_cksum();
                         let data: &[u8] = &[];
@@ -474,7 +678,7 @@ impl ChangeSet {
 
         let mut annotations: HashMap<String, String> = [
             ("io.stencila.version", env!("CARGO_PKG_VERSION").to_string()),
-            ("io.stencila.layer.created", Utc::now().to_rfc3339()),
+            ("io.stencila.layer.created", created(options).to_rfc3339()),
             (
                 "io.stencila.layer.directory",
                 self.dest_dir.to_string_lossy().to_string(),
@@ -484,6 +688,12 @@ impl ChangeSet {
         .map(|(name, value)| (name.to_string(), value))
         .into();
 
+        fn created(options: &LayerOptions) -> DateTime<Utc> {
+            options
+                .source_date_epoch
+                .and_then(|epoch| Utc.timestamp_opt(epoch, 0).single())
+                .unwrap_or_else(Utc::now)
+        }
         fn first_100(vec: Vec<String>) -> String {
             vec[..(std::cmp::min(vec.len(), 100))].join(":")
         }
@@ -508,13 +718,65 @@ impl ChangeSet {
 
         let descriptor = blob_writer.finish(Some(annotations))?;
 
-        Ok((diff_id, descriptor))
+        Ok(Some((diff_id, descriptor)))
     }
 }
 #[cfg(test)]
 mod test {
+    use super::{Change, LayerOptions, Snapshot};
+    use oci_spec::image::MediaType;
     use std::path::PathBuf;
 
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn test_diff_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let base = Snapshot::new().unwrap();
+        std::fs::create_dir(base.path.join("bin")).unwrap();
+        std::fs::write(base.path.join("bin/app"), "app").unwrap();
+        let next = base.init_by_self().await.unwrap();
+        assert!(base.diff(&next).items.is_empty());
+
+        let app = next.path.join("bin/app");
+        std::fs::set_permissions(&app, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(
+            base.diff(&next).items,
+            vec![Change::Modified("bin/app".to_string())]
+        );
+
+        let layout = tempfile::tempdir().unwrap();
+        let (_diff_id, descriptor) = base
+            .diff(&next)
+            .write_layer(
+                layout.path(),
+                &MediaType::ImageLayer,
+                &LayerOptions::default(),
+            )
+            .unwrap()
+            .unwrap();
+        let blob = layout
+            .path()
+            .join("blobs/sha256")
+            .join(descriptor.digest().trim_start_matches("sha256:"));
+        let mut archive = tar::Archive::new(std::fs::File::open(blob).unwrap());
+        let entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap().to_str(), Some("bin/app"));
+        assert_eq!(entry.header().mode().unwrap(), 0o755);
+        assert_eq!(
+            entry.header().mtime().unwrap() as i64,
+            filetime::FileTime::from_last_modification_time(&app.metadata().unwrap())
+                .unix_seconds()
+        );
+
+        let last = next.init_by_self().await.unwrap();
+        filetime::set_file_mtime(last.path.join("bin"), filetime::FileTime::zero()).unwrap();
+        assert_eq!(
+            next.diff(&last).items,
+            vec![Change::Modified("bin".to_string())]
+        );
+    }
+
     #[test]
     fn test_is_regex() {
         let valid_ident = regex::Regex::new(r"(.*)/([^/]*)$").unwrap();
@@ -548,6 +810,7 @@ mod test {
         }
     }
     #[test]
+    #[allow(clippy::join_absolute_paths)]
     fn test_is_dir() {
         let path: PathBuf = "C:\\Users\\DELL\\AppData".into();
         println!("{:?}", path.is_dir());
@@ -8,9 +8,22 @@ pub enum Instruction {
 }
 #[derive(Clone, Debug)]
 pub struct Copy(pub PathBuf, pub Dest);
-#[derive(Clone, Serialize, Deserialize, Debug)]
+
+impl Copy {
+    /// 记录在镜像history中的构建指令（只保留源文件名，避免泄露构建机路径）
+    pub fn created_by(&self) -> String {
+        let file_name = self
+            .0
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+        format!("COPY {} {}", file_name, self.1.orgin)
+    }
+}
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub enum Kind {
     Wasi,
+    #[default]
     App,
 }
 #[derive(Debug, Clone)]
@@ -1,6 +1,7 @@
 use crate::image::build::config::instructions::{Copy, Dest, Kind};
-use anyhow::bail;
+use anyhow::{bail, Context};
 use log::warn;
+use oci_distribution::manifest::Platform;
 
 pub mod instructions;
 #[derive(Debug)]
@@ -8,6 +9,51 @@ pub struct BuildConfig {
     pub kind: Kind,
     pub copys: Vec<Copy>,
     pub cmd: Dest,
+    /// 可复现构建：时间戳取自环境变量SOURCE_DATE_EPOCH（未设置时为0），并抹去属主等构建机信息
+    pub reproducible: bool,
+    /// 将所有Copy步骤合并为单个layer
+    pub squash: bool,
+    /// 多平台构建：每个平台在公共Copy之后追加各自的Copy，生成一个manifest；非空时构建结果为镜像索引
+    pub platforms: Vec<PlatformCopys>,
+    /// 以wasm OCI artifact形式输出（仅Kind为Wasi）：config为`application/vnd.wasm.config.v1+json`，
+    /// 唯一的layer为CMD指定的模块文件，可与wasm-to-oci等工具互通
+    pub wasm_artifact: bool,
+}
+
+/// 某个平台专属的Copy
+#[derive(Debug, Clone)]
+pub struct PlatformCopys {
+    pub platform: Platform,
+    pub copys: Vec<Copy>,
+}
+
+impl BuildConfig {
+    /// 检查配置项之间的约束：wasm artifact只能由Kind为Wasi的单平台镜像构建
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.wasm_artifact {
+            if !matches!(self.kind, Kind::Wasi) {
+                bail!("只有Kind为Wasi的镜像可以构建为wasm artifact");
+            }
+            if !self.platforms.is_empty() {
+                bail!("wasm artifact不支持多平台构建");
+            }
+        }
+        Ok(())
+    }
+    /// 可复现构建所使用的时间戳（秒），非可复现构建返回None
+    pub fn source_date_epoch(&self) -> anyhow::Result<Option<i64>> {
+        if !self.reproducible {
+            return Ok(None);
+        }
+        match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(epoch) => {
+                Ok(Some(epoch.trim().parse().with_context(|| {
+                    format!("非法的SOURCE_DATE_EPOCH: {:?}", epoch)
+                })?))
+            }
+            Err(_) => Ok(Some(0)),
+        }
+    }
 }
 
 #[derive(Default)]
@@ -15,17 +61,27 @@ pub struct BuildConfigBuilder {
     pub kind: Option<Kind>,
     pub copys: Vec<Copy>,
     pub cmd: Option<Dest>,
+    pub reproducible: bool,
+    pub squash: bool,
+    pub platforms: Vec<PlatformCopys>,
+    pub wasm_artifact: bool,
 }
 
 impl BuildConfigBuilder {
     pub fn build(self) -> anyhow::Result<BuildConfig> {
         if let Some(cmd) = self.cmd {
             if let Some(kind) = self.kind {
-                Ok(BuildConfig {
+                let config = BuildConfig {
                     cmd,
                     kind,
                     copys: self.copys,
-                })
+                    reproducible: self.reproducible,
+                    squash: self.squash,
+                    platforms: self.platforms,
+                    wasm_artifact: self.wasm_artifact,
+                };
+                config.validate()?;
+                Ok(config)
             } else {
                 bail!("配置项KIND缺失");
             }
@@ -36,12 +92,30 @@ impl BuildConfigBuilder {
     pub fn append_copy(&mut self, copy: Copy) {
         self.copys.push(copy);
     }
+    pub fn append_platform_copy(&mut self, platform: Platform, copy: Copy) {
+        match self.platforms.iter_mut().find(|x| x.platform == platform) {
+            Some(platform_copys) => platform_copys.copys.push(copy),
+            None => self.platforms.push(PlatformCopys {
+                platform,
+                copys: vec![copy],
+            }),
+        }
+    }
     pub fn mut_cmd(&mut self, cmd: Dest) {
         if self.cmd.is_some() {
             warn!("Cmd重复配置！");
         }
         let _ = self.cmd.insert(cmd);
     }
+    pub fn mut_reproducible(&mut self, reproducible: bool) {
+        self.reproducible = reproducible;
+    }
+    pub fn mut_squash(&mut self, squash: bool) {
+        self.squash = squash;
+    }
+    pub fn mut_wasm_artifact(&mut self, wasm_artifact: bool) {
+        self.wasm_artifact = wasm_artifact;
+    }
     pub fn mut_kind(&mut self, kind: Kind) {
         if self.kind.is_some() {
             warn!("Kind重复配置！");
@@ -1,57 +1,202 @@
 pub mod config;
 
 use crate::args::BuildArgs;
-use crate::filesystem::snapshot::Snapshot;
+use crate::filesystem::snapshot::{ChangeSet, LayerOptions, Snapshot};
 use crate::filesystem::FileSystem;
-use crate::image::{config::ConfigFile, Repositories};
+use crate::image::build::config::instructions::Copy;
+use crate::image::build::config::BuildConfig;
+use crate::image::config::{ConfigFile, History};
+use crate::image::index::platform_string;
+use crate::image::layer::{chain_id, LayerMetadata};
+use crate::image::manifest::TITLE_ANNOTATION;
+use crate::image::wasm;
+use crate::image::Repositories;
+use crate::util::cancel::Cancel;
 use crate::util::DigestPre;
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
+use chrono::{TimeZone, Utc};
 use log::{debug, warn};
-use oci_distribution::manifest::{OciDescriptor, OciImageManifest};
+use oci_distribution::manifest::{
+    ImageIndexEntry, OciDescriptor, OciImageIndex, OciImageManifest, Platform,
+    OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE, WASM_CONFIG_MEDIA_TYPE,
+    WASM_LAYER_MEDIA_TYPE,
+};
 use oci_spec::image::MediaType;
-use sha256::digest_bytes;
+use sha256::digest;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// 合并layer时记录在history中的构建指令
+pub const SQUASH_CREATED_BY: &str = "SQUASH";
+
+/// 将变更集写入layerdb并记录layer元数据，无变更时返回None
+pub(crate) fn commit_layer(
+    changeset: ChangeSet,
+    options: &LayerOptions,
+    parent_chain_id: Option<&str>,
+) -> Result<Option<(LayerMetadata, OciDescriptor)>> {
+    let (diff_id, describe) =
+        match changeset.write_layer(FileSystem.layer()?, &MediaType::ImageLayer, options)? {
+            Some(layer) => layer,
+            None => return Ok(None),
+        };
+    // 未压缩layer的blob即为tar本身，diff_id与digest相同
+    let layer = LayerMetadata {
+        chain_id: chain_id(parent_chain_id, &diff_id),
+        diff_id,
+        digest: describe.digest().to_string(),
+        media_type: describe.media_type().to_string(),
+        size: describe.size(),
+        uncompressed_size: describe.size(),
+    };
+    layer.save()?;
+    let descriptor = OciDescriptor {
+        media_type: describe.media_type().to_string(),
+        digest: describe.digest().to_string(),
+        size: describe.size(),
+        urls: None,
+        annotations: None,
+    };
+    Ok(Some((layer, descriptor)))
+}
 
 pub async fn build(args: &BuildArgs) -> Result<String> {
+    build_with(args, &Cancel::new()).await
+}
+
+///
+/// 构建镜像，取消或超时时在下一个构建步骤前返回[`crate::util::cancel::Cancelled`]错误。
+/// 构建用的临时快照目录总会被删除；已写入的layer、config可由gc清理，images.json保持不变
+pub async fn build_with(args: &BuildArgs, cancel: &Cancel) -> Result<String> {
     debug!("开始构建任务: {:?}", args);
     // let docker_file = dockerfile_parser::Dockerfile::parse(
     //     std::fs::read_to_string(args.config.as_str())?.as_str(),
     // )?;
     let build_file = &args.config;
     debug!("    构建参数: {:?}", build_file);
+    build_file.validate()?;
+    let manifest_digest = if build_file.wasm_artifact {
+        build_wasm_artifact(build_file, cancel).await?
+    } else if build_file.platforms.is_empty() {
+        build_image(build_file, &build_file.copys, None, cancel)
+            .await?
+            .0
+    } else {
+        build_index(build_file, cancel).await?
+    };
+    cancel.check()?;
+    // 更新images.json
+    Repositories::modify(|repo| repo.update(&args.image, manifest_digest.sha256_pre()))?;
+    Ok(manifest_digest)
+}
+
+/// 多平台构建：每个平台一个manifest，再生成镜像索引，返回镜像索引的digest
+async fn build_index(build_file: &BuildConfig, cancel: &Cancel) -> Result<String> {
+    let mut manifests = Vec::with_capacity(build_file.platforms.len());
+    for platform_copys in build_file.platforms.iter() {
+        debug!("构建平台: {}", platform_string(&platform_copys.platform));
+        let copys: Vec<Copy> = build_file
+            .copys
+            .iter()
+            .chain(platform_copys.copys.iter())
+            .cloned()
+            .collect();
+        let (manifest_digest, manifest_size) =
+            build_image(build_file, &copys, Some(&platform_copys.platform), cancel).await?;
+        manifests.push(ImageIndexEntry {
+            media_type: OCI_IMAGE_MEDIA_TYPE.to_string(),
+            digest: manifest_digest.sha256_pre(),
+            size: manifest_size,
+            platform: Some(platform_copys.platform.clone()),
+            annotations: None,
+        });
+    }
+    let index = OciImageIndex {
+        schema_version: 2,
+        media_type: Some(OCI_IMAGE_INDEX_MEDIA_TYPE.to_string()),
+        manifests,
+        annotations: None,
+    };
+    let index_data = serde_json::to_vec(&index)?;
+    let index_digest = digest(index_data.as_slice());
+    FileSystem.save_manifest(&index_digest, &index_data)?;
+    Ok(index_digest)
+}
+
+/// 构建单个平台的镜像，写入config与manifest，返回manifest的digest与大小
+async fn build_image(
+    build_file: &BuildConfig,
+    copys: &[Copy],
+    platform: Option<&Platform>,
+    cancel: &Cancel,
+) -> Result<(String, i64)> {
+    let source_date_epoch = build_file.source_date_epoch()?;
+    let layer_options = LayerOptions { source_date_epoch };
     let snapshot_base = Snapshot::new()?;
+    let mut snapshot_dirs = SnapshotDirs(vec![snapshot_base.path.clone()]);
     let mut snapshots = Vec::new();
 
     let mut before = snapshot_base.clone();
 
-    for copy in build_file.copys.iter() {
-        before = before.init_by_self().await?;
+    for copy in copys.iter() {
+        cancel.check()?;
+        // 先登记快照目录再复制，复制中途取消时目录也会被删除
+        let next = Snapshot::new()?;
+        snapshot_dirs.0.push(next.path.clone());
+        before.copy_to(&next, cancel).await?;
+        before = next;
         before.copy_in(&copy.0, &copy.1)?;
         snapshots.push(before.clone());
     }
-    if !before.file_exist(&build_file.cmd.path_by_base(before.path.clone())) {
+    if !before.file_exist(build_file.cmd.path_by_base(before.path.clone())) {
         bail!("镜像构建失败：不存在CMD【{:?}】文件", build_file.cmd);
     }
+    let created = source_date_epoch
+        .map(|epoch| {
+            Utc.timestamp_opt(epoch, 0)
+                .single()
+                .ok_or(anyhow!("非法的SOURCE_DATE_EPOCH: {}", epoch))
+        })
+        .transpose()?
+        .unwrap_or_else(Utc::now);
     ////////////// 构建layer
-    let mut descriptors = Vec::with_capacity(snapshots.len());
-    let mut layer_descriptors = Vec::with_capacity(snapshots.len());
+    let mut history = Vec::with_capacity(snapshots.len() + 1);
+    let steps: Vec<(Snapshot, String)> = if build_file.squash {
+        // 合并为单个layer：各Copy步骤只在history中记录为空层
+        for copy in copys.iter() {
+            history.push(History::new(created, copy.created_by(), true));
+        }
+        snapshots
+            .pop()
+            .map(|last| (last, SQUASH_CREATED_BY.to_string()))
+            .into_iter()
+            .collect()
+    } else {
+        snapshots
+            .into_iter()
+            .zip(copys.iter().map(|copy| copy.created_by()))
+            .collect()
+    };
+    let mut descriptors = Vec::with_capacity(steps.len());
+    let mut layer_descriptors = Vec::with_capacity(steps.len());
+    let mut parent_chain_id: Option<String> = None;
 
     let mut snapshot = snapshot_base;
-    for tmp in snapshots {
-        let next = tmp.into();
+    for (next, created_by) in steps {
+        cancel.check()?;
         let changeset = snapshot.diff(&next);
         debug!("changeset: {:?}", changeset);
-        match changeset.write_layer(FileSystem.layer()?, &MediaType::ImageLayer) {
-            Ok((_index, describe)) => {
-                // let layer: Layer = describe.into();
-                descriptors.push(describe.digest().to_string());
-
-                layer_descriptors.push(OciDescriptor {
-                    media_type: describe.media_type().to_string(),
-                    digest: describe.digest().to_string(),
-                    size: describe.size(),
-                    urls: None,
-                    annotations: None,
-                });
+        // 无变更的步骤不生成layer，只在history中记录为空层
+        match commit_layer(changeset, &layer_options, parent_chain_id.as_deref()) {
+            Ok(Some((layer, descriptor))) => {
+                parent_chain_id = Some(layer.chain_id);
+                descriptors.push(layer.diff_id);
+                layer_descriptors.push(descriptor);
+                history.push(History::new(created, created_by, false));
+            }
+            Ok(None) => {
+                debug!("{} 未产生变更，跳过layer", created_by);
+                history.push(History::new(created, created_by, true));
             }
             Err(e) => {
                 bail!("生成layer失败：{}", e);
@@ -60,9 +205,12 @@ pub async fn build(args: &BuildArgs) -> Result<String> {
         snapshot = next;
     }
     // 构建config、写入sha256文件夹
-    let config = ConfigFile::new(&build_file, descriptors)?;
+    let mut config = ConfigFile::new(build_file, descriptors, history, created)?;
+    if let Some(platform) = platform {
+        config.set_platform(platform);
+    }
     let config_data = serde_json::to_vec(&config)?;
-    let config_digest = digest_bytes(config_data.as_slice());
+    let config_digest = digest(config_data.as_slice());
     let config_descriptor = OciDescriptor {
         media_type: MediaType::ImageConfig.to_string(),
         digest: config_digest.sha256_pre(),
@@ -70,16 +218,7 @@ pub async fn build(args: &BuildArgs) -> Result<String> {
         urls: None,
         annotations: None,
     };
-    let config_path = FileSystem
-        .config_sha256()
-        .and_then(|x| {
-            if let Err(e) = std::fs::create_dir_all(&x) {
-                warn!("创建文件夹{:?}失败{:?}", x, e);
-            }
-            Ok(x)
-        })?
-        .join(config_digest.as_str());
-    std::fs::write(config_path, config_data)?;
+    FileSystem.save_config(&config_digest, &config_data)?;
     // 构建manifest
     // let annotations: Option<HashMap<String, String>> = Some(HashMap::new());
     let image_manifest = OciImageManifest {
@@ -90,20 +229,278 @@ pub async fn build(args: &BuildArgs) -> Result<String> {
         annotations: None,
     };
     let manifest_data = serde_json::to_vec(&image_manifest)?;
-    let manifest_digest = digest_bytes(manifest_data.as_slice());
-    let manifest_path = FileSystem
-        .manifest_sha256()
-        .and_then(|x| {
-            if let Err(e) = std::fs::create_dir_all(&x) {
-                warn!("创建文件夹{:?}失败{:?}", x, e);
-            }
-            Ok(x)
-        })?
-        .join(manifest_digest.as_str());
-    std::fs::write(manifest_path, manifest_data)?;
+    let manifest_digest = digest(manifest_data.as_slice());
+    FileSystem.save_manifest(&manifest_digest, &manifest_data)?;
+    let manifest_size = manifest_data.len() as i64;
+    Ok((manifest_digest, manifest_size))
+}
 
-    // 更新images.json
-    let mut repos = Repositories::init()?;
-    repos.update_and_save(&args.image, manifest_digest.sha256_pre())?;
+/// 以wasm OCI artifact形式构建：config为空对象，唯一的layer为CMD指定的模块文件，返回manifest的digest
+async fn build_wasm_artifact(build_file: &BuildConfig, cancel: &Cancel) -> Result<String> {
+    // 所有Copy写入同一个快照，再从中取出CMD文件
+    let snapshot = Snapshot::new()?;
+    let _snapshot_dirs = SnapshotDirs(vec![snapshot.path.clone()]);
+    for copy in build_file.copys.iter() {
+        cancel.check()?;
+        snapshot.copy_in(&copy.0, &copy.1)?;
+    }
+    let module_path = build_file.cmd.path_by_base(snapshot.path.clone());
+    if !module_path.is_file() {
+        bail!(
+            "wasm artifact构建失败：不存在CMD【{:?}】文件",
+            build_file.cmd
+        );
+    }
+    let module = std::fs::read(&module_path)?;
+    let module_digest = digest(module.as_slice());
+    FileSystem.save_layer(&module_digest, &module)?;
+    let title = module_path
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or(wasm::DEFAULT_MODULE_NAME.to_string());
+    let layer_descriptor = OciDescriptor {
+        media_type: WASM_LAYER_MEDIA_TYPE.to_string(),
+        digest: module_digest.sha256_pre(),
+        size: module.len() as i64,
+        urls: None,
+        annotations: Some(HashMap::from([(TITLE_ANNOTATION.to_string(), title)])),
+    };
+
+    let config_digest = digest(wasm::ARTIFACT_CONFIG);
+    FileSystem.save_config(&config_digest, wasm::ARTIFACT_CONFIG)?;
+    let config_descriptor = OciDescriptor {
+        media_type: WASM_CONFIG_MEDIA_TYPE.to_string(),
+        digest: config_digest.sha256_pre(),
+        size: wasm::ARTIFACT_CONFIG.len() as i64,
+        urls: None,
+        annotations: None,
+    };
+    let manifest = OciImageManifest {
+        schema_version: 2,
+        media_type: Some(OCI_IMAGE_MEDIA_TYPE.to_string()),
+        config: config_descriptor,
+        layers: vec![layer_descriptor],
+        annotations: None,
+    };
+    let manifest_data = serde_json::to_vec(&manifest)?;
+    let manifest_digest = digest(manifest_data.as_slice());
+    FileSystem.save_manifest(&manifest_digest, &manifest_data)?;
     Ok(manifest_digest)
 }
+
+/// 构建用的临时快照目录，构建结束（包括失败、取消或future被丢弃）时删除
+struct SnapshotDirs(Vec<PathBuf>);
+
+impl Drop for SnapshotDirs {
+    fn drop(&mut self) {
+        for dir in self.0.iter() {
+            if let Err(e) = std::fs::remove_dir_all(dir) {
+                warn!("删除临时快照目录{:?}失败：{:?}", dir, e);
+            }
+        }
+    }
+}
+
+/// 测试用的单文件应用镜像，`/bin/app`的内容为镜像名。返回镜像引用与manifest digest（带`sha256:`前缀）
+#[cfg(all(test, feature = "test-registry"))]
+pub(crate) async fn build_test_image(image: &str) -> (oci_distribution::Reference, String) {
+    use crate::image::build::config::instructions::{Dest, Kind};
+    crate::filesystem::test_home();
+    let src = tempfile::tempdir().unwrap();
+    let app = src.path().join("app");
+    std::fs::write(&app, image).unwrap();
+    let image: oci_distribution::Reference = image.parse().unwrap();
+    let args = BuildArgs {
+        config: BuildConfig {
+            kind: Kind::App,
+            copys: vec![Copy(app, "/bin/app".to_string().try_into().unwrap())],
+            cmd: Dest::try_from("/bin/app".to_string()).unwrap(),
+            reproducible: false,
+            squash: false,
+            platforms: Vec::new(),
+            wasm_artifact: false,
+        },
+        image: image.clone(),
+    };
+    let digest = build(&args).await.unwrap();
+    (image, digest.sha256_pre())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{build, build_with};
+    use crate::args::BuildArgs;
+    use crate::filesystem::test_home;
+    use crate::image::build::config::instructions::{Copy, Dest, Kind};
+    use crate::image::build::config::{BuildConfig, PlatformCopys};
+    use crate::image::config::ConfigFile;
+    use crate::image::index::{host_platform, select_manifest};
+    use crate::image::manifest::Manifest;
+    use crate::image::Repositories;
+    use crate::util::cancel::{Cancel, Cancelled};
+    use crate::util::DigestPre;
+
+    fn build_args(src: &std::path::Path, reproducible: bool) -> BuildArgs {
+        BuildArgs {
+            config: BuildConfig {
+                kind: Kind::App,
+                copys: vec![Copy(
+                    src.to_path_buf(),
+                    "/bin/app".to_string().try_into().unwrap(),
+                )],
+                cmd: Dest::try_from("/bin/app".to_string()).unwrap(),
+                reproducible,
+                squash: false,
+                platforms: Vec::new(),
+                wasm_artifact: false,
+            },
+            image: "localhost/test/reproducible:1".parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reproducible_build() {
+        test_home();
+        let src = tempfile::tempdir().unwrap();
+        let app = src.path().join("app");
+        std::fs::write(&app, "app").unwrap();
+
+        let first = build(&build_args(&app, true)).await.unwrap();
+        filetime::set_file_mtime(&app, filetime::FileTime::from_unix_time(1_000_000, 0)).unwrap();
+        let second = build(&build_args(&app, true)).await.unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_build() {
+        test_home();
+        let src = tempfile::tempdir().unwrap();
+        let app = src.path().join("app");
+        std::fs::write(&app, "app").unwrap();
+        let mut args = build_args(&app, false);
+        args.image = "localhost/test/cancelled:1".parse().unwrap();
+
+        let cancel = Cancel::new();
+        cancel.cancel();
+        let e = build_with(&args, &cancel).await.unwrap_err();
+        assert_eq!(e.downcast_ref::<Cancelled>(), Some(&Cancelled::Requested));
+        assert!(Repositories::init()
+            .unwrap()
+            .image_digest(&args.image)
+            .is_none());
+
+        // 构建中途取消：复制快照时取消，已创建的快照目录全部删除
+        let marker = src.path().join("cancelled-build-marker");
+        std::fs::write(&marker, "marker").unwrap();
+        let root = || "/".to_string().try_into().unwrap();
+        args.config.copys = vec![Copy(marker, root())];
+        for i in 0..12 {
+            let file = src.path().join(format!("file-{}", i));
+            std::fs::write(&file, "file").unwrap();
+            args.config.copys.push(Copy(file, root()));
+        }
+        args.config.copys.push(Copy(
+            app.clone(),
+            "/bin/app".to_string().try_into().unwrap(),
+        ));
+        let marker_dirs = || {
+            std::fs::read_dir(std::env::temp_dir())
+                .unwrap()
+                .filter_map(|x| x.ok().map(|x| x.path()))
+                .filter(|x| x != src.path() && x.join("cancelled-build-marker").exists())
+                .collect::<Vec<_>>()
+        };
+        let cancel = Cancel::new();
+        // 构建在复制快照时让出执行权，此时检查已复制出的快照目录：
+        // 第十个快照中出现marker时其余文件一般仍在复制中
+        let watcher = async {
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+            loop {
+                let dirs = marker_dirs();
+                if dirs.len() >= 10 || std::time::Instant::now() > deadline {
+                    cancel.cancel();
+                    return dirs;
+                }
+                tokio::task::yield_now().await;
+            }
+        };
+        let (result, dirs) = tokio::join!(build_with(&args, &cancel), watcher);
+        let e = result.unwrap_err();
+        assert_eq!(e.downcast_ref::<Cancelled>(), Some(&Cancelled::Requested));
+        assert!(dirs.len() >= 10, "{:?}", dirs);
+        assert!(dirs.iter().all(|x| !x.exists()), "{:?}", dirs);
+        assert!(marker_dirs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_copy() {
+        test_home();
+        let src = tempfile::tempdir().unwrap();
+        let app = src.path().join("app");
+        std::fs::write(&app, "app").unwrap();
+
+        let mut args = build_args(&app, false);
+        args.config.copys.push(args.config.copys[0].clone());
+        let manifest_digest = build(&args).await.unwrap();
+
+        let manifest = Manifest::load(&manifest_digest)
+            .unwrap()
+            .to_oci_manifest()
+            .unwrap();
+        assert_eq!(manifest.layers.len(), 1);
+        assert!(manifest.layers.iter().all(|x| x.size > 0));
+        let config = ConfigFile::load(&manifest.config.digest.get_digest().unwrap()).unwrap();
+        assert_eq!(config.rootf.diff_ids.len(), 1);
+        assert_eq!(config.history.len(), 2);
+        assert!(!config.history[0].empty_layer);
+        assert!(config.history[1].empty_layer);
+    }
+
+    #[tokio::test]
+    async fn test_multi_platform_build() {
+        test_home();
+        let src = tempfile::tempdir().unwrap();
+        let app = src.path().join("app");
+        std::fs::write(&app, "app").unwrap();
+
+        let host = host_platform();
+        let mut other = host.clone();
+        other.architecture = "other".to_string();
+        let mut args = build_args(&app, false);
+        args.image = "localhost/test/multi-platform:1".parse().unwrap();
+        let copys = std::mem::take(&mut args.config.copys);
+        for platform in [host.clone(), other] {
+            args.config.platforms.push(PlatformCopys {
+                platform,
+                copys: copys.clone(),
+            });
+        }
+        let index_digest = build(&args).await.unwrap();
+
+        let index = Manifest::load(&index_digest).unwrap();
+        assert!(index.is_index());
+        let index = index.to_oci_index().unwrap();
+        assert_eq!(index.manifests.len(), 2);
+        let resolved = Manifest::resolve(&index_digest, &host).unwrap();
+        assert_eq!(
+            resolved.sha256_pre(),
+            select_manifest(&index, &host).unwrap().digest
+        );
+        let manifest = Manifest::load(&resolved)
+            .unwrap()
+            .to_oci_manifest()
+            .unwrap();
+        let config = ConfigFile::load(&manifest.config.digest.get_digest().unwrap()).unwrap();
+        assert_eq!(config.architecture, Some(host.architecture));
+
+        // wasm artifact只支持单平台，且Kind须为Wasi
+        args.config.kind = Kind::Wasi;
+        args.config.wasm_artifact = true;
+        let e = build(&args).await.unwrap_err();
+        assert!(e.to_string().contains("多平台"), "{}", e);
+        args.config.platforms.clear();
+        args.config.kind = Kind::App;
+        let e = build(&args).await.unwrap_err();
+        assert!(e.to_string().contains("Wasi"), "{}", e);
+    }
+}
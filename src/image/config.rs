@@ -2,6 +2,8 @@ use crate::filesystem::FileSystem;
 use crate::image::build::config::instructions::Kind;
 use crate::image::build::config::BuildConfig;
 use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use oci_distribution::manifest::Platform;
 use serde::{Deserialize, Serialize};
 
 pub struct ConfigFileAndData {
@@ -13,16 +15,33 @@ impl ConfigFileAndData {
         let config_path = FileSystem.config_sha256()?.join(digest);
         let data = std::fs::read(&config_path)
             .with_context(|| format!("读取镜像config文件{:?}失败", config_path))?;
-        let file: ConfigFile = serde_json::from_slice(&data)?;
+        let file = ConfigFile::parse(&data)?;
         Ok(Self { file, data })
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ConfigFile {
+    /// 镜像创建时间（RFC 3339）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub architecture: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    /// 标准OCI/Docker镜像config中没有该字段，按App处理
+    #[serde(default)]
     pub kind: Kind,
+    /// 标准OCI/Docker镜像config中没有该字段，取`config.Entrypoint`或`config.Cmd`的第一项
+    #[serde(default)]
     pub cmd: String,
+    #[serde(alias = "rootfs")]
     pub rootf: RootFs,
+    /// 构建步骤记录，与layer按顺序对应（跳过empty_layer的条目）
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<History>,
 }
 
 impl ConfigFile {
@@ -30,22 +49,77 @@ impl ConfigFile {
         let config_path = FileSystem.config_sha256()?.join(digest);
         let data = std::fs::read(&config_path)
             .with_context(|| format!("读取镜像config文件{:?}失败", config_path))?;
-        let file: ConfigFile = serde_json::from_slice(&data)?;
+        Self::parse(&data)
+    }
+
+    fn parse(data: &[u8]) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Standard {
+            config: Option<ExecConfig>,
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct ExecConfig {
+            entrypoint: Option<Vec<String>>,
+            cmd: Option<Vec<String>>,
+        }
+        let mut file: ConfigFile = serde_json::from_slice(data).context("解析镜像config失败")?;
+        if file.cmd.is_empty() {
+            let standard: Standard = serde_json::from_slice(data)?;
+            file.cmd = standard
+                .config
+                .and_then(|x| {
+                    x.entrypoint
+                        .filter(|x| !x.is_empty())
+                        .or(x.cmd)?
+                        .into_iter()
+                        .next()
+                })
+                .map(|x| x.trim_start_matches('/').to_string())
+                .unwrap_or_default();
+        }
         Ok(file)
     }
 
-    pub fn new(config: &BuildConfig, diff_ids: Vec<String>) -> Result<Self> {
+    pub fn new(
+        config: &BuildConfig,
+        diff_ids: Vec<String>,
+        history: Vec<History>,
+        created: DateTime<Utc>,
+    ) -> Result<Self> {
         let regix = regex::Regex::new("^/")?;
         let cmd = regix.replace(config.cmd.orgin.as_str(), "").to_string();
         Ok(Self {
+            created: Some(created.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            architecture: None,
+            os: None,
+            variant: None,
             kind: config.kind.clone(),
-            cmd: cmd,
+            cmd,
             rootf: RootFs {
                 typ: "layers".to_string(),
                 diff_ids,
             },
+            history,
         })
     }
+    /// 记录镜像所属平台
+    pub fn set_platform(&mut self, platform: &Platform) {
+        self.architecture = Some(platform.architecture.clone());
+        self.os = Some(platform.os.clone());
+        self.variant = platform.variant.clone();
+    }
+    /// 从任意镜像config中读取diff_ids（兼容标准OCI config的`rootfs`字段）
+    pub fn diff_ids(data: &[u8]) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct RootFsOnly {
+            #[serde(alias = "rootfs")]
+            rootf: RootFs,
+        }
+        let config: RootFsOnly =
+            serde_json::from_slice(data).context("解析config中的rootfs失败")?;
+        Ok(config.rootf.diff_ids)
+    }
     pub fn data(&self) -> Result<Vec<u8>> {
         Ok(serde_json::to_vec(&self)?)
     }
@@ -57,3 +131,24 @@ pub struct RootFs {
     pub typ: String,
     pub diff_ids: Vec<String>,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct History {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    /// 该步骤未产生layer
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub empty_layer: bool,
+}
+
+impl History {
+    pub fn new(created: DateTime<Utc>, created_by: String, empty_layer: bool) -> Self {
+        Self {
+            created: Some(created.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            created_by: Some(created_by),
+            empty_layer,
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use crate::filesystem::FileSystem;
+use crate::image::config::ConfigFile;
+use crate::image::layer::chain_id;
+use crate::image::manifest::Manifest;
+use crate::image::Repositories;
+use crate::util::DigestPre;
+use anyhow::Result;
+use log::{debug, warn};
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// 默认只回收修改时间早于1小时的文件
+pub const DEFAULT_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// 垃圾回收结果，记录被删除的文件名（digest，不带`sha256:`前缀）
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub manifests: Vec<String>,
+    pub configs: Vec<String>,
+    pub layers: Vec<String>,
+    pub layer_contents: Vec<String>,
+    pub containers: Vec<String>,
+    /// 删除的manifest、config、layer文件总大小（不含容器目录）
+    pub freed_bytes: u64,
+}
+
+/// 垃圾回收选项
+#[derive(Debug, Clone)]
+pub struct GcOptions {
+    ///
+    /// 只删除修改时间早于该时长的文件与容器目录。
+    /// 进行中的pull、构建、上传先写入blob、最后才更新images.json（或推送manifest），
+    /// 期间写入的文件尚不可达；设为0时须确保没有进行中的此类操作（包括其它进程）
+    pub min_age: Duration,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        Self {
+            min_age: DEFAULT_MIN_AGE,
+        }
+    }
+}
+
+/// images.json中的镜像可达的文件
+#[derive(Default)]
+struct Reachable {
+    manifests: HashSet<String>,
+    configs: HashSet<String>,
+    layers: HashSet<String>,
+    layer_contents: HashSet<String>,
+}
+
+impl Reachable {
+    fn mark_manifest(&mut self, digest: &str) -> Result<()> {
+        if !self.manifests.insert(digest.to_string()) {
+            return Ok(());
+        }
+        if !FileSystem.exist_manifest(&digest.to_string())? {
+            warn!("images.json中的manifest[{}]在本地不存在", digest);
+            return Ok(());
+        }
+        let manifest = Manifest::load(digest)?;
+        if manifest.is_index() {
+            // 镜像索引只保留本地已拉取的条目
+            for entry in manifest.to_oci_index()?.manifests.iter() {
+                let entry_digest = entry.digest.get_digest()?;
+                if FileSystem.exist_manifest(&entry_digest)? {
+                    self.mark_manifest(&entry_digest)?;
+                }
+            }
+            return Ok(());
+        }
+        let manifest = match manifest.to_oci_manifest() {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                warn!("解析manifest[{}]失败，跳过：{:?}", digest, e);
+                return Ok(());
+            }
+        };
+        for layer in manifest.layers.iter() {
+            self.layers.insert(layer.digest.get_digest()?);
+        }
+        let config_digest = manifest.config.digest.get_digest()?;
+        if FileSystem.exist_config(&config_digest)? {
+            let data = std::fs::read(FileSystem.config_sha256()?.join(&config_digest))?;
+            if let Ok(diff_ids) = ConfigFile::diff_ids(&data) {
+                let mut parent: Option<String> = None;
+                for diff_id in diff_ids.iter() {
+                    let id = chain_id(parent.as_deref(), diff_id);
+                    self.layer_contents.insert(id.get_digest()?);
+                    // 没有元数据的旧layer以diff_id为blob文件名
+                    self.layers.insert(diff_id.get_digest()?);
+                    parent = Some(id);
+                }
+            }
+        }
+        self.configs.insert(config_digest);
+        Ok(())
+    }
+}
+
+///
+/// 垃圾回收：删除images.json中的镜像不再引用的manifest、config、layer及其元数据，
+/// 以及对应manifest已被删除的容器目录。
+/// 与进行中的pull、构建、上传不互斥，按[`DEFAULT_MIN_AGE`]保留最近写入的文件
+pub fn gc() -> Result<GcReport> {
+    gc_with(&GcOptions::default())
+}
+
+///
+/// 按指定选项执行垃圾回收。
+/// 只处理以digest命名的文件与目录，临时文件（如registry服务端上传中的blob）不受影响
+pub fn gc_with(options: &GcOptions) -> Result<GcReport> {
+    let repo = Repositories::init()?;
+    let mut reachable = Reachable::default();
+    for digest in repo.digests() {
+        reachable.mark_manifest(&digest.get_digest()?)?;
+    }
+    let mut report = GcReport::default();
+    report.manifests = remove_files(
+        &FileSystem.manifest_sha256()?,
+        &reachable.manifests,
+        options.min_age,
+        &mut report.freed_bytes,
+    )?;
+    report.configs = remove_files(
+        &FileSystem.config_sha256()?,
+        &reachable.configs,
+        options.min_age,
+        &mut report.freed_bytes,
+    )?;
+    report.layers = remove_files(
+        &FileSystem.layer_blobs()?,
+        &reachable.layers,
+        options.min_age,
+        &mut report.freed_bytes,
+    )?;
+    report.layer_contents = remove_files(
+        &FileSystem.layer_contents()?,
+        &reachable.layer_contents,
+        options.min_age,
+        &mut report.freed_bytes,
+    )?;
+    for entry in std::fs::read_dir(FileSystem.container()?)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !is_digest(&name) || reachable.manifests.contains(&name) {
+            continue;
+        }
+        if !is_expired(&entry.metadata()?, options.min_age) {
+            debug!("gc跳过最近修改的容器: {}", name);
+            continue;
+        }
+        debug!("gc删除容器: {}", name);
+        std::fs::remove_dir_all(entry.path())?;
+        report.containers.push(name);
+    }
+    debug!("gc完成: {:?}", report);
+    Ok(report)
+}
+
+/// 文件名是否为sha256摘要（64位小写十六进制），其它文件（临时文件等）不由gc管理
+fn is_digest(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|x| matches!(x, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// 修改时间是否早于`min_age`，修改时间晚于当前时间视为未过期
+fn is_expired(metadata: &std::fs::Metadata, min_age: Duration) -> bool {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|x| SystemTime::now().duration_since(x).ok())
+        .is_some_and(|x| x >= min_age)
+}
+
+/// 删除目录下不在`keep`中、修改时间早于`min_age`的digest文件，返回被删除的文件名
+fn remove_files(
+    dir: &Path,
+    keep: &HashSet<String>,
+    min_age: Duration,
+    freed_bytes: &mut u64,
+) -> Result<Vec<String>> {
+    let mut removed = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !metadata.is_file() || !is_digest(&name) || keep.contains(&name) {
+            continue;
+        }
+        if !is_expired(&metadata, min_age) {
+            debug!("gc跳过最近修改的文件: {:?}", entry.path());
+            continue;
+        }
+        debug!("gc删除: {:?}", entry.path());
+        std::fs::remove_file(entry.path())?;
+        *freed_bytes += metadata.len();
+        removed.push(name);
+    }
+    Ok(removed)
+}
@@ -0,0 +1,85 @@
+use oci_distribution::manifest::{ImageIndexEntry, OciImageIndex, Platform};
+
+/// 本机平台，os/architecture/variant的取值遵循OCI（Go）的命名
+pub fn host_platform() -> Platform {
+    let os = match std::env::consts::OS {
+        "macos" => "darwin",
+        other => other,
+    };
+    let (architecture, variant) = match std::env::consts::ARCH {
+        "x86_64" => ("amd64", None),
+        "x86" => ("386", None),
+        "aarch64" => ("arm64", Some("v8")),
+        "arm" => ("arm", Some("v7")),
+        "powerpc64" => ("ppc64", None),
+        "riscv64" => ("riscv64", None),
+        "s390x" => ("s390x", None),
+        other => (other, None),
+    };
+    Platform {
+        architecture: architecture.to_string(),
+        os: os.to_string(),
+        os_version: None,
+        os_features: None,
+        variant: variant.map(|x| x.to_string()),
+        features: None,
+    }
+}
+
+/// 判断`candidate`是否满足`wanted`：os与architecture必须相同，
+/// `wanted`指定了variant时variant也必须相同（arm64未标注variant时视为v8）
+pub fn platform_matches(candidate: &Platform, wanted: &Platform) -> bool {
+    fn variant(platform: &Platform) -> Option<&str> {
+        match (platform.architecture.as_str(), platform.variant.as_deref()) {
+            ("arm64", None) => Some("v8"),
+            (_, variant) => variant,
+        }
+    }
+    candidate.os == wanted.os
+        && candidate.architecture == wanted.architecture
+        && (wanted.variant.is_none() || variant(candidate) == variant(wanted))
+}
+
+/// 从镜像索引中选择与平台匹配的manifest
+pub fn select_manifest<'a>(
+    index: &'a OciImageIndex,
+    platform: &Platform,
+) -> Option<&'a ImageIndexEntry> {
+    index.manifests.iter().find(|entry| {
+        entry
+            .platform
+            .as_ref()
+            .is_some_and(|x| platform_matches(x, platform))
+    })
+}
+
+/// 平台的文本表示，如`linux/arm64/v8`
+pub fn platform_string(platform: &Platform) -> String {
+    match &platform.variant {
+        Some(variant) => format!("{}/{}/{}", platform.os, platform.architecture, variant),
+        None => format!("{}/{}", platform.os, platform.architecture),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{host_platform, platform_matches};
+
+    #[test]
+    fn test_platform_matches() {
+        let host = host_platform();
+        assert!(platform_matches(&host, &host));
+
+        let mut arm64 = host.clone();
+        arm64.architecture = "arm64".to_string();
+        arm64.variant = None;
+        let mut arm64_v8 = arm64.clone();
+        arm64_v8.variant = Some("v8".to_string());
+        assert!(platform_matches(&arm64, &arm64_v8));
+        assert!(platform_matches(&arm64_v8, &arm64));
+
+        let mut amd64 = arm64.clone();
+        amd64.architecture = "amd64".to_string();
+        assert!(!platform_matches(&amd64, &arm64));
+    }
+}
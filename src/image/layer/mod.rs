@@ -1,9 +1,14 @@
 pub mod tar_file;
 
-use crate::filesystem::FileSystem;
+use crate::filesystem::{write_atomic, FileSystem};
 use crate::util::DigestPre;
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::GzDecoder;
+use log::debug;
 use oci_distribution::client::ImageLayer;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
 
 pub static LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
 
@@ -13,35 +18,128 @@ pub struct LayerAndData {
     pub media_type: String,
 }
 
-// #[derive(Serialize, Deserialize)]
-// pub struct Layer {
-//     desc: Descriptor,
-// }
-//
-// impl Layer {
-//     /// 好像不行
-//     pub fn save_descriptor(&self) -> Result<String> {
-//         let path = FileSystem.layer_contents()?;
-//         let data = serde_json::to_vec(&self.desc)?;
-//         let digest = sha256::digest_bytes(data.as_slice());
-//         let path = path.join(&digest);
-//         std::fs::write(path, data)?;
-//         Ok(digest)
-//     }
-// }
-//
-// impl From<Descriptor> for Layer {
-//     fn from(desc: Descriptor) -> Self {
-//         Self { desc }
-//     }
-// }
-// impl Deref for Layer {
-//     type Target = Descriptor;
-//
-//     fn deref(&self) -> &Self::Target {
-//         &self.desc
-//     }
-// }
+/// layer元数据（layerdb/contents），以chain ID为文件名
+///
+/// 记录layer未压缩内容的diff_id（config中使用）与blob的digest（manifest中使用）之间的对应关系。
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LayerMetadata {
+    /// 未压缩tar的摘要，形如`sha256:...`
+    pub diff_id: String,
+    /// blob（可能已压缩）的摘要，形如`sha256:...`
+    pub digest: String,
+    pub media_type: String,
+    /// blob大小
+    pub size: i64,
+    /// 未压缩tar的大小
+    pub uncompressed_size: i64,
+    /// 该layer及其所有父layer的chain ID
+    pub chain_id: String,
+}
+
+impl LayerMetadata {
+    /// 读取本地blob、解压计算diff_id，生成layer元数据
+    pub fn from_blob(digest: &str, media_type: &str, parent: Option<&str>) -> Result<Self> {
+        let layer_path = FileSystem
+            .layer_blobs()?
+            .join(digest.to_string().get_digest()?);
+        let file = std::fs::File::open(&layer_path).context(anyhow!("加载{:?}失败", layer_path))?;
+        let size = file.metadata()?.len() as i64;
+        let mut reader = decompress(file, media_type)?;
+        let mut hasher = HashWriter(Sha256::new(), 0);
+        std::io::copy(&mut reader, &mut hasher)?;
+        let HashWriter(hash, uncompressed_size) = hasher;
+        let diff_id = format!("sha256:{:x}", hash.finalize());
+        Ok(Self {
+            chain_id: chain_id(parent, &diff_id),
+            diff_id,
+            digest: digest.to_string(),
+            media_type: media_type.to_string(),
+            size,
+            uncompressed_size: uncompressed_size as i64,
+        })
+    }
+    /// 按chain ID加载
+    pub fn load(chain_id: &str) -> Result<Self> {
+        let path = FileSystem
+            .layer_contents()?
+            .join(chain_id.to_string().get_digest()?);
+        let data = std::fs::read(&path).context(anyhow!("加载layer元数据{:?}失败", path))?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+    /// 按chain ID保存
+    pub fn save(&self) -> Result<()> {
+        let path = FileSystem
+            .layer_contents()?
+            .join(self.chain_id.get_digest()?);
+        write_atomic(&path, &serde_json::to_vec(self)?)
+    }
+    /// 按config中的diff_ids依次加载各layer的元数据
+    ///
+    /// 本地没有元数据的layer（旧版本构建/拉取）按未压缩layer处理：blob digest即diff_id。
+    pub fn load_all(diff_ids: &[String]) -> Result<Vec<Self>> {
+        let mut layers: Vec<Self> = Vec::with_capacity(diff_ids.len());
+        for diff_id in diff_ids {
+            let parent = layers.last().map(|x| x.chain_id.as_str());
+            let chain_id = chain_id(parent, diff_id);
+            let layer = match Self::load(&chain_id) {
+                Ok(layer) => layer,
+                Err(e) => {
+                    debug!("{:?}，按未压缩layer处理", e);
+                    Self {
+                        diff_id: diff_id.clone(),
+                        digest: diff_id.clone(),
+                        media_type: LAYER_MEDIA_TYPE.to_string(),
+                        size: -1,
+                        uncompressed_size: -1,
+                        chain_id,
+                    }
+                }
+            };
+            layers.push(layer);
+        }
+        Ok(layers)
+    }
+    /// 打开layer，返回解压后的tar数据流
+    pub fn open(&self) -> Result<Box<dyn Read>> {
+        let layer_path = FileSystem.layer_blobs()?.join(self.digest.get_digest()?);
+        let file = std::fs::File::open(&layer_path).context(anyhow!("加载{:?}失败", layer_path))?;
+        decompress(file, &self.media_type)
+    }
+}
+
+/// 计算chain ID：`ChainID(L0) = DiffID(L0)`，`ChainID(Ln) = SHA256(ChainID(Ln-1) + " " + DiffID(Ln))`
+pub fn chain_id(parent: Option<&str>, diff_id: &str) -> String {
+    match parent {
+        None => diff_id.to_string(),
+        Some(parent) => format!(
+            "sha256:{:x}",
+            Sha256::digest(format!("{} {}", parent, diff_id))
+        ),
+    }
+}
+
+/// 按layer的媒体类型解压
+pub fn decompress<R: Read + 'static>(reader: R, media_type: &str) -> Result<Box<dyn Read>> {
+    if media_type.ends_with("+gzip") || media_type.ends_with(".gzip") {
+        Ok(Box::new(GzDecoder::new(reader)))
+    } else if media_type.ends_with("+zstd") {
+        bail!("暂不支持zstd压缩的layer: {}", media_type)
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+struct HashWriter(Sha256, u64);
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        self.1 += buf.len() as u64;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
 
 impl LayerAndData {
     pub fn load(desc_digest: &String, media_type: String) -> Result<Self> {
@@ -66,3 +164,43 @@ impl From<LayerAndData> for ImageLayer {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{chain_id, LayerMetadata};
+    use crate::filesystem::{test_home, FileSystem};
+    use flate2::write::GzEncoder;
+    use sha2::{Digest, Sha256};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_gzip_layer_metadata() {
+        test_home();
+        let tar = b"not really a tar, but enough for hashing".to_vec();
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&tar).unwrap();
+        let blob = encoder.finish().unwrap();
+        let blob_digest = format!("{:x}", Sha256::digest(&blob));
+        FileSystem.save_layer(&blob_digest, &blob).unwrap();
+
+        let parent = "sha256:parent";
+        let layer = LayerMetadata::from_blob(
+            &format!("sha256:{}", blob_digest),
+            "application/vnd.oci.image.layer.v1.tar+gzip",
+            Some(parent),
+        )
+        .unwrap();
+        let diff_id = format!("sha256:{:x}", Sha256::digest(&tar));
+        assert_eq!(layer.diff_id, diff_id);
+        assert_eq!(layer.size, blob.len() as i64);
+        assert_eq!(layer.uncompressed_size, tar.len() as i64);
+        assert_eq!(layer.chain_id, chain_id(Some(parent), &diff_id));
+
+        layer.save().unwrap();
+        let loaded = LayerMetadata::load(&layer.chain_id).unwrap();
+        assert_eq!(loaded, layer);
+        let mut data = Vec::new();
+        loaded.open().unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, tar);
+    }
+}
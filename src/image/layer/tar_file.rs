@@ -4,10 +4,18 @@ use regex::Regex;
 pub enum TarFileTy {
     Update(String),
     Delete(String),
+    /// 不透明whiteout（`.wh..wh..opq`）：清空下层中该文件夹的内容
+    Opaque(String),
 }
 
+/// 不透明whiteout的文件名
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
 impl From<String> for TarFileTy {
     fn from(val: String) -> Self {
+        if let Some(dir) = val.strip_suffix(OPAQUE_WHITEOUT) {
+            return Self::Opaque(dir.to_string());
+        }
         if let Ok(valid_ident) = Regex::new(r"(.*?/?)(\.wh\.)([^/]+)$") {
             if valid_ident.is_match(val.as_str()) {
                 Self::Delete(valid_ident.replace(val.as_str(), "$1$3").to_string())
@@ -22,10 +30,22 @@ impl From<String> for TarFileTy {
 
 #[cfg(test)]
 mod test {
+    use super::TarFileTy;
     use regex::Regex;
+
+    #[test]
+    fn test_tar_file_ty() {
+        let ty = |path: &str| TarFileTy::from(path.to_string());
+        assert!(matches!(ty("bin/app"), TarFileTy::Update(x) if x == "bin/app"));
+        assert!(matches!(ty("etc/"), TarFileTy::Update(x) if x == "etc/"));
+        assert!(matches!(ty(".wh.app"), TarFileTy::Delete(x) if x == "app"));
+        assert!(matches!(ty("etc/.wh.app.toml"), TarFileTy::Delete(x) if x == "etc/app.toml"));
+        assert!(matches!(ty("etc/.wh..wh..opq"), TarFileTy::Opaque(x) if x == "etc/"));
+        assert!(matches!(ty(".wh..wh..opq"), TarFileTy::Opaque(x) if x.is_empty()));
+    }
     #[test]
     fn test_regex() {
-        /// "Cargo.toml"
+        // "Cargo.toml"
         // ".wh.dir"
         // "dir/.wh.Cargo.toml.copy"
         let valid_ident: Regex = Regex::new(r"(.*?)/?(\.wh\.)?([^/]+)$").unwrap();
@@ -56,16 +76,16 @@ mod test {
     }
     #[test]
     fn test_regex2() {
-        /// "Cargo.toml"
+        // "Cargo.toml"
         // ".wh.dir"
         // "dir/.wh.Cargo.toml.copy"
         let valid_ident: Regex = Regex::new(r"(.*?/?)(\.wh\.)([^/]+)$").unwrap();
         {
-            assert_eq!(valid_ident.is_match("Cargo.toml"), false);
-            assert_eq!(valid_ident.is_match(".wh.dir"), true);
-            assert_eq!(valid_ident.is_match("dir/.wh.Cargo.toml.copy"), true);
-            assert_eq!(valid_ident.is_match("dir/dir2/Cargo.toml.copy"), false);
-            assert_eq!(valid_ident.is_match("dir/dir2/.wh.Cargo.toml.copy"), true);
+            assert!(!valid_ident.is_match("Cargo.toml"));
+            assert!(valid_ident.is_match(".wh.dir"));
+            assert!(valid_ident.is_match("dir/.wh.Cargo.toml.copy"));
+            assert!(!valid_ident.is_match("dir/dir2/Cargo.toml.copy"));
+            assert!(valid_ident.is_match("dir/dir2/.wh.Cargo.toml.copy"));
 
             assert_eq!(valid_ident.replace("Cargo.toml", "$1$3"), "Cargo.toml");
             assert_eq!(valid_ident.replace(".wh.dir", "$1$3"), "dir");
@@ -1,11 +1,48 @@
 use crate::filesystem::FileSystem;
+use crate::image::index::{platform_string, select_manifest};
 use crate::image::layer::LayerAndData;
-use anyhow::{anyhow, Context, Result};
-use oci_distribution::manifest::{OciDescriptor, OciImageManifest};
+use crate::util::DigestPre;
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use oci_distribution::manifest::{
+    OciDescriptor, OciImageIndex, OciImageManifest, Platform, IMAGE_CONFIG_MEDIA_TYPE,
+    IMAGE_DOCKER_CONFIG_MEDIA_TYPE, IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
+    IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE, IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE,
+    IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE, IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+    IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE,
+};
+use serde::Deserialize;
+
+/// Docker外部layer（不经registry分发）的媒体类型
+pub const DOCKER_FOREIGN_LAYER_MEDIA_TYPE: &str =
+    "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip";
+
+/// 记录layer文件名的注解
+pub const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+/// Docker schema1 manifest媒体类型的前缀
+const DOCKER_SCHEMA1_MEDIA_TYPE_PREFIX: &str = "application/vnd.docker.distribution.manifest.v1";
+
+/// Docker schema2媒体类型对应的OCI媒体类型，其它类型返回None
+pub fn oci_media_type(media_type: &str) -> Option<&'static str> {
+    match media_type {
+        IMAGE_MANIFEST_MEDIA_TYPE => Some(OCI_IMAGE_MEDIA_TYPE),
+        IMAGE_MANIFEST_LIST_MEDIA_TYPE => Some(OCI_IMAGE_INDEX_MEDIA_TYPE),
+        IMAGE_DOCKER_CONFIG_MEDIA_TYPE => Some(IMAGE_CONFIG_MEDIA_TYPE),
+        IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE => Some(IMAGE_LAYER_GZIP_MEDIA_TYPE),
+        IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE => Some(IMAGE_LAYER_MEDIA_TYPE),
+        DOCKER_FOREIGN_LAYER_MEDIA_TYPE => Some(IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE),
+        _ => None,
+    }
+}
 
 pub struct Manifest(Vec<u8>);
 
 impl Manifest {
+    /// 由manifest原始字节构造
+    pub fn new(data: Vec<u8>) -> Self {
+        Self(data)
+    }
     pub fn load(digest: &str) -> Result<Self> {
         let path = FileSystem.manifest_sha256()?.join(digest);
         let data = std::fs::read(path)?;
@@ -14,9 +51,115 @@ impl Manifest {
     pub fn to_oci_manifest(&self) -> Result<OciImageManifest> {
         Ok(serde_json::from_slice(&self.0)?)
     }
+    pub fn to_oci_index(&self) -> Result<OciImageIndex> {
+        Ok(serde_json::from_slice(&self.0)?)
+    }
+    /// 是否为镜像索引（OCI image index或Docker manifest list）
+    pub fn is_index(&self) -> bool {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Probe {
+            media_type: Option<String>,
+            manifests: Option<serde_json::Value>,
+        }
+        match serde_json::from_slice::<Probe>(&self.0) {
+            Ok(Probe {
+                media_type: Some(media_type),
+                ..
+            }) => {
+                media_type == OCI_IMAGE_INDEX_MEDIA_TYPE
+                    || media_type == IMAGE_MANIFEST_LIST_MEDIA_TYPE
+            }
+            Ok(Probe { manifests, .. }) => manifests.is_some(),
+            Err(_) => false,
+        }
+    }
+    /// manifest中记录的媒体类型（`mediaType`字段）
+    pub fn media_type(&self) -> Option<String> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Probe {
+            media_type: Option<String>,
+        }
+        serde_json::from_slice::<Probe>(&self.0)
+            .ok()
+            .and_then(|x| x.media_type)
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.0
+    }
+    /// 检查manifest格式：支持OCI与Docker schema2，不支持Docker schema1
+    pub fn check_schema(&self) -> Result<()> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Probe {
+            schema_version: Option<u32>,
+            media_type: Option<String>,
+        }
+        let probe: Probe = serde_json::from_slice(&self.0).context("解析manifest失败")?;
+        let schema1 = probe
+            .media_type
+            .is_some_and(|x| x.starts_with(DOCKER_SCHEMA1_MEDIA_TYPE_PREFIX));
+        if schema1 || probe.schema_version == Some(1) {
+            bail!("不支持Docker schema1格式的manifest，请以schema2或OCI格式重新推送镜像");
+        }
+        Ok(())
+    }
+    ///
+    /// 转换为OCI格式：manifest（或manifest list）自身及其中config、layer、条目的
+    /// Docker schema2媒体类型改为对应的OCI类型，blob与其它字段不变。
+    /// 没有需要转换的媒体类型时返回None
+    pub fn to_oci(&self) -> Result<Option<Manifest>> {
+        let mut value: serde_json::Value =
+            serde_json::from_slice(&self.0).context("解析manifest失败")?;
+        let mut changed = false;
+        let mut convert = |item: &mut serde_json::Value| {
+            let media_type = item
+                .get("mediaType")
+                .and_then(|x| x.as_str())
+                .and_then(oci_media_type);
+            if let Some(media_type) = media_type {
+                item["mediaType"] = media_type.into();
+                changed = true;
+            }
+        };
+        convert(&mut value);
+        if let Some(config) = value.get_mut("config") {
+            convert(config);
+        }
+        for key in ["layers", "manifests"] {
+            if let Some(items) = value.get_mut(key).and_then(|x| x.as_array_mut()) {
+                items.iter_mut().for_each(&mut convert);
+            }
+        }
+        if !changed {
+            return Ok(None);
+        }
+        Ok(Some(Self(serde_json::to_vec(&value)?)))
+    }
+    /// 解析镜像清单digest（不带`sha256:`前缀）：镜像索引按平台选择对应的manifest，普通manifest原样返回
+    pub fn resolve(digest: &str, platform: &Platform) -> Result<String> {
+        let manifest = Self::load(digest)?;
+        if !manifest.is_index() {
+            return Ok(digest.to_string());
+        }
+        let index = manifest.to_oci_index()?;
+        let entry = select_manifest(&index, platform).ok_or(anyhow!(
+            "镜像索引{}中没有平台{}的manifest",
+            digest,
+            platform_string(platform)
+        ))?;
+        debug!(
+            "镜像索引{}选择平台{}的manifest: {}",
+            digest,
+            platform_string(platform),
+            entry.digest
+        );
+        entry.digest.get_digest()
+    }
 }
 
-pub fn load_layer(lays_des: &Vec<OciDescriptor>) -> Result<Vec<LayerAndData>> {
+pub fn load_layer(lays_des: &[OciDescriptor]) -> Result<Vec<LayerAndData>> {
     let mut layers = Vec::with_capacity(lays_des.len());
     for desc_item in lays_des.iter() {
         let layer = LayerAndData::load(&desc_item.digest, desc_item.media_type.clone())
@@ -25,3 +168,111 @@ pub fn load_layer(lays_des: &Vec<OciDescriptor>) -> Result<Vec<LayerAndData>> {
     }
     Ok(layers)
 }
+
+#[cfg(all(test, feature = "test-registry"))]
+mod test {
+    use super::Manifest;
+    use crate::container::init_with;
+    use crate::distribution::pull::pull_with;
+    use crate::distribution::push::{push_with, PushOptions};
+    use crate::distribution::test_registry::{TestRegistry, TestRegistryOptions};
+    use crate::filesystem::test_home;
+    use crate::image::index::host_platform;
+    use crate::util::cancel::Cancel;
+    use crate::util::DigestPre;
+    use flate2::write::GzEncoder;
+    use oci_distribution::manifest::{
+        IMAGE_CONFIG_MEDIA_TYPE, IMAGE_DOCKER_CONFIG_MEDIA_TYPE,
+        IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE,
+        OCI_IMAGE_MEDIA_TYPE,
+    };
+    use oci_distribution::secrets::RegistryAuth;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn test_docker_schema2() {
+        test_home();
+        let registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let client = registry.client(RegistryAuth::Anonymous).unwrap();
+        let image = registry.reference("docker/app:1");
+
+        // 构造Docker schema2镜像：gzip压缩的layer与Docker格式的config
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(6);
+        header.set_mode(0o755);
+        header.set_cksum();
+        tar.append_data(&mut header, "bin/app", b"docker".as_slice())
+            .unwrap();
+        let tar = tar.into_inner().unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&tar).unwrap();
+        let layer = encoder.finish().unwrap();
+        let config = serde_json::json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "config": {"Entrypoint": ["/bin/app"]},
+            "rootfs": {"type": "layers", "diff_ids": [sha256::digest(tar.as_slice()).sha256_pre()]}
+        })
+        .to_string()
+        .into_bytes();
+        let config_digest = sha256::digest(config.as_slice()).sha256_pre();
+        let layer_digest = sha256::digest(layer.as_slice()).sha256_pre();
+        client
+            .upload_blob(&image, &config_digest, &config)
+            .await
+            .unwrap();
+        client
+            .upload_blob(&image, &layer_digest, &layer)
+            .await
+            .unwrap();
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": IMAGE_MANIFEST_MEDIA_TYPE,
+            "config": {"mediaType": IMAGE_DOCKER_CONFIG_MEDIA_TYPE, "size": config.len(), "digest": config_digest},
+            "layers": [{"mediaType": IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE, "size": layer.len(), "digest": layer_digest}]
+        })
+        .to_string()
+        .into_bytes();
+        client
+            .push_manifest_raw(&image, &manifest, IMAGE_MANIFEST_MEDIA_TYPE)
+            .await
+            .unwrap();
+
+        // 原样保存并正确展开
+        let digest = pull_with(&client, &image, &host_platform(), &Cancel::new())
+            .await
+            .unwrap();
+        assert_eq!(Manifest::load(&digest).unwrap().data(), manifest.as_slice());
+        let session = registry.session(RegistryAuth::Anonymous).unwrap();
+        let container = init_with(&session, &image, true, &Cancel::new())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(container.cmd()).unwrap(), b"docker");
+
+        // 推送时转为OCI格式
+        let target = registry.reference("docker/oci:1");
+        let options = PushOptions {
+            convert_to_oci: true,
+        };
+        let report = push_with(&client, &image, &target, &options, &Cancel::new())
+            .await
+            .unwrap();
+        assert_ne!(report.manifest_digest, digest.sha256_pre());
+        let pushed = registry.manifest("docker/oci", "1").unwrap();
+        let pushed = Manifest::new(pushed).to_oci_manifest().unwrap();
+        assert_eq!(pushed.media_type.as_deref(), Some(OCI_IMAGE_MEDIA_TYPE));
+        assert_eq!(pushed.config.media_type, IMAGE_CONFIG_MEDIA_TYPE);
+        assert_eq!(pushed.layers[0].media_type, IMAGE_LAYER_GZIP_MEDIA_TYPE);
+        assert_eq!(pushed.layers[0].digest, layer_digest);
+
+        assert!(
+            Manifest::new(br#"{"schemaVersion":1,"name":"a","tag":"1"}"#.to_vec())
+                .check_schema()
+                .is_err()
+        );
+        registry.shutdown().await.unwrap();
+    }
+}
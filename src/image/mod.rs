@@ -1,18 +1,35 @@
 pub mod build;
 pub mod config;
+pub mod gc;
+pub mod index;
 pub mod layer;
 pub mod manifest;
+pub mod squash;
+pub mod wasm;
 
-use crate::filesystem::FileSystem;
+use crate::filesystem::{write_atomic, FileSystem};
+use crate::util::{lock, DigestPre};
 use anyhow::Result;
 use log::warn;
+use oci_distribution::manifest::Platform;
 use oci_distribution::Reference;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+/// 进程内images.json读取-修改-保存的互斥锁
+static IMAGES_JSON: Mutex<()> = Mutex::new(());
+
+fn lock_images_json() -> MutexGuard<'static, ()> {
+    lock(&IMAGES_JSON)
+}
 #[derive(Serialize, Deserialize, Default)]
 pub struct Repositories {
     #[serde(default)]
     repositories: HashMap<String, HashMap<String, String>>,
+    /// 镜像为镜像索引时，拉取时选择的平台；键为镜像的whole name
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    platforms: HashMap<String, Platform>,
 }
 
 impl Repositories {
@@ -32,20 +49,43 @@ impl Repositories {
             .unwrap_or(Repositories::default()))
     }
 
-    /// 获取本地镜像的digest
-    pub fn image_digest(&self, image: &Reference) -> Option<&String> {
+    ///
+    /// 获取本地镜像的digest（带`sha256:`前缀）。
+    /// digest引用（`repo@sha256:...`）在本地存在该manifest时直接返回，不依赖images.json中的记录
+    pub fn image_digest(&self, image: &Reference) -> Option<String> {
+        if let Some(digest) = image.digest() {
+            let exists = digest
+                .to_string()
+                .get_digest()
+                .and_then(|x| FileSystem.exist_manifest(&x))
+                .unwrap_or(false);
+            if exists {
+                return Some(digest.to_string());
+            }
+        }
         let full_name = full_name(image);
-        let whole_name = image.whole();
+        let whole_name = record_name(image);
         if let Some(repo) = self.repositories.get(&full_name) {
-            repo.get(&whole_name)
+            repo.get(&whole_name).cloned()
         } else {
             None
         }
     }
-    /// 更新镜像信息
+    /// 获取镜像索引拉取时选择的平台
+    pub fn image_platform(&self, image: &Reference) -> Option<&Platform> {
+        self.platforms.get(&record_name(image))
+    }
+    /// 记录镜像索引选择的平台
+    pub fn update_platform(&mut self, image: &Reference, platform: Platform) {
+        self.platforms.insert(record_name(image), platform);
+    }
+    ///
+    /// 更新镜像信息（同时清除之前记录的平台）。
+    /// digest引用只按digest记录，不影响同名tag的记录
     pub fn update(&mut self, image: &Reference, digest: String) {
         let full_name = full_name(image);
-        let whole_name = image.whole();
+        let whole_name = record_name(image);
+        self.platforms.remove(&whole_name);
 
         if let Some(repo) = self.repositories.get_mut(&full_name) {
             repo.insert(whole_name, digest);
@@ -55,16 +95,127 @@ impl Repositories {
             self.repositories.insert(full_name, repo);
         }
     }
-    /// 更新镜像信息、并保存至本地
+    ///
+    /// 按仓库名查找tag对应的digest（带`sha256:`前缀）。
+    /// 仓库名可为全名`registry/repository`，也可省略registry；同名仓库优先全名匹配
+    pub fn find_tag(&self, name: &str, tag: &str) -> Option<String> {
+        self.matching_repos(name)
+            .into_iter()
+            .find_map(|(full_name, repo)| repo.get(&format!("{}:{}", full_name, tag)).cloned())
+    }
+    /// 按仓库名（规则同`find_tag`）列出所有tag，已排序
+    pub fn tags(&self, name: &str) -> Vec<String> {
+        let mut tags: Vec<String> = self
+            .matching_repos(name)
+            .into_iter()
+            .flat_map(|(full_name, repo)| {
+                repo.keys().filter_map(move |whole_name| {
+                    let tag = whole_name
+                        .strip_prefix(full_name.as_str())?
+                        .strip_prefix(':')?;
+                    (!tag.contains('@')).then(|| tag.to_string())
+                })
+            })
+            .collect();
+        tags.sort();
+        tags.dedup();
+        tags
+    }
+    /// 所有仓库名（省略registry，按`find_tag`的规则可匹配），已排序去重
+    pub fn repository_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .repositories
+            .keys()
+            .map(|full_name| {
+                full_name
+                    .split_once('/')
+                    .map_or(full_name.as_str(), |x| x.1)
+                    .to_string()
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+    fn matching_repos(&self, name: &str) -> Vec<(&String, &HashMap<String, String>)> {
+        let mut repos: Vec<_> = self
+            .repositories
+            .iter()
+            .filter(|(full_name, _)| {
+                full_name.as_str() == name || full_name.split_once('/').map(|x| x.1) == Some(name)
+            })
+            .collect();
+        repos.sort_by_key(|(full_name, _)| (full_name.as_str() != name, full_name.to_string()));
+        repos
+    }
+    /// 所有已记录的镜像：仓库全名（`registry/repository`）与digest（带`sha256:`前缀）
+    pub fn images(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.repositories
+            .iter()
+            .flat_map(|(full_name, repo)| repo.values().map(move |digest| (full_name, digest)))
+    }
+    /// 所有已记录镜像的digest（带`sha256:`前缀）
+    pub fn digests(&self) -> impl Iterator<Item = &String> {
+        self.repositories.values().flat_map(|repo| repo.values())
+    }
+    /// 只保留`keep`返回true的镜像（参数为镜像记录名），返回被移除的镜像记录名
+    pub fn retain<F: FnMut(&str) -> bool>(&mut self, mut keep: F) -> Vec<String> {
+        let mut removed = Vec::new();
+        for repo in self.repositories.values_mut() {
+            repo.retain(|whole_name, _| {
+                let retain = keep(whole_name);
+                if !retain {
+                    removed.push(whole_name.clone());
+                }
+                retain
+            });
+        }
+        self.repositories.retain(|_, repo| !repo.is_empty());
+        for whole_name in removed.iter() {
+            self.platforms.remove(whole_name);
+        }
+        removed
+    }
+    ///
+    /// 重新读取images.json、更新镜像信息并保存至本地，读取与保存之间与其它更新互斥。
+    /// 更新后`self`为最新的内容
     pub fn update_and_save(&mut self, image: &Reference, digest: String) -> Result<()> {
-        self.update(image, digest);
-        self.save()
+        *self = Self::modify(|repo| repo.update(image, digest))?.1;
+        Ok(())
     }
-    /// 保存至本地
+    ///
+    /// 读取images.json、以`f`修改并保存，读取与保存之间与其它更新互斥（只在进程内互斥），
+    /// 返回`f`的结果与修改后的内容
+    pub fn modify<T, F: FnOnce(&mut Self) -> T>(f: F) -> Result<(T, Self)> {
+        let _guard = lock_images_json();
+        let mut repo = Self::init()?;
+        let result = f(&mut repo);
+        repo.save_unlocked()?;
+        Ok((result, repo))
+    }
+    ///
+    /// 保存至本地（先写临时文件再重命名）。
+    /// 保存的是`self`读取时的内容加上之后的修改，会覆盖期间其它更新，修改已有记录应使用[`Self::modify`]
     pub fn save(&self) -> Result<()> {
+        let _guard = lock_images_json();
+        self.save_unlocked()
+    }
+    fn save_unlocked(&self) -> Result<()> {
         let repos_path = FileSystem.images_json()?;
-        std::fs::write(&repos_path, serde_json::to_vec(&self)?)?;
-        Ok(())
+        write_atomic(&repos_path, &serde_json::to_vec(&self)?)
+    }
+}
+
+/// images.json中记录镜像所用的名称：digest引用忽略tag，只保留digest
+pub(crate) fn record_name(image: &Reference) -> String {
+    match image.digest() {
+        Some(digest) => Reference::with_digest(
+            image.registry().to_string(),
+            image.repository().to_string(),
+            digest.to_string(),
+        )
+        .whole(),
+        None => image.whole(),
     }
 }
 
@@ -75,3 +226,59 @@ fn full_name(image: &Reference) -> String {
         format!("{}/{}", image.registry(), image.repository())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Repositories;
+    use crate::filesystem::{test_home, FileSystem};
+    use crate::util::DigestPre;
+    use oci_distribution::Reference;
+
+    #[test]
+    fn test_digest_reference() {
+        test_home();
+        let data = br#"{"schemaVersion":2,"layers":[]}"#;
+        let digest = sha256::digest(data.as_slice());
+        FileSystem.save_manifest(&digest, data).unwrap();
+
+        let mut repo = Repositories::default();
+        let tagged: Reference = "localhost/test/digest:1".parse().unwrap();
+        repo.update(&tagged, "sha256:tag".to_string());
+        let by_digest: Reference = format!("localhost/test/digest:1@{}", digest.sha256_pre())
+            .parse()
+            .unwrap();
+        // 本地存在manifest，无需images.json中的记录
+        assert_eq!(repo.image_digest(&by_digest), Some(digest.sha256_pre()));
+
+        repo.update(&by_digest, digest.sha256_pre());
+        assert_eq!(repo.image_digest(&tagged), Some("sha256:tag".to_string()));
+        let digest_only: Reference = format!("localhost/test/digest@{}", digest.sha256_pre())
+            .parse()
+            .unwrap();
+        assert_eq!(
+            repo.repositories["localhost/test/digest"].get(&digest_only.whole()),
+            Some(&digest.sha256_pre())
+        );
+    }
+
+    #[test]
+    fn test_concurrent_modify() {
+        test_home();
+        let images: Vec<Reference> = (0..8)
+            .map(|i| format!("localhost/test/modify:{}", i).parse().unwrap())
+            .collect();
+        std::thread::scope(|scope| {
+            for image in images.iter() {
+                scope.spawn(|| {
+                    Repositories::modify(|repo| repo.update(image, "sha256:modify".to_string()))
+                        .unwrap()
+                });
+            }
+        });
+        // 并发的更新互不覆盖
+        let repo = Repositories::init().unwrap();
+        for image in images.iter() {
+            assert_eq!(repo.image_digest(image), Some("sha256:modify".to_string()));
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::container::unpack_layers;
+use crate::filesystem::snapshot::{LayerOptions, Snapshot};
+use crate::filesystem::FileSystem;
+use crate::image::build::{commit_layer, SQUASH_CREATED_BY};
+use crate::image::config::{ConfigFile, History};
+use crate::image::index::host_platform;
+use crate::image::manifest::{oci_media_type, Manifest};
+use crate::image::wasm::is_wasm_artifact;
+use crate::image::Repositories;
+use crate::util::DigestPre;
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
+use log::debug;
+use oci_distribution::Reference;
+use sha256::digest;
+
+///
+/// 合并本地镜像的所有layer（应用whiteout）为单个layer，
+/// 以`new_tag`保存为同一仓库下的新镜像，返回新manifest的digest。
+/// 镜像索引按拉取时选择的平台（默认本机平台）选择manifest合并，新镜像为单个manifest
+pub async fn squash(image: &Reference, new_tag: &str) -> Result<String> {
+    debug!("开始合并镜像layer: {:?}", image);
+    let repo = Repositories::init()?;
+    let manifest_digest = repo
+        .image_digest(image)
+        .ok_or(anyhow!("本地未找到镜像{:?}", image))?
+        .get_digest()?;
+    let platform = repo
+        .image_platform(image)
+        .cloned()
+        .unwrap_or_else(host_platform);
+    let manifest_digest = Manifest::resolve(&manifest_digest, &platform)?;
+    let manifest = Manifest::load(manifest_digest.as_str())?;
+    if manifest.is_index() {
+        bail!(
+            "镜像{:?}的manifest[{}]仍为镜像索引，无法合并",
+            image,
+            manifest_digest
+        );
+    }
+    let mut manifest = manifest.to_oci_manifest()?;
+    if is_wasm_artifact(&manifest) {
+        bail!("镜像{:?}为wasm artifact，没有可合并的layer", image);
+    }
+    // 合并后的layer为OCI类型，Docker schema2镜像一并转为OCI格式，避免混用两种媒体类型
+    if let Some(media_type) = manifest.media_type.as_deref().and_then(oci_media_type) {
+        manifest.media_type = Some(media_type.to_string());
+    }
+    if let Some(media_type) = oci_media_type(&manifest.config.media_type) {
+        manifest.config.media_type = media_type.to_string();
+    }
+    let config_path = FileSystem
+        .config_sha256()?
+        .join(manifest.config.digest.get_digest()?);
+    let mut config: serde_json::Value = serde_json::from_slice(&std::fs::read(config_path)?)?;
+    let diff_ids = ConfigFile::diff_ids(&serde_json::to_vec(&config)?)?;
+
+    // 展开所有layer后与空快照比较，得到合并后的layer
+    let base = Snapshot::new()?;
+    let merged = Snapshot::new()?;
+    unpack_layers(&diff_ids, &merged.path)?;
+    let changeset = base.diff(&merged);
+    let layer = commit_layer(changeset, &LayerOptions::default(), None)?;
+
+    let rootfs_key = if config.get("rootfs").is_some() {
+        "rootfs"
+    } else {
+        "rootf"
+    };
+    let created = Utc::now();
+    let squashed_history = History::new(created, SQUASH_CREATED_BY.to_string(), layer.is_none());
+    let history = config
+        .as_object_mut()
+        .ok_or(anyhow!("镜像config格式错误"))?
+        .entry("history")
+        .or_insert_with(|| serde_json::Value::Array(Vec::new()));
+    if let Some(history) = history.as_array_mut() {
+        for item in history.iter_mut() {
+            item["empty_layer"] = serde_json::Value::Bool(true);
+        }
+        history.push(serde_json::to_value(squashed_history)?);
+    }
+    match layer {
+        Some((layer, descriptor)) => {
+            config[rootfs_key]["diff_ids"] = serde_json::json!([layer.diff_id]);
+            manifest.layers = vec![descriptor];
+        }
+        None => {
+            config[rootfs_key]["diff_ids"] = serde_json::json!([]);
+            manifest.layers = Vec::new();
+        }
+    }
+
+    let config_data = serde_json::to_vec(&config)?;
+    let config_digest = digest(config_data.as_slice());
+    FileSystem.save_config(&config_digest, &config_data)?;
+    manifest.config.digest = config_digest.sha256_pre();
+    manifest.config.size = config_data.len() as i64;
+
+    let manifest_data = serde_json::to_vec(&manifest)?;
+    let manifest_digest = digest(manifest_data.as_slice());
+    FileSystem.save_manifest(&manifest_digest, &manifest_data)?;
+
+    let target = Reference::with_tag(
+        image.registry().to_string(),
+        image.repository().to_string(),
+        new_tag.to_string(),
+    );
+    Repositories::modify(|repo| repo.update(&target, manifest_digest.sha256_pre()))?;
+    Ok(manifest_digest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::squash;
+    use crate::args::BuildArgs;
+    use crate::container::unpack_layers;
+    use crate::filesystem::test_home;
+    use crate::image::build::build;
+    use crate::image::build::config::instructions::{Copy, Dest, Kind};
+    use crate::image::build::config::{BuildConfig, PlatformCopys};
+    use crate::image::config::ConfigFile;
+    use crate::image::index::host_platform;
+    use crate::image::manifest::Manifest;
+    use crate::util::DigestPre;
+
+    fn load_config(manifest_digest: &str) -> (usize, ConfigFile) {
+        let manifest = Manifest::load(manifest_digest)
+            .unwrap()
+            .to_oci_manifest()
+            .unwrap();
+        let config = ConfigFile::load(&manifest.config.digest.get_digest().unwrap()).unwrap();
+        (manifest.layers.len(), config)
+    }
+
+    #[tokio::test]
+    async fn test_squash() {
+        test_home();
+        let src = tempfile::tempdir().unwrap();
+        let app = src.path().join("app");
+        let conf = src.path().join("app.toml");
+        std::fs::write(&app, "app").unwrap();
+        std::fs::write(&conf, "conf").unwrap();
+        let mut args = BuildArgs {
+            config: BuildConfig {
+                kind: Kind::App,
+                copys: vec![
+                    Copy(app, "/bin/".to_string().try_into().unwrap()),
+                    Copy(conf, "/etc/".to_string().try_into().unwrap()),
+                ],
+                cmd: Dest::try_from("/bin/app".to_string()).unwrap(),
+                reproducible: false,
+                squash: false,
+                platforms: Vec::new(),
+                wasm_artifact: false,
+            },
+            image: "localhost/test/squash:1".parse().unwrap(),
+        };
+        let layered = build(&args).await.unwrap();
+        assert_eq!(load_config(&layered).0, 2);
+
+        let squashed = squash(&args.image, "squashed").await.unwrap();
+        let (layers, config) = load_config(&squashed);
+        assert_eq!(layers, 1);
+        assert_eq!(config.rootf.diff_ids.len(), 1);
+        assert_eq!(config.history.iter().filter(|x| !x.empty_layer).count(), 1);
+        let target = tempfile::tempdir().unwrap();
+        unpack_layers(&config.rootf.diff_ids, target.path()).unwrap();
+        assert!(target.path().join("bin/app").is_file());
+        assert!(target.path().join("etc/app.toml").is_file());
+
+        args.config.squash = true;
+        let built_squashed = build(&args).await.unwrap();
+        assert_eq!(load_config(&built_squashed).0, 1);
+
+        // 镜像索引按本机平台选择manifest合并
+        args.config.squash = false;
+        args.image = "localhost/test/squash-index:1".parse().unwrap();
+        let mut other = host_platform();
+        other.architecture = "other".to_string();
+        for platform in [host_platform(), other] {
+            args.config.platforms.push(PlatformCopys {
+                platform,
+                copys: Vec::new(),
+            });
+        }
+        build(&args).await.unwrap();
+        let squashed = squash(&args.image, "squashed").await.unwrap();
+        let (layers, config) = load_config(&squashed);
+        assert_eq!(layers, 1);
+        assert_eq!(config.architecture, Some(host_platform().architecture));
+
+        // wasm artifact没有可合并的layer
+        args.image = "localhost/test/squash-wasm:1".parse().unwrap();
+        args.config.platforms.clear();
+        args.config.kind = Kind::Wasi;
+        args.config.wasm_artifact = true;
+        build(&args).await.unwrap();
+        let e = squash(&args.image, "squashed").await.unwrap_err();
+        assert!(e.to_string().contains("wasm artifact"), "{}", e);
+    }
+}
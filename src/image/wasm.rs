@@ -0,0 +1,129 @@
+use crate::image::build::config::instructions::Kind;
+use crate::image::config::{ConfigFile, RootFs};
+use crate::image::manifest::TITLE_ANNOTATION;
+use anyhow::{bail, Result};
+use oci_distribution::manifest::{
+    OciDescriptor, OciImageManifest, WASM_CONFIG_MEDIA_TYPE, WASM_LAYER_MEDIA_TYPE,
+};
+
+/// 没有文件名注解时模块在容器中的文件名
+pub const DEFAULT_MODULE_NAME: &str = "module.wasm";
+
+/// wasm artifact的config内容（与wasm-to-oci一致，为空对象）
+pub const ARTIFACT_CONFIG: &[u8] = b"{}";
+
+/// 是否为wasm OCI artifact（config为`application/vnd.wasm.config.v1+json`）
+pub fn is_wasm_artifact(manifest: &OciImageManifest) -> bool {
+    manifest.config.media_type == WASM_CONFIG_MEDIA_TYPE
+}
+
+/// wasm artifact中的模块layer：必须有且只有一个`application/vnd.wasm.content.layer.v1+wasm`的layer
+pub fn module_layer(manifest: &OciImageManifest) -> Result<&OciDescriptor> {
+    match manifest.layers.as_slice() {
+        [layer] if layer.media_type == WASM_LAYER_MEDIA_TYPE => Ok(layer),
+        [layer] => bail!("wasm artifact的layer类型错误: {}", layer.media_type),
+        layers => bail!("wasm artifact应只有一个layer，实际为{}个", layers.len()),
+    }
+}
+
+/// 模块在容器中的文件名，取自layer的`org.opencontainers.image.title`注解
+pub fn module_name(layer: &OciDescriptor) -> String {
+    layer
+        .annotations
+        .as_ref()
+        .and_then(|x| x.get(TITLE_ANNOTATION))
+        .and_then(|x| std::path::Path::new(x).file_name())
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or(DEFAULT_MODULE_NAME.to_string())
+}
+
+/// 由wasm artifact的manifest生成容器使用的config：Kind为Wasi，CMD为模块文件
+pub fn config_file(manifest: &OciImageManifest) -> Result<ConfigFile> {
+    let layer = module_layer(manifest)?;
+    Ok(ConfigFile {
+        created: None,
+        architecture: None,
+        os: None,
+        variant: None,
+        kind: Kind::Wasi,
+        cmd: module_name(layer),
+        rootf: RootFs {
+            typ: "layers".to_string(),
+            diff_ids: Vec::new(),
+        },
+        history: Vec::new(),
+    })
+}
+
+#[cfg(all(test, feature = "test-registry"))]
+mod test {
+    use crate::args::BuildArgs;
+    use crate::container::init_with;
+    use crate::distribution::pull::pull_with;
+    use crate::distribution::push::{push_with, PushOptions};
+    use crate::distribution::test_registry::{TestRegistry, TestRegistryOptions};
+    use crate::filesystem::test_home;
+    use crate::image::build::build;
+    use crate::image::build::config::instructions::{Copy, Dest, Kind};
+    use crate::image::build::config::BuildConfig;
+    use crate::image::index::host_platform;
+    use crate::image::manifest::Manifest;
+    use crate::util::cancel::Cancel;
+    use oci_distribution::manifest::{WASM_CONFIG_MEDIA_TYPE, WASM_LAYER_MEDIA_TYPE};
+    use oci_distribution::secrets::RegistryAuth;
+    use oci_distribution::Reference;
+
+    #[tokio::test]
+    async fn test_wasm_artifact() {
+        test_home();
+        let src = tempfile::tempdir().unwrap();
+        let module = src.path().join("app.wasm");
+        std::fs::write(&module, b"\0asm\x01\0\0\0").unwrap();
+        let image: Reference = "localhost/test/wasm-artifact:1".parse().unwrap();
+        let args = BuildArgs {
+            config: BuildConfig {
+                kind: Kind::Wasi,
+                copys: vec![Copy(module, "/".to_string().try_into().unwrap())],
+                cmd: Dest::try_from("/app.wasm".to_string()).unwrap(),
+                reproducible: false,
+                squash: false,
+                platforms: Vec::new(),
+                wasm_artifact: true,
+            },
+            image: image.clone(),
+        };
+        build(&args).await.unwrap();
+        let registry = TestRegistry::start(TestRegistryOptions::default())
+            .await
+            .unwrap();
+        let client = registry.client(RegistryAuth::Anonymous).unwrap();
+        let target = registry.reference("wasm/app:1");
+        push_with(
+            &client,
+            &image,
+            &target,
+            &PushOptions::default(),
+            &Cancel::new(),
+        )
+        .await
+        .unwrap();
+        let pushed = registry.manifest("wasm/app", "1").unwrap();
+        let pushed = Manifest::new(pushed).to_oci_manifest().unwrap();
+        assert_eq!(pushed.config.media_type, WASM_CONFIG_MEDIA_TYPE);
+        assert_eq!(pushed.layers.len(), 1);
+        assert_eq!(pushed.layers[0].media_type, WASM_LAYER_MEDIA_TYPE);
+
+        // 拉取为另一个镜像后初始化，模块文件按注解中的文件名放置
+        pull_with(&client, &target, &host_platform(), &Cancel::new())
+            .await
+            .unwrap();
+        let session = registry.session(RegistryAuth::Anonymous).unwrap();
+        let container = init_with(&session, &target, true, &Cancel::new())
+            .await
+            .unwrap();
+        let module = container.module().unwrap();
+        assert!(module.ends_with("app.wasm"));
+        assert_eq!(std::fs::read(module).unwrap(), b"\0asm\x01\0\0\0");
+        registry.shutdown().await.unwrap();
+    }
+}
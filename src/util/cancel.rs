@@ -0,0 +1,148 @@
+use anyhow::Result;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// 操作被取消的原因，可通过`anyhow::Error::downcast_ref`判断
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cancelled {
+    /// 调用方取消
+    Requested,
+    /// 超过截止时间
+    TimedOut,
+}
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cancelled::Requested => write!(f, "操作已取消"),
+            Cancelled::TimedOut => write!(f, "操作超时"),
+        }
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+///
+/// 取消信号：调用方可随时调用`cancel`，也可设置截止时间。
+/// 克隆后共享同一信号；被取消的操作在下一个检查点返回[`Cancelled`]错误
+#[derive(Clone)]
+pub struct Cancel {
+    sender: Arc<watch::Sender<bool>>,
+    deadline: Option<Instant>,
+}
+
+impl Default for Cancel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cancel {
+    pub fn new() -> Self {
+        Self {
+            sender: Arc::new(watch::channel(false).0),
+            deadline: None,
+        }
+    }
+
+    /// 在`timeout`后超时的取消信号
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self::new().timeout(timeout)
+    }
+
+    /// 共享同一取消信号、并在`timeout`后超时（取与原截止时间中较早者）
+    pub fn timeout(&self, timeout: Duration) -> Self {
+        let deadline = Instant::now() + timeout;
+        Self {
+            sender: self.sender.clone(),
+            deadline: Some(self.deadline.map_or(deadline, |x| x.min(deadline))),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.sender.send_replace(true);
+    }
+
+    /// 已取消或已超时时返回原因
+    pub fn reason(&self) -> Option<Cancelled> {
+        if *self.sender.borrow() {
+            Some(Cancelled::Requested)
+        } else if self.deadline.is_some_and(|x| Instant::now() >= x) {
+            Some(Cancelled::TimedOut)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.reason().is_some()
+    }
+
+    /// 检查点：已取消或已超时时返回错误
+    pub fn check(&self) -> Result<()> {
+        match self.reason() {
+            Some(reason) => Err(reason.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// 等待取消或超时，返回原因
+    pub async fn cancelled(&self) -> Cancelled {
+        let mut receiver = self.sender.subscribe();
+        let requested = async {
+            // sender由self持有，不会关闭
+            let _ = receiver.wait_for(|x| *x).await;
+            Cancelled::Requested
+        };
+        match self.deadline {
+            Some(deadline) => tokio::select! {
+                reason = requested => reason,
+                _ = tokio::time::sleep_until(deadline) => Cancelled::TimedOut,
+            },
+            None => requested.await,
+        }
+    }
+
+    /// 执行`future`，取消或超时时立即放弃（丢弃`future`）并返回错误
+    pub async fn run<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        self.check()?;
+        tokio::select! {
+            result = future => result,
+            reason = self.cancelled() => Err(reason.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cancel, Cancelled};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let cancel = Cancel::new();
+        assert!(cancel.check().is_ok());
+        let clone = cancel.clone();
+        let pending = cancel.run(async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        });
+        let (result, _) = tokio::join!(pending, async { clone.cancel() });
+        let e = result.unwrap_err();
+        assert_eq!(e.downcast_ref::<Cancelled>(), Some(&Cancelled::Requested));
+
+        let cancel = Cancel::with_timeout(Duration::from_millis(10));
+        let e = cancel
+            .run(async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                Ok(())
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(e.downcast_ref::<Cancelled>(), Some(&Cancelled::TimedOut));
+        assert!(cancel.is_cancelled());
+    }
+}
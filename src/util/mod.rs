@@ -1,48 +1,79 @@
+pub mod cancel;
+
+use crate::util::cancel::{Cancel, Cancelled};
 use anyhow::{bail, Result};
 use async_recursion::async_recursion;
 use log::{debug, warn};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use tokio::task::JoinSet;
+
+///
+/// 加锁；锁被毒化（持有锁的线程panic）时仍取出数据。
+/// 锁内只有缓存与会话等简单的插入、删除，panic后数据依然可用
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
 
-pub async fn copy_dir(from: PathBuf, dest: PathBuf) -> Result<()> {
+///
+/// 复制文件夹。取消时不再开始新的复制，等待已开始的复制结束后返回[`Cancelled`]错误；
+/// future被丢弃时中止尚未完成的复制任务
+pub async fn copy_dir(from: PathBuf, dest: PathBuf, cancel: &Cancel) -> Result<()> {
     if !from.exists() || from.is_file() {
         bail!("源文件夹不存在或非文件夹");
     }
     if dest.exists() && dest.is_file() {
         bail!("目标文件夹为文件");
     }
-    let num = copy_dir_detail(from.clone(), dest.clone()).await?;
+    let num = copy_dir_detail(from.clone(), dest.clone(), cancel.clone()).await?;
+    cancel.check()?;
     debug!("copy {} files: {:?} -> {:?}", num, from, dest);
     Ok(())
 }
 #[async_recursion]
-async fn copy_dir_detail(from: PathBuf, dest: PathBuf) -> Result<usize> {
+async fn copy_dir_detail(from: PathBuf, dest: PathBuf, cancel: Cancel) -> Result<usize> {
+    cancel.check()?;
     if !dest.exists() {
         tokio::fs::create_dir_all(&dest).await?;
     }
     let mut dir = tokio::fs::read_dir(&from).await?;
-    let mut cp_file_task = Vec::new();
-    let mut cp_dir_task = Vec::new();
+    // JoinSet被丢弃时中止其中的任务
+    let mut cp_file_task = JoinSet::new();
+    let mut cp_dir_task = JoinSet::new();
     let mut file_num = 0;
     while let Ok(entry) = dir.next_entry().await {
         if let Some(entry) = entry {
             if let Ok(metadata) = entry.metadata().await {
                 let src_file = from.join(entry.file_name());
                 let dest_file = dest.join(entry.file_name());
+                let cancel = cancel.clone();
                 if metadata.is_file() {
-                    cp_file_task.push(tokio::spawn(tokio::fs::copy(src_file, dest_file)));
+                    cp_file_task.spawn(async move {
+                        cancel.check()?;
+                        tokio::fs::copy(&src_file, &dest_file).await?;
+                        copy_metadata(&src_file, &dest_file)
+                    });
+                } else if metadata.is_symlink() {
+                    cp_file_task.spawn(async move {
+                        cancel.check()?;
+                        copy_symlink(&src_file, &dest_file)?;
+                        copy_metadata(&src_file, &dest_file)
+                    });
                 } else {
-                    cp_dir_task.push(tokio::spawn(copy_dir_detail(src_file, dest_file)));
+                    cp_dir_task.spawn(copy_dir_detail(src_file, dest_file, cancel));
                 }
             }
         } else {
             break;
         }
     }
-    for task in cp_file_task {
-        match task.await {
+    while let Some(task) = cp_file_task.join_next().await {
+        match task {
             Ok(res) => {
                 if let Err(e) = res {
-                    warn!("{:?}", e);
+                    if !is_cancelled(&e) {
+                        warn!("{:?}", e);
+                    }
                 } else {
                     file_num += 1;
                 }
@@ -52,11 +83,13 @@ async fn copy_dir_detail(from: PathBuf, dest: PathBuf) -> Result<usize> {
             }
         }
     }
-    for task in cp_dir_task {
-        match task.await {
+    while let Some(task) = cp_dir_task.join_next().await {
+        match task {
             Ok(res) => match res {
                 Err(e) => {
-                    warn!("{:?}", e);
+                    if !is_cancelled(&e) {
+                        warn!("{:?}", e);
+                    }
                 }
                 Ok(num) => {
                     file_num += num;
@@ -67,10 +100,55 @@ async fn copy_dir_detail(from: PathBuf, dest: PathBuf) -> Result<usize> {
             }
         }
     }
+    cancel.check()?;
+    // 子项全部复制完成后再同步文件夹的元数据，避免修改时间被覆盖
+    copy_metadata(&from, &dest)?;
 
     Ok(file_num)
 }
 
+fn is_cancelled(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<Cancelled>().is_some()
+}
+
+/// 复制符号链接本身（不跟随链接）
+#[cfg(target_family = "unix")]
+fn copy_symlink(from: &Path, dest: &Path) -> Result<()> {
+    let target = std::fs::read_link(from)?;
+    std::os::unix::fs::symlink(target, dest)?;
+    Ok(())
+}
+#[cfg(not(target_family = "unix"))]
+fn copy_symlink(from: &Path, dest: &Path) -> Result<()> {
+    std::fs::copy(from, dest)?;
+    Ok(())
+}
+
+/// 将源文件的权限、扩展属性与修改时间同步到目标文件（符号链接只同步修改时间）
+pub fn copy_metadata(from: &Path, dest: &Path) -> Result<()> {
+    let metadata = std::fs::symlink_metadata(from)?;
+    let mtime = filetime::FileTime::from_last_modification_time(&metadata);
+    if metadata.is_symlink() {
+        let atime = filetime::FileTime::from_last_access_time(&metadata);
+        filetime::set_symlink_file_times(dest, atime, mtime)?;
+        return Ok(());
+    }
+    #[cfg(target_family = "unix")]
+    if let Ok(names) = xattr::list(from) {
+        for name in names {
+            if let Ok(Some(value)) = xattr::get(from, &name) {
+                if let Err(e) = xattr::set(dest, &name, value.as_slice()) {
+                    debug!("复制扩展属性{:?}失败: {:?}", name, e);
+                }
+            }
+        }
+    }
+    // 权限放在扩展属性之后设置，避免只读文件无法写入扩展属性
+    std::fs::set_permissions(dest, metadata.permissions())?;
+    filetime::set_file_mtime(dest, mtime)?;
+    Ok(())
+}
+
 pub trait DigestPre {
     fn sha256_pre(&self) -> String;
 
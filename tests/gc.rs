@@ -0,0 +1,116 @@
+//! gc会删除当前HOME下所有不可达的文件，在独立进程（独立HOME）中测试，避免影响其它测试
+use oci_util::args::BuildArgs;
+use oci_util::container::init_with;
+use oci_util::distribution::session::RegistrySession;
+use oci_util::filesystem::FileSystem;
+use oci_util::image::build::build;
+use oci_util::image::build::config::instructions::{Copy, Dest, Kind};
+use oci_util::image::build::config::{BuildConfig, PlatformCopys};
+use oci_util::image::gc::{gc, gc_with, GcOptions};
+use oci_util::image::index::host_platform;
+use oci_util::image::Repositories;
+use oci_util::util::cancel::Cancel;
+use oci_util::{Reference, RegistryAuth};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+fn build_args(image: &str, content: &str, src: &tempfile::TempDir) -> BuildArgs {
+    let app = src.path().join(image.replace(['/', ':'], "_"));
+    std::fs::write(&app, content).unwrap();
+    BuildArgs {
+        config: BuildConfig {
+            kind: Kind::App,
+            copys: vec![Copy(app, "/bin/app".to_string().try_into().unwrap())],
+            cmd: Dest::try_from("/bin/app".to_string()).unwrap(),
+            reproducible: false,
+            squash: false,
+            platforms: Vec::new(),
+            wasm_artifact: false,
+        },
+        image: image.parse().unwrap(),
+    }
+}
+
+/// 本地存储中的所有文件与容器目录
+fn store_files() -> BTreeSet<PathBuf> {
+    let mut files = BTreeSet::new();
+    for dir in [
+        FileSystem.manifest_sha256().unwrap(),
+        FileSystem.config_sha256().unwrap(),
+        FileSystem.layer_blobs().unwrap(),
+        FileSystem.layer_contents().unwrap(),
+        FileSystem.container().unwrap(),
+    ] {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            files.insert(entry.unwrap().path());
+        }
+    }
+    files
+}
+
+async fn init(image: &Reference) {
+    let session = RegistrySession::https(RegistryAuth::Anonymous).unwrap();
+    init_with(&session, image, false, &Cancel::new())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_gc() {
+    let home = tempfile::tempdir().unwrap();
+    std::env::set_var("HOME", home.path());
+    let src = tempfile::tempdir().unwrap();
+
+    // 保留的镜像：普通镜像（及其容器）、多平台镜像索引、wasm artifact
+    let plain = build_args("localhost/test/gc-plain:1", "plain", &src);
+    build(&plain).await.unwrap();
+    init(&plain.image).await;
+    let mut index = build_args("localhost/test/gc-index:1", "index", &src);
+    let mut other = host_platform();
+    other.architecture = "other".to_string();
+    for platform in [host_platform(), other] {
+        index.config.platforms.push(PlatformCopys {
+            platform,
+            copys: Vec::new(),
+        });
+    }
+    build(&index).await.unwrap();
+    let mut wasm = build_args("localhost/test/gc-wasm:1", "wasm", &src);
+    wasm.config.kind = Kind::Wasi;
+    wasm.config.wasm_artifact = true;
+    build(&wasm).await.unwrap();
+    let reachable = store_files();
+
+    // 删除记录后不可达的镜像
+    let dropped = build_args("localhost/test/gc-dropped:1", "dropped", &src);
+    build(&dropped).await.unwrap();
+    init(&dropped.image).await;
+    let unreachable: BTreeSet<PathBuf> = store_files().difference(&reachable).cloned().collect();
+    assert!(unreachable.len() >= 5, "{:?}", unreachable);
+    Repositories::modify(|repo| repo.retain(|x| x != "localhost/test/gc-dropped:1")).unwrap();
+
+    // 临时文件（如registry服务端上传中的blob）不受gc影响
+    let temp = tempfile::NamedTempFile::new_in(FileSystem.layer_blobs().unwrap()).unwrap();
+
+    // 默认只回收较早写入的文件
+    let report = gc().unwrap();
+    assert_eq!(report.freed_bytes, 0);
+    assert_eq!(store_files().len(), reachable.len() + unreachable.len() + 1);
+
+    let report = gc_with(&GcOptions {
+        min_age: Duration::ZERO,
+    })
+    .unwrap();
+    let mut expected = reachable.clone();
+    expected.insert(temp.path().to_path_buf());
+    assert_eq!(store_files(), expected);
+    let removed = report.manifests.len()
+        + report.configs.len()
+        + report.layers.len()
+        + report.layer_contents.len()
+        + report.containers.len();
+    assert_eq!(removed, unreachable.len());
+    assert_eq!(report.containers.len(), 1);
+    assert!(report.freed_bytes > 0);
+}
@@ -0,0 +1,124 @@
+//! sync会移除images.json中不在期望列表里的镜像，在独立进程（独立HOME）中测试，避免影响其它测试
+#![cfg(feature = "test-registry")]
+
+use oci_util::args::BuildArgs;
+use oci_util::container::sync::sync_with;
+use oci_util::distribution::pull::pull_with;
+use oci_util::distribution::push::{push_with, PushOptions};
+use oci_util::distribution::test_registry::{TestRegistry, TestRegistryOptions};
+use oci_util::image::build::build;
+use oci_util::image::build::config::instructions::{Copy, Dest, Kind};
+use oci_util::image::build::config::BuildConfig;
+use oci_util::image::index::host_platform;
+use oci_util::image::Repositories;
+use oci_util::util::cancel::Cancel;
+use oci_util::{Reference, RegistryAuth};
+
+async fn build_image(image: &str, content: &str) -> Reference {
+    let src = tempfile::tempdir().unwrap();
+    let app = src.path().join("app");
+    std::fs::write(&app, content).unwrap();
+    let image: Reference = image.parse().unwrap();
+    let args = BuildArgs {
+        config: BuildConfig {
+            kind: Kind::App,
+            copys: vec![Copy(app, "/bin/app".to_string().try_into().unwrap())],
+            cmd: Dest::try_from("/bin/app".to_string()).unwrap(),
+            reproducible: false,
+            squash: false,
+            platforms: Vec::new(),
+            wasm_artifact: false,
+        },
+        image: image.clone(),
+    };
+    build(&args).await.unwrap();
+    image
+}
+
+#[tokio::test]
+async fn test_sync() {
+    let home = tempfile::tempdir().unwrap();
+    std::env::set_var("HOME", home.path());
+    let registry = TestRegistry::start(TestRegistryOptions::default())
+        .await
+        .unwrap();
+    let client = registry.client(RegistryAuth::Anonymous).unwrap();
+    let push = |image: Reference, target: Reference| {
+        let client = &client;
+        async move {
+            push_with(
+                client,
+                &image,
+                &target,
+                &PushOptions::default(),
+                &Cancel::new(),
+            )
+            .await
+            .unwrap()
+            .manifest_digest
+        }
+    };
+
+    // missing：本地没有；changed：本地为旧版本，registry上已更新；local：本地构建、不在期望列表中
+    let missing = registry.reference("test/missing:1");
+    let changed = registry.reference("test/changed:1");
+    let missing_digest = push(
+        build_image("localhost/test/missing:1", "missing").await,
+        missing.clone(),
+    )
+    .await;
+    let old_digest = push(
+        build_image("localhost/test/changed:1", "old").await,
+        changed.clone(),
+    )
+    .await;
+    pull_with(&client, &changed, &host_platform(), &Cancel::new())
+        .await
+        .unwrap();
+    let new_digest = push(
+        build_image("localhost/test/changed:2", "new").await,
+        changed.clone(),
+    )
+    .await;
+    assert_ne!(old_digest, new_digest);
+
+    let session = registry.session(RegistryAuth::Anonymous).unwrap();
+    let report = sync_with(
+        &session,
+        &[missing.clone(), changed.clone()],
+        &Cancel::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(report.pulled, vec![missing.clone()]);
+    assert_eq!(report.updated, vec![changed.clone()]);
+    assert!(report.unchanged.is_empty());
+    assert!(report.failed.is_empty(), "{:?}", report.failed);
+    let mut removed = report.removed.clone();
+    removed.sort();
+    assert_eq!(
+        removed,
+        vec![
+            "localhost/test/changed:1".to_string(),
+            "localhost/test/changed:2".to_string(),
+            "localhost/test/missing:1".to_string(),
+        ]
+    );
+
+    let repo = Repositories::init().unwrap();
+    assert_eq!(repo.image_digest(&missing), Some(missing_digest));
+    assert_eq!(repo.image_digest(&changed), Some(new_digest));
+    assert_eq!(repo.digests().count(), 2);
+
+    // 再次同步时均已是最新
+    let report = sync_with(
+        &session,
+        &[missing.clone(), changed.clone()],
+        &Cancel::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(report.unchanged, vec![missing, changed]);
+    assert!(report.removed.is_empty());
+    registry.shutdown().await.unwrap();
+}